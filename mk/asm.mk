@@ -35,8 +35,11 @@ ASMOBJ ?= $(OBJBDIR)asm.o
 # TODO: architecture filter
 ASRCLIST := $(shell find $(SRCDIR) -type f -name '*.fasm')
 
+# Files included by assembler sources.
+AINCLIST := $(shell find $(SRCDIR) -type f -name '*.inc')
+
 # Command to build all assembly files.
 asm: $(ASMOBJ)
 
-$(ASMOBJ): $(ASRCLIST) $(FASM)
+$(ASMOBJ): $(ASRCLIST) $(AINCLIST) $(FASM)
 	$(FASM) $(ASRCLIST) $@
@@ -48,8 +48,22 @@ GRUBCFG    := $(CONFIGDIR)grub.cfg
 LINKSCRIPT := $(CONFIGDIR)link.ld
 TARGETSPEC := $(CONFIGDIR)target.json
 
+# Physical frame allocator backend. Valid values are 'stack' for
+# the page stack allocator and 'buddy' for the buddy system allocator.
+FRAMEALLOC ?= stack
+
+# Allocator debug mode. Set to 'yes' to poison released memory, place
+# redzones around heap objects and record allocation sites. Frame
+# pointers are kept so callers of the allocator can be found.
+MEMDEBUG ?= no
+
+ifeq ($(MEMDEBUG),yes)
+MEMDEBUGF := --cfg mem_debug -C force-frame-pointers=yes
+endif
+
 # Rust compiler, flags and combination.
 RUSTC ?= rustc
-RUSTF ?= -O --cfg arch__$(ARCH) --target=$(TARGETSPEC)
+RUSTF ?= -O --cfg arch__$(ARCH) --cfg frame_alloc__$(FRAMEALLOC) \
+	$(MEMDEBUGF) --target=$(TARGETSPEC)
 RUSTCF := $(RUSTC)
 RUSTCF += $(RUSTF)
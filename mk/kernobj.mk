@@ -5,6 +5,6 @@ KERNOBJ := $(OBJBDIR)kernel.o
 
 krust: $(RSRCLIST) $(KERNOBJ)
 
-$(KERNOBJ): $(RSRCLIST) $(OBJCORE) $(OBJASM-X86_64) $(TARGETSPEC)
+$(KERNOBJ): $(RSRCLIST) $(OBJCORE) $(OBJALLOC) $(OBJASM-X86_64) $(TARGETSPEC)
 	@mkdir -p $(OBJBDIR)
-	$(RUSTCF) --out-dir=$(OBJBDIR) -C lto --emit=asm,obj --extern core=$(OBJCORE) --extern new_bitflags=$(OBJNEW_BITFLAGS) --extern asm_x86_64=$(OBJASM-X86_64) $(MAINRS)
+	$(RUSTCF) --out-dir=$(OBJBDIR) -C lto --emit=asm,obj --extern core=$(OBJCORE) --extern alloc=$(OBJALLOC) --extern new_bitflags=$(OBJNEW_BITFLAGS) --extern asm_x86_64=$(OBJASM-X86_64) $(MAINRS)
@@ -0,0 +1,14 @@
+# This file purpose is to make a Rust LibAlloc library object. It provides
+# Box, Vec, BTreeMap and other types that use kernel heap.
+
+# Rust Alloc library object
+OBJALLOC ?= $(OBJBDIR)liballoc.rlib
+
+# Rule to build liballoc object file
+$(OBJALLOC): $(RUSTDOWNDIR)src/liballoc/lib.rs $(OBJCORE) $(TARGETSPEC)
+	@mkdir -p $(dir $@)
+	$(RUSTCF) --crate-name alloc -C panic=abort --out-dir=$(OBJBDIR) --crate-type=lib --emit=link,dep-info --extern core=$(OBJCORE) $<
+
+# Rule to get lib.rs if it is not available. This means that rust was not
+# downloaded and so this rule downloads rust.
+$(RUSTDOWNDIR)src/liballoc/lib.rs: $(RUSTDOWNDIR)
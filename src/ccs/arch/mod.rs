@@ -1,5 +1,5 @@
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
-/*
+
 #[cfg(target_arch = "x86_64")]
-pub use self::x86_64::setup;*/
+pub use self::x86_64::*;
@@ -0,0 +1,78 @@
+use ::ccs::{Object, ServiceArgs};
+use ::mem::VirtAddr;
+use ::mem::ptdump;
+use ::mem::space::AddressSpace;
+
+/// Errors of debugging services.
+pub enum DebugError {
+
+    /// Requester has no address space.
+    NoSpace,
+
+    /// Given virtual address is not canonical.
+    InvalidAddress,
+}
+
+impl DebugError {
+
+    /// Code of the error that is returned to service requester. Zero is
+    /// reserved for success.
+    pub fn code(&self) -> u64 {
+        match *self {
+            DebugError::NoSpace         => 1,
+            DebugError::InvalidAddress  => 2,
+        }
+    }
+
+    /// Description of the error.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            DebugError::NoSpace         => "no address space",
+            DebugError::InvalidAddress  => "non-canonical address",
+        }
+    }
+}
+
+/// Address space of the requester. None if object has no space yet.
+fn caller_space(caller: &Object) -> Option<&AddressSpace> {
+    let space = caller.space();
+    if space.is_null() {
+        None
+    } else {
+        Some(unsafe { &*space })
+    }
+}
+
+/// Entry point of "ptdump" service. Merged ranges of the address space of
+/// the requester are printed on the kernel logger. Arguments are not used.
+pub extern fn ptdump_service(caller: &mut Object, _: &mut ServiceArgs)
+        -> u64 {
+    match caller_space(caller) {
+        Some(space) => {
+            ptdump::log_space(space);
+            0
+        },
+        None => DebugError::NoSpace.code(),
+    }
+}
+
+/// Entry point of "translate" service. Argument 0 holds virtual address
+/// which translation in the address space of the requester is printed on
+/// the kernel logger. Arguments receive entries of P4, P3, P2 and P1
+/// tables. Entries that were not read are zero.
+pub extern fn translate_service(caller: &mut Object,
+        args: &mut ServiceArgs) -> u64 {
+    let virt = match VirtAddr::try_new(args[0]) {
+        Some(virt)  => virt,
+        None        => return DebugError::InvalidAddress.code(),
+    };
+    let space = match caller_space(caller) {
+        Some(space) => space,
+        None        => return DebugError::NoSpace.code(),
+    };
+
+    ptdump::log_translation(space, virt);
+    let walk = unsafe { ptdump::walk(space.p4_addr(), virt) };
+    *args = walk.entries;
+    0
+}
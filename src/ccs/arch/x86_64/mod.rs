@@ -1 +1,12 @@
-// TODO
+/// Creation of the basic CCS tree of the kernel.
+mod setup;
+pub use self::setup::{setup, root_object};
+
+/// RAM manager services of the kernel.
+pub mod ram;
+
+/// Memory regions shared between CCS objects.
+pub mod shared;
+
+/// Debugging services of the kernel.
+pub mod debug;
@@ -0,0 +1,525 @@
+use ::ccs::{Object, ServiceArgs};
+use ::mem::{VirtAddr, PAGE_SIZE, FrameAlloc};
+use ::mem::map::{USER_RAM_BASE, USER_RAM_END};
+use ::mem::page_alloc_mut;
+use ::mem::slab::ObjectCache;
+use ::mem::space::*;
+use ::mem::stats::{self, Subsystem};
+use core::ptr::null_mut;
+use core::mem::size_of;
+use super::shared;
+
+/// Count of pages that each object is allowed to hold by default.
+pub const DEFAULT_QUOTA: u64 = 0x4000;
+
+/// Flags of the pages given to objects.
+const RAM_FLAGS: u64 = WRITABLE | USER | NO_EXECUTE;
+
+/// Errors of RAM manager services.
+pub enum RamError {
+
+    /// Requester has no own address space.
+    NoSpace,
+
+    /// Request has zero size or the size is too big.
+    InvalidSize,
+
+    /// Object would hold more pages than it's quota allows.
+    QuotaExceeded,
+
+    /// No free frames or no memory for paging tables.
+    NoMemory,
+
+    /// No free range in the RAM window of the address space.
+    NoVirtualSpace,
+
+    /// Range was not allocated by the object.
+    NotAllocated,
+}
+
+/// Memory usage of single CCS object.
+#[derive(Clone, Copy)]
+pub struct ObjectStats {
+
+    /// Count of pages the object holds.
+    pub pages   : u64,
+
+    /// Maximal count of pages the object can hold.
+    pub quota   : u64,
+
+    /// Count of blocks the object has allocated.
+    pub blocks  : u64,
+
+    /// Count of paging tables of the address space of the object.
+    pub tables  : u64,
+
+    /// Bytes of slab cache objects that hold records about the object:
+    /// it's account and blocks and it's shared regions with their grants.
+    pub records : u64,
+}
+
+/// Range of memory allocated by the object.
+struct Block {
+
+    /// First byte of the block in the address space of the object.
+    start   : VirtAddr,
+
+    /// Count of pages of the block.
+    pages   : u64,
+
+    /// Next block of the object. Blocks are sorted by address.
+    next    : *mut Block,
+}
+
+/// RAM usage of single CCS object.
+struct Account {
+
+    /// Object that holds the memory.
+    object  : *const Object,
+
+    /// Address space that memory of the object is mapped in.
+    space   : *mut AddressSpace,
+
+    /// Maximal count of pages the object can hold.
+    quota   : u64,
+
+    /// Count of pages the object currently holds.
+    used    : u64,
+
+    /// Allocated blocks sorted by address.
+    blocks  : *mut Block,
+
+    /// Next account in the list of all accounts.
+    next    : *mut Account,
+}
+
+/// Cache of the allocated blocks.
+static mut BLOCK_CACHE: ObjectCache<Block> =
+        ObjectCache::new("ram-block", None, None);
+
+/// Cache of the accounts.
+static mut ACCOUNT_CACHE: ObjectCache<Account> =
+        ObjectCache::new("ram-account", None, None);
+
+/// List of accounts of all objects that have requested memory.
+static mut ACCOUNTS: *mut Account = 0 as *mut Account;
+
+impl RamError {
+
+    /// Code of the error that is returned to service requester. Zero is
+    /// reserved for success.
+    pub fn code(&self) -> u64 {
+        match *self {
+            RamError::NoSpace           => 1,
+            RamError::InvalidSize       => 2,
+            RamError::QuotaExceeded     => 3,
+            RamError::NoMemory          => 4,
+            RamError::NoVirtualSpace    => 5,
+            RamError::NotAllocated      => 6,
+        }
+    }
+
+    /// Description of the error.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            RamError::NoSpace           => "object has no address space",
+            RamError::InvalidSize       => "invalid size",
+            RamError::QuotaExceeded     => "quota exceeded",
+            RamError::NoMemory          => "out of memory",
+            RamError::NoVirtualSpace    => "no free virtual memory",
+            RamError::NotAllocated      => "memory was not allocated",
+        }
+    }
+}
+
+impl Block {
+
+    /// Byte after the last byte of the block.
+    fn end(&self) -> VirtAddr {
+        self.start + self.pages * PAGE_SIZE
+    }
+}
+
+impl Account {
+
+    /// Memory usage of the account.
+    unsafe fn stats(&self) -> ObjectStats {
+        let mut blocks = 0;
+        let mut block = self.blocks;
+        while !block.is_null() {
+            blocks += 1;
+            block = (*block).next;
+        }
+
+        let records = size_of::<Account>() + blocks * size_of::<Block>()
+                + shared::record_bytes(self.object);
+
+        ObjectStats {
+            pages   : self.used,
+            quota   : self.quota,
+            blocks  : blocks as u64,
+            tables  : (*self.space).table_count() as u64,
+            records : records as u64,
+        }
+    }
+
+    /// Find free range of given size in RAM window. Returns the range
+    /// start and the block after which new block must be linked.
+    unsafe fn find_free(&self, pages: u64)
+            -> Option<(VirtAddr, *mut Block)> {
+        let size = pages * PAGE_SIZE;
+        let mut start = USER_RAM_BASE;
+        let mut prev = null_mut();
+        let mut block = self.blocks;
+        while !block.is_null() {
+            if (*block).start.as_u64() - start >= size {
+                break;
+            }
+            start = (*block).end().as_u64();
+            prev = block;
+            block = (*block).next;
+        }
+
+        if USER_RAM_END - start >= size {
+            Some((VirtAddr::new(start), prev))
+        } else {
+            None
+        }
+    }
+
+    /// Find the block that starts at given address. Returns the block and
+    /// the previous block.
+    unsafe fn find_block(&self, start: VirtAddr)
+            -> Option<(*mut Block, *mut Block)> {
+        let mut prev = null_mut();
+        let mut block = self.blocks;
+        while !block.is_null() {
+            if (*block).start == start {
+                return Some((block, prev));
+            }
+            prev = block;
+            block = (*block).next;
+        }
+        None
+    }
+}
+
+/// Cache of the allocated blocks.
+fn block_cache_mut() -> &'static mut ObjectCache<Block> {
+    unsafe { &mut BLOCK_CACHE }
+}
+
+/// Cache of the accounts.
+fn account_cache_mut() -> &'static mut ObjectCache<Account> {
+    unsafe { &mut ACCOUNT_CACHE }
+}
+
+/// Account of given object if it exists.
+unsafe fn find_account(object: *const Object) -> *mut Account {
+    let mut account = ACCOUNTS;
+    while !account.is_null() && (*account).object != object {
+        account = (*account).next;
+    }
+    account
+}
+
+/// Account of given object. New account with default quota is created
+/// for the object in it's own address space.
+unsafe fn account_mut(object: *const Object)
+        -> Result<&'static mut Account, RamError> {
+    let account = find_account(object);
+    if !account.is_null() {
+        return Ok(&mut *account);
+    }
+
+    let space = (*object).space();
+    if space.is_null() {
+        return Err(RamError::NoSpace);
+    }
+    let account = Account {
+        object  : object,
+        space   : space,
+        quota   : DEFAULT_QUOTA,
+        used    : 0,
+        blocks  : null_mut(),
+        next    : ACCOUNTS,
+    };
+    match account_cache_mut().insert(account) {
+        Some(account) => {
+            ACCOUNTS = account;
+            Ok(&mut *account)
+        },
+        None => Err(RamError::NoMemory),
+    }
+}
+
+/// Unmap the pages and release the frames.
+fn unmap(space: &mut AddressSpace, start: VirtAddr, size: u64) {
+    if space.unmap_release(start, size).is_err() {
+        panic!("Failed to unmap RAM block");
+    }
+    stats::uncharge(Subsystem::ObjectRam, (size / PAGE_SIZE) as usize);
+}
+
+/// Map fresh zeroed frames at the range.
+fn map(space: &mut AddressSpace, start: VirtAddr, pages: u64)
+        -> Result<(), RamError> {
+    for i in 0..pages {
+        let frame = match page_alloc_mut().alloc_frame() {
+            Ok(frame)   => frame,
+            Err(_)      => {
+                unmap(space, start, i * PAGE_SIZE);
+                return Err(RamError::NoMemory);
+            },
+        };
+        unsafe {
+            ::core::ptr::write_bytes(frame.as_mut_ptr::<u8>(), 0,
+                    PAGE_SIZE as usize);
+        }
+
+        let page = start + i * PAGE_SIZE;
+        if space.map_page(page, frame, PageSize::Size4k, RAM_FLAGS).is_err() {
+            unsafe { let _ = page_alloc_mut().release_frame(frame); }
+            unmap(space, start, i * PAGE_SIZE);
+            return Err(RamError::NoMemory);
+        }
+        stats::charge(Subsystem::ObjectRam, 1);
+    }
+    Ok(())
+}
+
+/// Set the maximal count of pages the object can hold. Pages that are
+/// already held are not released when the quota gets lower.
+///
+/// # Errors
+/// NoSpace error occurs when object has no account yet and has no own
+/// address space. NoMemory error occurs when account cannot be
+/// created.
+pub fn set_quota(object: *const Object, pages: u64) -> Result<(), RamError> {
+    let account = try!(unsafe { account_mut(object) });
+    account.quota = pages;
+    Ok(())
+}
+
+/// Count of pages the object holds and it's quota.
+pub fn usage(object: *const Object) -> (u64, u64) {
+    unsafe {
+        let account = find_account(object);
+        if account.is_null() {
+            (0, DEFAULT_QUOTA)
+        } else {
+            ((*account).used, (*account).quota)
+        }
+    }
+}
+
+/// Memory usage of the object. None if object has never requested
+/// memory.
+pub fn object_stats(object: *const Object) -> Option<ObjectStats> {
+    unsafe {
+        let account = find_account(object);
+        if account.is_null() {
+            None
+        } else {
+            Some((*account).stats())
+        }
+    }
+}
+
+/// Print memory usage of the system, CCS caches and each object that
+/// holds memory on the kernel logger.
+pub fn dump_stats() {
+    use ::early::logger;
+    use ::ccs::cache::cache_stats;
+    use core::fmt::Write;
+
+    ::mem::stats::dump();
+
+    let caches = [
+        (block_cache_mut().name(),      block_cache_mut().stats()),
+        (account_cache_mut().name(),    account_cache_mut().stats()),
+    ];
+    for &(name, stats) in cache_stats().iter().chain(caches.iter()) {
+        write!(logger(), "  cache {}: {} objects, {} slabs, peak {}\n",
+            name, stats.in_use, stats.slabs, stats.peak).unwrap();
+    }
+
+    unsafe {
+        let mut account = ACCOUNTS;
+        while !account.is_null() {
+            let stats = (*account).stats();
+            let name = &*(*(*account).object).name;
+            write!(logger(),
+                "  object {}: {}/{} pages in {} blocks, {} tables, {} \
+                record bytes\n", name, stats.pages, stats.quota,
+                stats.blocks, stats.tables, stats.records).unwrap();
+            account = (*account).next;
+        }
+    }
+}
+
+/// Allocate given count of pages for the object and map them in it's
+/// address space. Pages are filled with zeroes.
+///
+/// # Errors
+/// See `RamError` variants.
+pub fn allocate(object: *const Object, pages: u64)
+        -> Result<VirtAddr, RamError> {
+    if pages == 0 || pages > (USER_RAM_END - USER_RAM_BASE) / PAGE_SIZE {
+        return Err(RamError::InvalidSize);
+    }
+
+    unsafe {
+        let account = try!(account_mut(object));
+        if account.used + pages > account.quota {
+            return Err(RamError::QuotaExceeded);
+        }
+
+        let (start, prev) = match account.find_free(pages) {
+            Some(found) => found,
+            None        => return Err(RamError::NoVirtualSpace),
+        };
+
+        let block = Block {
+            start   : start,
+            pages   : pages,
+            next    : null_mut(),
+        };
+        let block = match block_cache_mut().insert(block) {
+            Some(block) => block,
+            None        => return Err(RamError::NoMemory),
+        };
+
+        if let Err(e) = map(&mut *account.space, start, pages) {
+            block_cache_mut().remove(block);
+            return Err(e);
+        }
+
+        if prev.is_null() {
+            (*block).next = account.blocks;
+            account.blocks = block;
+        } else {
+            (*block).next = (*prev).next;
+            (*prev).next = block;
+        }
+        account.used += pages;
+        Ok(start)
+    }
+}
+
+/// Release the block that was allocated by the object. Block must be
+/// released whole.
+///
+/// # Errors
+/// NotAllocated error occurs when object has no block that starts at
+/// given address and has given size.
+pub fn release(object: *const Object, start: VirtAddr, pages: u64)
+        -> Result<(), RamError> {
+    unsafe {
+        let account = find_account(object);
+        if account.is_null() {
+            return Err(RamError::NotAllocated);
+        }
+        let account = &mut *account;
+
+        let (block, prev) = match account.find_block(start) {
+            Some(found) => found,
+            None        => return Err(RamError::NotAllocated),
+        };
+        if (*block).pages != pages {
+            return Err(RamError::NotAllocated);
+        }
+
+        unmap(&mut *account.space, start, pages * PAGE_SIZE);
+        if prev.is_null() {
+            account.blocks = (*block).next;
+        } else {
+            (*prev).next = (*block).next;
+        }
+        account.used -= pages;
+        block_cache_mut().remove(block);
+        Ok(())
+    }
+}
+
+/// Release all memory of the object and remove it's account. Called by
+/// destructor hook of CCS object caches when object gets destroyed.
+pub fn release_all(object: *const Object) {
+    unsafe {
+        let mut prev: *mut Account = null_mut();
+        let mut account = ACCOUNTS;
+        while !account.is_null() && (*account).object != object {
+            prev = account;
+            account = (*account).next;
+        }
+        if account.is_null() {
+            return;
+        }
+
+        let mut block = (*account).blocks;
+        while !block.is_null() {
+            let next = (*block).next;
+            let size = (*block).pages * PAGE_SIZE;
+            unmap(&mut *(*account).space, (*block).start, size);
+            block_cache_mut().remove(block);
+            block = next;
+        }
+
+        if prev.is_null() {
+            ACCOUNTS = (*account).next;
+        } else {
+            (*prev).next = (*account).next;
+        }
+        account_cache_mut().remove(account);
+    }
+}
+
+/// Entry point of "allocate" service. Argument 0 holds count of pages
+/// and receives the address of allocated memory.
+pub extern fn allocate_service(caller: &mut Object, args: &mut ServiceArgs)
+        -> u64 {
+    match allocate(caller as *const Object, args[0]) {
+        Ok(addr) => {
+            args[0] = addr.as_u64();
+            0
+        },
+        Err(e) => e.code(),
+    }
+}
+
+/// Entry point of "stats" service. Arguments receive count of pages the
+/// requester holds, it's quota, count of paging tables of it's address
+/// space and bytes of slab objects that hold records about it. Usage of
+/// the whole system is printed by `dump_stats`.
+pub extern fn stats_service(caller: &mut Object, args: &mut ServiceArgs)
+        -> u64 {
+    let stats = match object_stats(caller as *const Object) {
+        Some(stats) => stats,
+        None        => ObjectStats {
+            pages   : 0,
+            quota   : DEFAULT_QUOTA,
+            blocks  : 0,
+            tables  : 0,
+            records : shared::record_bytes(caller as *const Object) as u64,
+        },
+    };
+    args[0] = stats.pages;
+    args[1] = stats.quota;
+    args[2] = stats.tables;
+    args[3] = stats.records;
+    0
+}
+
+/// Entry point of "release" service. Argument 0 holds the address of
+/// allocated memory and argument 1 holds count of pages.
+pub extern fn release_service(caller: &mut Object, args: &mut ServiceArgs)
+        -> u64 {
+    let start = match VirtAddr::try_new(args[0]) {
+        Some(start) => start,
+        None        => return RamError::NotAllocated.code(),
+    };
+    match release(caller as *const Object, start, args[1]) {
+        Ok(_)   => 0,
+        Err(e)  => e.code(),
+    }
+}
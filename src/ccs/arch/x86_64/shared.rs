@@ -0,0 +1,564 @@
+use ::ccs::Object;
+use ::mem::{VirtAddr, PAGE_SIZE, FrameAlloc};
+use ::mem::map::{USER_SHARED_BASE, USER_SHARED_END};
+use ::mem::page_alloc_mut;
+use ::mem::slab::ObjectCache;
+use ::mem::space::*;
+use ::mem::stats::{self, Subsystem};
+use core::ptr::null_mut;
+
+/// Handle of the shared region. Handles are never reused.
+pub type RegionHandle = u64;
+
+/// Access rights that object gets to the shared region.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Errors of shared region operations.
+pub enum SharedError {
+
+    /// Requester has no own address space.
+    NoSpace,
+
+    /// Region has zero size or the size is too big.
+    InvalidSize,
+
+    /// No free frames, no memory for paging tables or region records.
+    NoMemory,
+
+    /// No free range in the shared window of the address space.
+    NoVirtualSpace,
+
+    /// There is no region with given handle or name.
+    NotFound,
+
+    /// Only the object that created the region can do this.
+    NotOwner,
+
+    /// Region was not granted to the object.
+    NotGranted,
+
+    /// Region is already mapped in the address space of the object.
+    AlreadyAttached,
+}
+
+/// Mapping of the region in some address space.
+#[derive(Clone, Copy)]
+struct Mapping {
+
+    /// Address space the region is mapped in.
+    space   : *mut AddressSpace,
+
+    /// First byte of the region in the space.
+    start   : VirtAddr,
+}
+
+/// Permission of the object to map the region.
+struct Grant {
+
+    /// Object that got the region.
+    grantee : *const Object,
+
+    /// Access rights of the object.
+    access  : Access,
+
+    /// Mapping of the region in the space of the object if it attached.
+    mapping : Option<Mapping>,
+
+    /// Next grant of the region.
+    next    : *mut Grant,
+}
+
+/// Pages created by one CCS object that can be mapped by the objects the
+/// region was granted to. Frames are shared, usage counter of each frame
+/// counts all spaces the frame is mapped in.
+struct Region {
+
+    /// Handle of the region.
+    handle  : RegionHandle,
+
+    /// Name the region can be found by.
+    name    : &'static str,
+
+    /// Object that created the region.
+    owner   : *const Object,
+
+    /// Count of pages of the region.
+    pages   : u64,
+
+    /// Mapping of the region in the space of the owner.
+    mapping : Mapping,
+
+    /// Objects the region was granted to.
+    grants  : *mut Grant,
+
+    /// Next region in the list of all regions.
+    next    : *mut Region,
+}
+
+/// Cache of the regions.
+static mut REGION_CACHE: ObjectCache<Region> =
+        ObjectCache::new("shared-region", None, None);
+
+/// Cache of the grants.
+static mut GRANT_CACHE: ObjectCache<Grant> =
+        ObjectCache::new("shared-grant", None, None);
+
+/// List of all regions.
+static mut REGIONS: *mut Region = 0 as *mut Region;
+
+/// Handle of the next created region.
+static mut NEXT_HANDLE: RegionHandle = 1;
+
+impl SharedError {
+
+    /// Description of the error.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            SharedError::NoSpace            => "object has no address space",
+            SharedError::InvalidSize        => "invalid size",
+            SharedError::NoMemory           => "out of memory",
+            SharedError::NoVirtualSpace     => "no free virtual memory",
+            SharedError::NotFound           => "region not found",
+            SharedError::NotOwner           => "not an owner of the region",
+            SharedError::NotGranted         => "region was not granted",
+            SharedError::AlreadyAttached    => "region is already attached",
+        }
+    }
+}
+
+impl Access {
+
+    /// Flags of the pages mapped with this access.
+    fn page_flags(&self) -> u64 {
+        match *self {
+            Access::ReadOnly    => USER | NO_EXECUTE,
+            Access::ReadWrite   => USER | NO_EXECUTE | WRITABLE,
+        }
+    }
+}
+
+impl Mapping {
+
+    /// Unmap the region and drop references to it's frames.
+    unsafe fn unmap(&self, pages: u64) {
+        let space = &mut *self.space;
+        if space.unmap_release(self.start, pages * PAGE_SIZE).is_err() {
+            panic!("Failed to unmap shared region");
+        }
+    }
+
+    /// Whether mapping of given size overlaps the range of the space.
+    fn overlaps(&self, pages: u64, space: *mut AddressSpace, start: u64,
+            size: u64) -> bool {
+        let from = self.start.as_u64();
+        let to = from + pages * PAGE_SIZE;
+        self.space == space && start < to && from < start + size
+    }
+}
+
+impl Region {
+
+    /// Grant of given object.
+    unsafe fn grant_of(&self, grantee: *const Object) -> *mut Grant {
+        let mut grant = self.grants;
+        while !grant.is_null() && (*grant).grantee != grantee {
+            grant = (*grant).next;
+        }
+        grant
+    }
+}
+
+/// Cache of the regions.
+fn region_cache_mut() -> &'static mut ObjectCache<Region> {
+    unsafe { &mut REGION_CACHE }
+}
+
+/// Cache of the grants.
+fn grant_cache_mut() -> &'static mut ObjectCache<Grant> {
+    unsafe { &mut GRANT_CACHE }
+}
+
+/// Region with given handle. Null if there is no such region.
+unsafe fn find_region(handle: RegionHandle) -> *mut Region {
+    let mut region = REGIONS;
+    while !region.is_null() && (*region).handle != handle {
+        region = (*region).next;
+    }
+    region
+}
+
+/// Address space of the object.
+unsafe fn object_space(object: *const Object)
+        -> Result<*mut AddressSpace, SharedError> {
+    let space = (*object).space();
+    if space.is_null() {
+        Err(SharedError::NoSpace)
+    } else {
+        Ok(space)
+    }
+}
+
+/// Find free range of the shared window of the space.
+unsafe fn find_free(space: *mut AddressSpace, pages: u64)
+        -> Result<VirtAddr, SharedError> {
+    let size = pages * PAGE_SIZE;
+    let mut start = USER_SHARED_BASE;
+
+    // First fit. Start is moved behind each overlapping mapping until
+    // range is free.
+    'search: while start + size <= USER_SHARED_END {
+        let mut region = REGIONS;
+        while !region.is_null() {
+            let r = &*region;
+            let mut mapping = Some(r.mapping);
+            let mut grant = r.grants;
+            loop {
+                if let Some(m) = mapping {
+                    if m.overlaps(r.pages, space, start, size) {
+                        start = m.start.as_u64() + r.pages * PAGE_SIZE;
+                        continue 'search;
+                    }
+                }
+                if grant.is_null() {
+                    break;
+                }
+                mapping = (*grant).mapping;
+                grant = (*grant).next;
+            }
+            region = r.next;
+        }
+        return Ok(VirtAddr::new(start));
+    }
+    Err(SharedError::NoVirtualSpace)
+}
+
+/// Map fresh zeroed frames at the range. Mapped part is released on
+/// error.
+fn map_new(space: &mut AddressSpace, start: VirtAddr, pages: u64)
+        -> Result<(), SharedError> {
+    let flags = Access::ReadWrite.page_flags();
+    for i in 0..pages {
+        let frame = match page_alloc_mut().alloc_frame() {
+            Ok(frame)   => frame,
+            Err(_)      => {
+                let _ = space.unmap_release(start, i * PAGE_SIZE);
+                return Err(SharedError::NoMemory);
+            },
+        };
+        unsafe {
+            ::core::ptr::write_bytes(frame.as_mut_ptr::<u8>(), 0,
+                    PAGE_SIZE as usize);
+        }
+
+        let page = start + i * PAGE_SIZE;
+        if space.map_page(page, frame, PageSize::Size4k, flags).is_err() {
+            unsafe { let _ = page_alloc_mut().release_frame(frame); }
+            let _ = space.unmap_release(start, i * PAGE_SIZE);
+            return Err(SharedError::NoMemory);
+        }
+    }
+    Ok(())
+}
+
+/// Map frames of the region into other space. Usage counter of each
+/// frame is incremented. Mapped part is released on error.
+unsafe fn map_shared(region: &Region, space: &mut AddressSpace,
+        start: VirtAddr, access: Access) -> Result<(), SharedError> {
+    let owner = &*region.mapping.space;
+    let flags = access.page_flags();
+    for i in 0..region.pages {
+        let off = i * PAGE_SIZE;
+        let frame = owner.translate(region.mapping.start + off).unwrap().phys;
+        if space.map_page(start + off, frame, PageSize::Size4k, flags)
+                .is_err() {
+            let _ = space.unmap_release(start, off);
+            return Err(SharedError::NoMemory);
+        }
+        if let Some(status) = page_alloc_mut().status_mut(frame) {
+            status.inc_user();
+        }
+    }
+    Ok(())
+}
+
+/// Create shared region of given count of pages and map it in the
+/// address space of the owner. Pages are filled with zeroes. Name must
+/// live as long as the kernel as regions are found by it.
+///
+/// # Errors
+/// See `SharedError` variants.
+pub fn create(owner: *const Object, name: &'static str, pages: u64)
+        -> Result<RegionHandle, SharedError> {
+    let max = (USER_SHARED_END - USER_SHARED_BASE) / PAGE_SIZE;
+    if pages == 0 || pages > max {
+        return Err(SharedError::InvalidSize);
+    }
+
+    unsafe {
+        let space = try!(object_space(owner));
+        let start = try!(find_free(space, pages));
+
+        let region = Region {
+            handle  : NEXT_HANDLE,
+            name    : name,
+            owner   : owner,
+            pages   : pages,
+            mapping : Mapping { space: space, start: start },
+            grants  : null_mut(),
+            next    : REGIONS,
+        };
+        let region = match region_cache_mut().insert(region) {
+            Some(region)    => region,
+            None            => return Err(SharedError::NoMemory),
+        };
+
+        if let Err(e) = map_new(&mut *space, start, pages) {
+            region_cache_mut().remove(region);
+            return Err(e);
+        }
+
+        stats::charge(Subsystem::ObjectRam, pages as usize);
+        NEXT_HANDLE += 1;
+        REGIONS = region;
+        Ok((*region).handle)
+    }
+}
+
+/// Handle of the region with given name.
+pub fn find(name: &str) -> Option<RegionHandle> {
+    unsafe {
+        let mut region = REGIONS;
+        while !region.is_null() {
+            if (*region).name == name {
+                return Some((*region).handle);
+            }
+            region = (*region).next;
+        }
+        None
+    }
+}
+
+/// Address of the region in the space of the owner.
+pub fn owner_address(handle: RegionHandle) -> Option<VirtAddr> {
+    unsafe {
+        let region = find_region(handle);
+        if region.is_null() {
+            None
+        } else {
+            Some((*region).mapping.start)
+        }
+    }
+}
+
+/// Allow the object to map the region with given access. Access of the
+/// object that already has the grant is changed. When the region is
+/// attached it's pages get new access right away so write access can be
+/// revoked.
+///
+/// # Errors
+/// NotFound, NotOwner or NoMemory error.
+pub fn grant(owner: *const Object, handle: RegionHandle,
+        grantee: *const Object, access: Access) -> Result<(), SharedError> {
+    unsafe {
+        let region = find_region(handle);
+        if region.is_null() {
+            return Err(SharedError::NotFound);
+        }
+        let region = &mut *region;
+        if region.owner != owner {
+            return Err(SharedError::NotOwner);
+        }
+
+        let grant = region.grant_of(grantee);
+        if !grant.is_null() {
+            if let Some(mapping) = (*grant).mapping {
+                let space = &mut *mapping.space;
+                let size = region.pages * PAGE_SIZE;
+                let flags = access.page_flags();
+                if space.protect(mapping.start, size, flags).is_err() {
+                    return Err(SharedError::NoMemory);
+                }
+            }
+            (*grant).access = access;
+            return Ok(());
+        }
+
+        let grant = Grant {
+            grantee : grantee,
+            access  : access,
+            mapping : None,
+            next    : region.grants,
+        };
+        match grant_cache_mut().insert(grant) {
+            Some(grant) => {
+                region.grants = grant;
+                Ok(())
+            },
+            None => Err(SharedError::NoMemory),
+        }
+    }
+}
+
+/// Map the region that was granted to the object in the address space of
+/// the object. Returns the address of the region.
+///
+/// # Errors
+/// See `SharedError` variants.
+pub fn attach(grantee: *const Object, handle: RegionHandle)
+        -> Result<VirtAddr, SharedError> {
+    unsafe {
+        let region = find_region(handle);
+        if region.is_null() {
+            return Err(SharedError::NotFound);
+        }
+        let region = &mut *region;
+
+        let grant = region.grant_of(grantee);
+        if grant.is_null() {
+            return Err(SharedError::NotGranted);
+        }
+        let grant = &mut *grant;
+        if grant.mapping.is_some() {
+            return Err(SharedError::AlreadyAttached);
+        }
+
+        let space = try!(object_space(grantee));
+        let start = try!(find_free(space, region.pages));
+        try!(map_shared(region, &mut *space, start, grant.access));
+        grant.mapping = Some(Mapping { space: space, start: start });
+        Ok(start)
+    }
+}
+
+/// Unmap the region from the space of the object. The grant stays so the
+/// region can be attached again.
+///
+/// # Errors
+/// NotFound or NotGranted error. NotGranted is also returned when the
+/// region is not attached.
+pub fn detach(grantee: *const Object, handle: RegionHandle)
+        -> Result<(), SharedError> {
+    unsafe {
+        let region = find_region(handle);
+        if region.is_null() {
+            return Err(SharedError::NotFound);
+        }
+        let region = &mut *region;
+
+        let grant = region.grant_of(grantee);
+        if grant.is_null() {
+            return Err(SharedError::NotGranted);
+        }
+        match (*grant).mapping.take() {
+            Some(mapping)   => mapping.unmap(region.pages),
+            None            => return Err(SharedError::NotGranted),
+        }
+        Ok(())
+    }
+}
+
+/// Unmap the region from all spaces and remove all it's grants. Frames
+/// are released when they are not mapped anywhere else.
+unsafe fn remove(region: *mut Region) {
+    let r = &mut *region;
+    let mut grant = r.grants;
+    while !grant.is_null() {
+        let next = (*grant).next;
+        if let Some(mapping) = (*grant).mapping {
+            mapping.unmap(r.pages);
+        }
+        grant_cache_mut().remove(grant);
+        grant = next;
+    }
+    r.mapping.unmap(r.pages);
+    stats::uncharge(Subsystem::ObjectRam, r.pages as usize);
+    region_cache_mut().remove(region);
+}
+
+/// Remove the region. It is unmapped from the spaces of all objects it
+/// was granted to.
+///
+/// # Errors
+/// NotFound or NotOwner error.
+pub fn destroy(owner: *const Object, handle: RegionHandle)
+        -> Result<(), SharedError> {
+    unsafe {
+        let mut prev: *mut Region = null_mut();
+        let mut region = REGIONS;
+        while !region.is_null() && (*region).handle != handle {
+            prev = region;
+            region = (*region).next;
+        }
+        if region.is_null() {
+            return Err(SharedError::NotFound);
+        }
+        if (*region).owner != owner {
+            return Err(SharedError::NotOwner);
+        }
+
+        if prev.is_null() {
+            REGIONS = (*region).next;
+        } else {
+            (*prev).next = (*region).next;
+        }
+        remove(region);
+        Ok(())
+    }
+}
+
+/// Revoke all regions created by the object and detach the object from
+/// regions granted to it. Called by destructor hook of CCS object caches
+/// when object gets destroyed.
+pub fn release_all(object: *const Object) {
+    unsafe {
+        let mut prev: *mut Region = null_mut();
+        let mut region = REGIONS;
+        while !region.is_null() {
+            let next = (*region).next;
+            if (*region).owner == object {
+                if prev.is_null() {
+                    REGIONS = next;
+                } else {
+                    (*prev).next = next;
+                }
+                remove(region);
+            } else {
+                let grant = (*region).grant_of(object);
+                if !grant.is_null() {
+                    if let Some(mapping) = (*grant).mapping.take() {
+                        mapping.unmap((*region).pages);
+                    }
+                }
+                prev = region;
+            }
+            region = next;
+        }
+    }
+}
+
+/// Bytes of slab objects that hold records of the regions that the object
+/// created and of their grants.
+pub fn record_bytes(object: *const Object) -> usize {
+    use core::mem::size_of;
+
+    let mut bytes = 0;
+    unsafe {
+        let mut region = REGIONS;
+        while !region.is_null() {
+            if (*region).owner == object {
+                bytes += size_of::<Region>();
+                let mut grant = (*region).grants;
+                while !grant.is_null() {
+                    bytes += size_of::<Grant>();
+                    grant = (*grant).next;
+                }
+            }
+            region = (*region).next;
+        }
+    }
+    bytes
+}
@@ -0,0 +1,115 @@
+use super::*;
+use ::mem::slab::{ObjectCache, CacheStats};
+
+/// Cache of root objects that are not stored in any object list.
+static mut OBJECT_CACHE: ObjectCache<Object> =
+        ObjectCache::new("ccs-object", None, Some(release_object_lists));
+
+/// Cache of the nodes of service lists.
+static mut SERVICE_NODE_CACHE: ObjectCache<ServiceListNode> =
+        ObjectCache::new("ccs-service-node", None, None);
+
+/// Cache of the nodes of object lists.
+static mut OBJECT_NODE_CACHE: ObjectCache<ObjectListNode> =
+        ObjectCache::new("ccs-object-node", None, Some(release_node_lists));
+
+/// Cache of root objects.
+pub fn object_cache_mut() -> &'static mut ObjectCache<Object> {
+    unsafe { &mut OBJECT_CACHE }
+}
+
+/// Cache of service list nodes.
+pub fn service_node_cache_mut()
+        -> &'static mut ObjectCache<ServiceListNode> {
+    unsafe { &mut SERVICE_NODE_CACHE }
+}
+
+/// Cache of object list nodes.
+pub fn object_node_cache_mut() -> &'static mut ObjectCache<ObjectListNode> {
+    unsafe { &mut OBJECT_NODE_CACHE }
+}
+
+/// Name and statistics of each CCS cache.
+pub fn cache_stats() -> [(&'static str, CacheStats); 3] {
+    unsafe {[
+        (OBJECT_CACHE.name(),       OBJECT_CACHE.stats()),
+        (SERVICE_NODE_CACHE.name(), SERVICE_NODE_CACHE.stats()),
+        (OBJECT_NODE_CACHE.name(),  OBJECT_NODE_CACHE.stats()),
+    ]}
+}
+
+/// Return all nodes of the service list to the cache.
+fn release_service_list(list: &mut ServiceList) {
+    let mut node = list.top_mut();
+    while let Some(ptr) = node {
+        unsafe {
+            node = *(*ptr).next_ref();
+            service_node_cache_mut().remove(ptr);
+        }
+    }
+    list.set_top(None);
+}
+
+/// Return all nodes of the object list to the cache. Sub-objects release
+/// their lists by the destructor hook of the node cache.
+fn release_object_list(list: &mut ObjectList) {
+    let mut node = list.top_mut();
+    while let Some(ptr) = node {
+        unsafe {
+            node = *(*ptr).next_ref();
+            object_node_cache_mut().remove(ptr);
+        }
+    }
+    list.set_top(None);
+}
+
+/// Destructor hook of objects. Releases all the services and sub-objects
+/// so whole subtree returns to the caches. Memory that RAM manager gave
+/// to the object and it's shared regions are released too.
+fn release_object_lists(obj: &mut Object) {
+    ram::release_all(obj);
+    shared::release_all(obj);
+    release_service_list(&mut obj.pub_serv_list);
+    release_service_list(&mut obj.priv_serv_list);
+    release_object_list(&mut obj.pub_obj_list);
+    release_object_list(&mut obj.priv_obj_list);
+}
+
+/// Destructor hook of object list nodes. Releases the subtree of the
+/// object stored in the node.
+fn release_node_lists(node: &mut ObjectListNode) {
+    release_object_lists(node.elem_mut());
+}
+
+/// Place new object list node in the cache.
+///
+/// # Panics
+/// When there is no memory for the node.
+pub fn new_object_node(obj: Object) -> *mut ObjectListNode {
+    match object_node_cache_mut().insert(ObjectListNode::new(obj)) {
+        Some(node)  => node,
+        None        => panic!("No memory for CCS object"),
+    }
+}
+
+/// Place new service list node in the cache.
+///
+/// # Panics
+/// When there is no memory for the node.
+pub fn new_service_node(serv: Service) -> *mut ServiceListNode {
+    match service_node_cache_mut().insert(ServiceListNode::new(serv)) {
+        Some(node)  => node,
+        None        => panic!("No memory for CCS service"),
+    }
+}
+
+/// Place new root object in the cache.
+///
+/// # Panics
+/// When there is no memory for the object.
+pub fn new_object(obj: Object) -> *mut Object {
+    match object_cache_mut().insert(obj) {
+        Some(obj)   => obj,
+        None        => panic!("No memory for CCS object"),
+    }
+}
@@ -1,10 +1,15 @@
 mod arch;
 
-// pub use self::arch::setup;
+pub use self::arch::*;
 
 mod lists;
 use self::lists::*;
 
+use ::mem::space::AddressSpace;
+
+/// Slab caches of CCS objects and list nodes.
+pub mod cache;
+
 /// Module related to channels and communication between two or multiple
 /// objects or single object with itself.
 mod chan;
@@ -12,6 +17,19 @@ mod chan;
 /// Scheduler and it's related traits and structs.
 mod sched;
 
+/// Count of arguments passed to the service.
+pub const SERVICE_ARG_COUNT: usize = 4;
+
+/// Arguments of the service request. Service can overwrite them with
+/// results.
+pub type ServiceArgs = [u64; SERVICE_ARG_COUNT];
+
+/// Entry point of the service provided by the kernel. Receives the object
+/// that requested the service and the arguments. Returns zero on success
+/// or error code of the service.
+pub type ServiceFn = extern fn(caller: &mut Object, args: &mut ServiceArgs)
+        -> u64;
+
 #[derive(Clone, Copy)]
 /// CCS Service handle.
 pub struct Service {
@@ -73,6 +91,10 @@ pub struct Object {
     /// Whether parent private and public object services and objects are
     /// visible for this child.
     is_parent_network_visible   : bool,
+
+    /// Address space where memory of the object gets mapped. Null for
+    /// objects that have no own address space.
+    space               : *mut AddressSpace,
 }
 
 impl Object {
@@ -89,6 +111,19 @@ impl Object {
 
             is_external_network_visible : false,
             is_parent_network_visible   : false,
+
+            space           : 0 as *mut AddressSpace,
         }
     }
+
+    /// Address space of the object. Null if object has no own space.
+    pub fn space(&self) -> *mut AddressSpace {
+        self.space
+    }
+
+    /// Set address space where memory of the object gets mapped. Space
+    /// must live longer than the memory of the object.
+    pub fn set_space(&mut self, space: *mut AddressSpace) {
+        self.space = space;
+    }
 }
@@ -1,9 +1,24 @@
 //! Process List Controller module.
 
-use super::ProcessHandle;
+use super::{ProcessHandle, ProcessState};
+use ::mem::kstack;
+use ::mem::stats::Subsystem;
 
 /// Error of process allocation.
 pub enum ProcessAllocErr {
+
+    /// No free slot or no memory for the kernel stack of the process.
+    NoKernelStack,
+}
+
+impl ProcessAllocErr {
+
+    /// Description of the error.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            ProcessAllocErr::NoKernelStack => "no kernel stack for process",
+        }
+    }
 }
 
 /// Process handle set. This set stores all process handles. Different
@@ -37,11 +52,30 @@ pub trait ProcessSet {
     /// External controller must ensure no more pointers for this
     /// process exist. Otherwise, remain pointers will become dangling.
     unsafe fn remove(&mut self, p: &Self::P) {
-        self.remove_id(p.id())
+        let id = p.id();
+        if let Some(p) = self.process_by_id_mut(id) {
+            p.set_state(ProcessState::End);
+        }
+        self.remove_id(id)
     }
 
-    /// Create new process entry in this set.
+    /// Create new process entry in this set. Use `spawn` to create the
+    /// process with it's kernel stack.
     fn new_process(&mut self) -> Result<Self::P, ProcessAllocErr>;
+
+    /// Create new process entry with the kernel stack of it's thread.
+    /// Stack is dropped when the process reaches the end.
+    fn spawn(&mut self) -> Result<Self::P, ProcessAllocErr> {
+        let mut p = try!(self.new_process());
+        match kstack::alloc(p.id(), Subsystem::KernelStacks) {
+            Some(stack) => *p.kernel_stack_mut() = Some(stack),
+            None        => {
+                unsafe { self.remove_id(p.id()); }
+                return Err(ProcessAllocErr::NoKernelStack);
+            },
+        }
+        Ok(p)
+    }
 }
 
 /// Queue of processes. Processes in the queue are automatically
@@ -1,6 +1,7 @@
 mod arch;
 mod list;
 use self::list::*;
+use ::mem::kstack::KernelStack;
 
 /// All process states.
 pub enum ProcessState {
@@ -35,8 +36,32 @@ pub trait ProcessHandle : Sized {
     /// Process current state.
     fn state(&self) -> ProcessState;
 
+    /// Store new state of the process. Resources are not released, use
+    /// `set_state` to change the state.
+    fn store_state(&mut self, state: ProcessState);
+
     /// Process ID value.
     fn id(&self) -> u32;
+
+    /// Kernel stack of the process thread. None when the stack was not
+    /// allocated yet or was already released.
+    fn kernel_stack_mut(&mut self) -> &mut Option<KernelStack>;
+
+    /// Change the state of the process. Kernel stack is dropped when the
+    /// process reaches the end. Process may still run on the stack so it's
+    /// memory is released by `kstack::reap` after the switch to other
+    /// thread.
+    fn set_state(&mut self, state: ProcessState) {
+        let end = match state {
+            ProcessState::End   => true,
+            _                   => false,
+        };
+        self.store_state(state);
+
+        if end {
+            self.kernel_stack_mut().take();
+        }
+    }
 }
 
 /// The CPU unit that is running single thread. Used to assign to it
@@ -1,5 +1,37 @@
 use super::LoggerTrait;
 use core::fmt::{Write, Error};
+use mem::PhysAddr;
+use mem::mmio::{MmioRegion, CacheType, map_mmio};
+
+/// Physical address of VGA text buffer.
+const TEXT_BUFFER: u64 = 0xB8000;
+
+/// Size of VGA text buffer with 80x25 cells.
+const TEXT_BUFFER_SIZE: u64 = 80 * 25 * 2;
+
+/// VGA text buffer in MMIO window. Is None until `map_text_buffer` call.
+static mut TEXT_REGION: Option<MmioRegion> = None;
+
+/// Address of VGA text buffer. Before kernel paging is set the buffer is
+/// accessed through the direct map of the boot code.
+fn text_buffer() -> isize {
+    unsafe {
+        match TEXT_REGION {
+            Some(ref region) => region.virt().as_u64() as isize,
+            None => PhysAddr::new(TEXT_BUFFER).to_virt().as_u64() as isize,
+        }
+    }
+}
+
+/// Map VGA text buffer in MMIO window. Kernel direct map has no device
+/// memory so this must be called right after kernel paging is loaded.
+pub fn map_text_buffer() {
+    let phys = PhysAddr::new(TEXT_BUFFER);
+    match map_mmio(phys, TEXT_BUFFER_SIZE, CacheType::Uncacheable) {
+        Ok(region)  => unsafe { TEXT_REGION = Some(region); },
+        Err(_)      => ::halt_forever(),
+    }
+}
 
 pub struct Logger {
     /// Index of a cell being updated.
@@ -15,8 +47,9 @@ impl Logger {
     /// Shift all symbols up by one line.
     fn shift(&mut self) {
         // Point to second row on the screen.
-        let mut i = 0xB8000 + 80 * 2;
-        while i < 0xB8000 + 80 * 2 * 25 {
+        let buffer = text_buffer();
+        let mut i = buffer + 80 * 2;
+        while i < buffer + 80 * 2 * 25 {
             let cell_src = (i + 80 * 2) as *mut i16;
             let cell_dst = (i         ) as *mut i16;
 
@@ -27,7 +60,7 @@ impl Logger {
     }
 
     fn set(&self, c: char) {
-        let cell = (self.index as isize * 2 + 0xB8000) as *mut i16;
+        let cell = (self.index as isize * 2 + text_buffer()) as *mut i16;
         unsafe { *cell = 0x0700 | (c as i16); }
 
         ::arch::port::Port::from(0x2E8u16).out_u8(c as u8);
@@ -7,3 +7,6 @@ pub use self::logger::*;
 /// Interrupts setup.
 mod interrupts;
 pub use self::interrupts::*;
+
+/// Multiboot information structure parser.
+pub mod multiboot;
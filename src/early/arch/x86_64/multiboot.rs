@@ -0,0 +1,355 @@
+//! Multiboot information structure that boot loader passes to the kernel.
+//! Only the fields that kernel uses are interpreted. See Multiboot
+//! Specification version 0.6.96 for the full structure layout.
+
+use mem::{Region, RegionKind, RegionList, RegionListError, PhysAddr};
+use mem::VirtAddr;
+
+/// Magic value that Multiboot compliant boot loader stores in EAX.
+pub const BOOTLOADER_MAGIC: u32 = 0x2BADB002;
+
+/// Flag bits of the information structure. Each bit tells whether related
+/// fields are valid.
+const FLAG_MEM          : u32 = 1 << 0;
+const FLAG_CMDLINE      : u32 = 1 << 2;
+const FLAG_MODS         : u32 = 1 << 3;
+const FLAG_MMAP         : u32 = 1 << 6;
+const FLAG_LOADER_NAME  : u32 = 1 << 9;
+
+/// Memory map entry types.
+const MMAP_AVAILABLE        : u32 = 1;
+const MMAP_ACPI_RECLAIMABLE : u32 = 3;
+const MMAP_ACPI_NVS         : u32 = 4;
+const MMAP_DEFECTIVE        : u32 = 5;
+
+/// Multiboot information structure.
+#[repr(C, packed)]
+pub struct MultibootInfo {
+    flags               : u32,
+    mem_lower           : u32,
+    mem_upper           : u32,
+    boot_device         : u32,
+    cmdline             : u32,
+    mods_count          : u32,
+    mods_addr           : u32,
+    syms                : [u32; 4],
+    mmap_length         : u32,
+    mmap_addr           : u32,
+    drives_length       : u32,
+    drives_addr         : u32,
+    config_table        : u32,
+    boot_loader_name    : u32,
+}
+
+/// Entry of the memory map provided by the boot loader.
+#[repr(C, packed)]
+pub struct MmapEntry {
+
+    /// Size of the entry without this field.
+    size    : u32,
+    base    : u64,
+    length  : u64,
+    kind    : u32,
+}
+
+/// Boot module loaded by the boot loader.
+#[repr(C, packed)]
+pub struct Module {
+    start   : u32,
+    end     : u32,
+    string  : u32,
+    reserved: u32,
+}
+
+/// Iterator over memory map entries.
+pub struct MmapIter<'a> {
+    cur     : usize,
+    end     : usize,
+    info    : &'a MultibootInfo,
+}
+
+/// Iterator over boot modules.
+pub struct ModuleIter<'a> {
+    index   : u32,
+    info    : &'a MultibootInfo,
+}
+
+/// Pointer to the structure at given physical address. Boot loader gives
+/// physical addresses which are accessed through the direct map.
+fn phys_ptr<T>(addr: usize) -> *const T {
+    PhysAddr::new(addr as u64).as_ptr()
+}
+
+/// Region of reserved memory that holds C string at given physical
+/// address including terminating zero.
+///
+/// # Safety
+/// Address must point to valid zero-terminated UTF-8 string.
+unsafe fn c_str_region(addr: usize) -> Region {
+    let len = c_str(addr).len() as u64 + 1;
+    Region::new(addr as u64, addr as u64 + len, RegionKind::Reserved)
+}
+
+/// Get string slice from C string with terminating zero.
+///
+/// # Safety
+/// Address must point to valid zero-terminated UTF-8 string.
+unsafe fn c_str<'a>(addr: usize) -> &'a str {
+    use core::{slice, str};
+
+    let ptr = phys_ptr::<u8>(addr);
+    let mut len = 0;
+    while *ptr.offset(len as _) != 0 {
+        len += 1;
+    }
+
+    str::from_utf8_unchecked(slice::from_raw_parts(ptr, len))
+}
+
+impl MultibootInfo {
+
+    /// Get information structure by the physical address that boot loader
+    /// passed to the kernel.
+    ///
+    /// # Safety
+    /// Address must point to valid structure. Memory of the structure must
+    /// be accessible and must not be overwritten while reference lives.
+    pub unsafe fn from_addr<'a>(addr: usize) -> &'a MultibootInfo {
+        &*phys_ptr::<MultibootInfo>(addr)
+    }
+
+    fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Amount of lower memory in KiB (starting from address 0), if known.
+    pub fn mem_lower(&self) -> Option<u32> {
+        if self.has_flag(FLAG_MEM) {
+            Some(self.mem_lower)
+        } else {
+            None
+        }
+    }
+
+    /// Amount of upper memory in KiB (starting from address 1MiB),
+    /// if known.
+    pub fn mem_upper(&self) -> Option<u32> {
+        if self.has_flag(FLAG_MEM) {
+            Some(self.mem_upper)
+        } else {
+            None
+        }
+    }
+
+    /// Kernel command line, if any.
+    pub fn cmdline(&self) -> Option<&str> {
+        if self.has_flag(FLAG_CMDLINE) {
+            Some(unsafe { c_str(self.cmdline as _) })
+        } else {
+            None
+        }
+    }
+
+    /// Name of the boot loader that started the kernel, if given.
+    pub fn boot_loader_name(&self) -> Option<&str> {
+        if self.has_flag(FLAG_LOADER_NAME) {
+            Some(unsafe { c_str(self.boot_loader_name as _) })
+        } else {
+            None
+        }
+    }
+
+    /// Memory map entries provided by the boot loader, if any.
+    pub fn mmap(&self) -> Option<MmapIter> {
+        if !self.has_flag(FLAG_MMAP) {
+            return None;
+        }
+
+        let start = self.mmap_addr as usize;
+        Some(MmapIter {
+            cur     : start,
+            end     : start + self.mmap_length as usize,
+            info    : self,
+        })
+    }
+
+    /// Boot modules loaded with the kernel.
+    pub fn modules(&self) -> ModuleIter {
+        ModuleIter {
+            index   : 0,
+            info    : self,
+        }
+    }
+
+    /// Count of boot modules.
+    pub fn module_count(&self) -> u32 {
+        if self.has_flag(FLAG_MODS) {
+            self.mods_count
+        } else {
+            0
+        }
+    }
+
+    /// Save regions of memory that hold boot information in given list:
+    /// this structure, the memory map, the command line, the boot loader
+    /// name, boot modules and their strings. This memory must not be
+    /// given away while boot information is still read.
+    ///
+    /// # Errors
+    /// Error is returned if list has not enough space to store all regions.
+    pub fn reserved_regions(&self, list: &mut RegionList)
+            -> Result<(), RegionListError> {
+        use core::mem::size_of;
+
+        let addr = VirtAddr::from_ptr(self as *const Self);
+        let start = addr.direct_map_phys().unwrap().as_u64();
+        try!(list.insert(Region::new(start, start + size_of::<Self>() as u64,
+                RegionKind::Reserved)));
+
+        if self.has_flag(FLAG_MMAP) {
+            let start = self.mmap_addr as u64;
+            try!(list.insert(Region::new(start,
+                    start + self.mmap_length as u64, RegionKind::Reserved)));
+        }
+        if self.has_flag(FLAG_CMDLINE) {
+            try!(list.insert(unsafe { c_str_region(self.cmdline as _) }));
+        }
+        if self.has_flag(FLAG_LOADER_NAME) {
+            let name = self.boot_loader_name as usize;
+            try!(list.insert(unsafe { c_str_region(name) }));
+        }
+
+        if self.module_count() != 0 {
+            let start = self.mods_addr as u64;
+            let size = self.mods_count as u64 * size_of::<Module>() as u64;
+            try!(list.insert(Region::new(start, start + size,
+                    RegionKind::Reserved)));
+        }
+        for module in self.modules() {
+            try!(list.insert(Region::new(module.start(), module.end(),
+                    RegionKind::Reserved)));
+            if module.string != 0 {
+                let string = module.string as usize;
+                try!(list.insert(unsafe { c_str_region(string) }));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Save physical memory regions in given list. If boot loader did not
+    /// provide the memory map, lower and upper memory sizes are used to
+    /// guess usable regions.
+    ///
+    /// # Errors
+    /// Error is returned if list has not enough space to store all regions.
+    pub fn memory_regions(&self, list: &mut RegionList)
+            -> Result<(), RegionListError> {
+        if let Some(mmap) = self.mmap() {
+            for entry in mmap {
+                try!(list.insert(entry.region()));
+            }
+            return Ok(());
+        }
+
+        if self.has_flag(FLAG_MEM) {
+            let lower = self.mem_lower as u64 * 1024;
+            let upper = self.mem_upper as u64 * 1024;
+
+            try!(list.insert(Region::new(0, lower, RegionKind::Usable)));
+            try!(list.insert(Region::new(0x100000, 0x100000 + upper,
+                    RegionKind::Usable)));
+        }
+
+        Ok(())
+    }
+}
+
+impl MmapEntry {
+
+    /// Start address of the region.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Size of the region in bytes.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Type of the memory region.
+    pub fn kind(&self) -> RegionKind {
+        match self.kind {
+            MMAP_AVAILABLE          => RegionKind::Usable,
+            MMAP_ACPI_RECLAIMABLE   => RegionKind::AcpiReclaimable,
+            MMAP_ACPI_NVS           => RegionKind::AcpiNvs,
+            MMAP_DEFECTIVE          => RegionKind::Defective,
+            _                       => RegionKind::Reserved,
+        }
+    }
+
+    /// Convert entry to physical memory region.
+    pub fn region(&self) -> Region {
+        Region::new(self.base, self.base + self.length, self.kind())
+    }
+}
+
+impl Module {
+
+    /// First byte of the module.
+    pub fn start(&self) -> u64 {
+        self.start as _
+    }
+
+    /// Byte after the last byte of the module.
+    pub fn end(&self) -> u64 {
+        self.end as _
+    }
+
+    /// String associated with the module. Usually it is the command line
+    /// of the module.
+    pub fn string(&self) -> Option<&str> {
+        if self.string == 0 {
+            None
+        } else {
+            Some(unsafe { c_str(self.string as _) })
+        }
+    }
+}
+
+impl<'a> Iterator for MmapIter<'a> {
+
+    type Item = &'a MmapEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        use core::mem::size_of;
+
+        if self.cur + size_of::<MmapEntry>() > self.end {
+            return None;
+        }
+
+        let entry = unsafe { &*phys_ptr::<MmapEntry>(self.cur) };
+
+        // Size field does not include itself.
+        self.cur += entry.size as usize + size_of::<u32>();
+
+        Some(entry)
+    }
+}
+
+impl<'a> Iterator for ModuleIter<'a> {
+
+    type Item = &'a Module;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.info.module_count() {
+            return None;
+        }
+
+        let modules = phys_ptr::<Module>(self.info.mods_addr as _);
+        let module = unsafe { &*modules.offset(self.index as _) };
+        self.index += 1;
+
+        Some(module)
+    }
+}
@@ -15,3 +15,15 @@ pub static RAM_ALLOCATE_SERVICE     : &'static str = "allocate";
 
 /// Service to release allocated RAM.
 pub static RAM_RELEASE_SERVICE      : &'static str = "release";
+
+/// Service to get memory usage statistics.
+pub static RAM_STATS_SERVICE        : &'static str = "stats";
+
+/// Debugging object name.
+pub static DEBUG_OBJECT             : &'static str = "debug";
+
+/// Service to print paging tables of the requester.
+pub static DEBUG_PTDUMP_SERVICE     : &'static str = "ptdump";
+
+/// Service to print translation of the address of the requester.
+pub static DEBUG_TRANSLATE_SERVICE  : &'static str = "translate";
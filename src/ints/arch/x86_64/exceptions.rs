@@ -0,0 +1,51 @@
+use super::InterruptFrame;
+use early::logger;
+use core::fmt::Write;
+
+/// Print registers of the interrupted code.
+fn print_frame(frame: &InterruptFrame) {
+    write!(logger(), "  IP {:04X}:{:016X} SP {:04X}:{:016X} FLAGS {:016X}\n",
+        frame.cs, frame.ip, frame.ss, frame.sp, frame.flags).unwrap();
+}
+
+/// Handler of double fault. Runs on own stack from Interrupt Stack Table
+/// so it works even when kernel stack has overflowed. Double fault cannot
+/// be recovered so the processor gets halted.
+#[no_mangle]
+pub extern fn double_fault_handler(error: u64, frame: &InterruptFrame) {
+    let cr2: u64;
+    unsafe { asm!("mov %cr2, $0" : "=r"(cr2) : : : "volatile"); }
+
+    write!(logger(), "DOUBLE FAULT, error code {:X}\n", error).unwrap();
+    print_frame(frame);
+
+    // Page fault handler cannot store interrupt frame when it's own stack
+    // pointer is in the guard, like on too deep nested faults, so the
+    // fault becomes double.
+    let addr = ::mem::VirtAddr::new_truncate(cr2);
+    if let Some(pid) = ::mem::kstack::guard_owner(addr) {
+        write!(logger(), "  kernel stack overflow at {:016X} in process {}\n",
+            cr2, pid).unwrap();
+    } else {
+        write!(logger(), "  last page fault at {:016X}\n", cr2).unwrap();
+    }
+
+    ::halt_forever();
+}
+
+/// Handler of non-maskable interrupt. Runs on own stack from Interrupt
+/// Stack Table as NMI can arrive at any instruction.
+#[no_mangle]
+pub extern fn nmi_handler(_: u64, frame: &InterruptFrame) {
+    write!(logger(), "NMI received\n").unwrap();
+    print_frame(frame);
+}
+
+/// Handler of machine check exception. Hardware error was detected so
+/// the processor gets halted.
+#[no_mangle]
+pub extern fn machine_check_handler(_: u64, frame: &InterruptFrame) {
+    write!(logger(), "MACHINE CHECK\n").unwrap();
+    print_frame(frame);
+    ::halt_forever();
+}
@@ -1,12 +1,76 @@
 use mem::map::IDT as IDT_ADDR;
-use mem::map::APIC_BASE_ADDRESS;
+use mem::mmio::{MmioRegion, CacheType, map_mmio};
+use mem::PhysAddr;
+use mem::gdt::MAX_CPU_COUNT;
 use arch::mem;
-use arch::idt::*;
-use arch::apic;
 use arch::apic::LocalApic;
 use arch::pic::Pic;
 
-static mut LAPIC_ADDR: ::mem::Address = ::mem::Address::null();
+/// Handlers of exceptions that are not related to other modules.
+mod exceptions;
+
+/// Local APIC of current processor. Is None until `init`.
+static mut APIC: Option<LocalApic> = None;
+
+/// Local APIC registers mapped in MMIO window. Region is kept while APIC
+/// is used.
+static mut APIC_REGION: Option<MmioRegion> = None;
+
+/// Count of Local APIC IDs, which are 8-bit in xAPIC mode.
+const APIC_ID_COUNT: usize = 256;
+
+/// Index of each registered processor plus one, by Local APIC ID. Zero
+/// for IDs of processors that were not registered.
+static mut CPU_INDEX: [u8; APIC_ID_COUNT] = [0; APIC_ID_COUNT];
+
+/// Local APIC ID of each registered processor, by index.
+static mut APIC_IDS: [u8; MAX_CPU_COUNT] = [0; MAX_CPU_COUNT];
+
+/// Count of registered processors.
+static mut CPU_COUNT: usize = 0;
+
+/// IA32_APIC_BASE model specific register.
+const IA32_APIC_BASE: u32 = 0x1B;
+
+/// Bits of IA32_APIC_BASE that store physical address of the registers.
+const APIC_BASE_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Size of the memory of Local APIC registers.
+const APIC_SIZE: u64 = 0x1000;
+
+/// Selector of the kernel code segment.
+const CODE_SEG: u16 = ::mem::gdt::KERNEL_CODE_SELECTOR;
+
+/// Type and attributes of the gate: present 64-bit interrupt gate which
+/// can be used only by the kernel.
+const INTERRUPT_GATE: u8 = 0x8E;
+
+/// Count of entries in IDT.
+const IDT_ENTRIES: usize = 256;
+
+/// Exception vectors defined by architecture specs.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum ExceptionVector {
+    DivideError         = 0,
+    Debug               = 1,
+    Nmi                 = 2,
+    Breakpoint          = 3,
+    Overflow            = 4,
+    BoundRange          = 5,
+    InvalidOpcode       = 6,
+    DeviceNotAvailable  = 7,
+    DoubleFault         = 8,
+    InvalidTss          = 10,
+    SegmentNotPresent   = 11,
+    StackFault          = 12,
+    GeneralProtection   = 13,
+    PageFault           = 14,
+    X87Fpu              = 16,
+    AlignmentCheck      = 17,
+    MachineCheck        = 18,
+    Simd                = 19,
+}
 
 /// Interrupt vectors of the kernel core. Vectors from 0 to 31 are defined
 /// by architecture specs and are not listed here.
@@ -33,51 +97,199 @@ pub enum KernelVector {
     AtaSecond   = 47,
 
 
+    /// Request to invalidate TLB entries sent by other processor.
+    TlbShootdown = 253,
+
     /// APIC spurious interrupt.
     /// Must be 0xFF (255).
     ApicSpurious = 255,
 }
 
-/// IDT reference.
-fn idt() -> &'static Idt {
-    unsafe { &*(IDT_ADDR as *const Idt) }
+/// Frame that processor pushes on the stack when interrupt occurs.
+#[repr(C)]
+pub struct InterruptFrame {
+
+    /// Address of the instruction that was interrupted.
+    pub ip      : u64,
+
+    /// Code segment of the interrupted code.
+    pub cs      : u64,
+
+    /// RFLAGS register of the interrupted code.
+    pub flags   : u64,
+
+    /// Stack pointer of the interrupted code.
+    pub sp      : u64,
+
+    /// Stack segment of the interrupted code.
+    pub ss      : u64,
+}
+
+/// Gate descriptor of IDT.
+#[repr(C, packed)]
+struct Gate {
+    offset_low  : u16,
+    selector    : u16,
+    ist         : u8,
+    attributes  : u8,
+    offset_mid  : u16,
+    offset_high : u32,
+    reserved    : u32,
+}
+
+/// Value that is loaded by LIDT instruction.
+#[repr(C, packed)]
+struct IdtPointer {
+    limit       : u16,
+    base        : u64,
 }
 
-/// IDT mutable reference.
-fn idt_mut() -> &'static mut Idt {
-    unsafe { &mut *(IDT_ADDR as *const Idt as *mut Idt) }
+extern {
+    // Service routines of exceptions. Defined in assembly sources.
+    fn isr_page_fault();
+    fn isr_double_fault();
+    fn isr_nmi();
+    fn isr_machine_check();
+    fn isr_tlb_shootdown();
 }
 
 /// Local APIC reference.
+/// Is allowed to be used only after `init` call.
 fn apic() -> &'static LocalApic {
-    unsafe { LAPIC_ADDR.as_ref() }
+    unsafe { APIC.as_ref().unwrap() }
 }
 
-/// Local APIC mutbale reference.
+/// Local APIC mutable reference.
+/// Is allowed to be used only after `init` call.
 fn apic_mut() -> &'static mut LocalApic {
-    unsafe { LAPIC_ADDR.as_ref_mut() }
+    unsafe { APIC.as_mut().unwrap() }
+}
+
+/// Physical address of Local APIC registers of current processor.
+fn apic_base() -> PhysAddr {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdmsr" : "={eax}"(low), "={edx}"(high)
+                : "{ecx}"(IA32_APIC_BASE) : : "volatile");
+    }
+    let base = (high as u64) << 32 | low as u64;
+    PhysAddr::new(base & APIC_BASE_MASK)
+}
+
+/// ID of Local APIC of current processor.
+fn apic_id() -> usize {
+    apic().id() as usize
+}
+
+/// Give next index to current processor. Must be called once by each
+/// processor right after it's Local APIC is mapped.
+///
+/// # Panics
+/// When there are more than MAX_CPU_COUNT processors.
+fn register_cpu() {
+    let id = apic_id();
+    unsafe {
+        if CPU_COUNT == MAX_CPU_COUNT {
+            panic!("Too many processors");
+        }
+        APIC_IDS[CPU_COUNT] = id as u8;
+        CPU_COUNT += 1;
+        CPU_INDEX[id] = CPU_COUNT as u8;
+    }
+}
+
+/// Index of current processor, less than MAX_CPU_COUNT. Indices are
+/// given in order processors get registered and need not match Local
+/// APIC IDs. Zero until APIC is initialized as only bootstrap processor
+/// runs then.
+pub fn cpu_id() -> usize {
+    unsafe {
+        if APIC.is_none() {
+            return 0;
+        }
+        match CPU_INDEX[apic_id()] {
+            0       => panic!("Processor was not registered"),
+            index   => index as usize - 1,
+        }
+    }
+}
+
+/// Send fixed interrupt with given vector to the processor with given
+/// index. Waits until APIC accepts the interrupt.
+pub fn send_ipi(cpu: usize, vector: u8) {
+    let id = unsafe {
+        assert!(cpu < CPU_COUNT);
+        APIC_IDS[cpu]
+    };
+    apic_mut().send_ipi(id, vector);
+}
+
+/// Signal end of interrupt to Local APIC.
+pub fn end_of_interrupt() {
+    apic_mut().end_of_interrupt();
+}
+
+/// Set interrupt gate for given vector. Gate with non-zero IST index
+/// switches to the stack from given entry of Interrupt Stack Table of TSS.
+///
+/// # Safety
+/// Handler must be interrupt service routine that ends with IRETQ.
+pub unsafe fn set_gate(vector: u8, handler: unsafe extern fn(), ist: u8) {
+    let addr = handler as usize as u64;
+    let gate = IDT_ADDR.as_mut_ptr::<Gate>().offset(vector as _);
+    *gate = Gate {
+        offset_low  : addr as u16,
+        selector    : CODE_SEG,
+        ist         : ist,
+        attributes  : INTERRUPT_GATE,
+        offset_mid  : (addr >> 16) as u16,
+        offset_high : (addr >> 32) as u32,
+        reserved    : 0,
+    };
+}
+
+/// Load IDT register with the address of kernel IDT.
+fn load_idt() {
+    use core::mem::size_of;
+
+    let pointer = IdtPointer {
+        limit   : (IDT_ENTRIES * size_of::<Gate>() - 1) as u16,
+        base    : IDT_ADDR.to_virt().as_u64(),
+    };
+    unsafe {
+        asm!("lidt ($0)" : : "r"(&pointer) : "memory" : "volatile");
+    }
 }
 
 /// Initialize IDT and APIC.
 pub fn init() {
     // Zero all bytes of IDT table. This makes all entries treated as
     // unexisting.
-    mem::stosq(IDT_ADDR as _, 0, 4096 / 8);
+    mem::stosq(IDT_ADDR.to_virt().as_usize() as _, 0, 4096 / 8);
 
-    // Allocate APIC interface.
+    // Set handlers of exceptions and load IDT.
     unsafe {
-        use mem::{Allocator, AllocatorAlign, main_alloc_mut};
+        use mem::gdt::{DOUBLE_FAULT_IST, NMI_IST, MACHINE_CHECK_IST};
+        use mem::gdt::PAGE_FAULT_IST;
 
-        main_alloc_mut().align(8);
-        LAPIC_ADDR = main_alloc_mut().alloc_for::<LocalApic>();
+        set_gate(ExceptionVector::PageFault as _, isr_page_fault,
+                PAGE_FAULT_IST);
+        set_gate(ExceptionVector::DoubleFault as _, isr_double_fault,
+                DOUBLE_FAULT_IST);
+        set_gate(ExceptionVector::Nmi as _, isr_nmi, NMI_IST);
+        set_gate(ExceptionVector::MachineCheck as _, isr_machine_check,
+                MACHINE_CHECK_IST);
+        set_gate(KernelVector::TlbShootdown as _, isr_tlb_shootdown, 0);
     }
+    load_idt();
 
     // Try to initialize APIC interface.
-    let option = LocalApic::new();
-    if option.is_none() {
-        panic!("APIC is not supported but needed by Kobzar implementation");
-    }
-    *apic_mut() = option.unwrap();
+    let mut local = match LocalApic::new() {
+        Some(local) => local,
+        None        => panic!(
+            "APIC is not supported but needed by Kobzar implementation"),
+    };
 
     // Create PIC interface.
     let pic = Pic::new();
@@ -90,10 +302,20 @@ pub fn init() {
     // Disable PIC. It is neccessary to properly use APIC.
     pic.disable();
 
+    // Registers stay at the physical address set by firmware. They are
+    // device memory so they get mapped uncacheable in MMIO window and
+    // APIC interface accesses them there.
+    let region = match map_mmio(apic_base(), APIC_SIZE,
+            CacheType::Uncacheable) {
+        Ok(region)  => region,
+        Err(_)      => panic!("Failed to map Local APIC registers"),
+    };
     unsafe {
-        // Remap APIC to defined base address.
-        apic_mut().set_base_addr(APIC_BASE_ADDRESS as _);
+        local.set_base_addr(region.virt().as_u64());
+        APIC_REGION = Some(region);
+        APIC = Some(local);
     }
+    register_cpu();
 
     // Copy spurious interrupt register.
     let mut spurious = apic().spurious_interrupt().clone();
@@ -5,6 +5,9 @@
 #![feature(lang_items)]
 #![feature(asm)]
 #![feature(const_fn)]
+#![feature(alloc)]
+#![feature(global_allocator)]
+#![feature(alloc_error_handler)]
 
 #![allow(dead_code)]
 
@@ -20,6 +23,9 @@ extern crate asm_x86_64;
 #[cfg(target_arch = "x86_64")]
 use asm_x86_64 as arch;
 
+// Heap types like Box, Vec and BTreeMap. Memory is given by kernel heap.
+extern crate alloc;
+
 /// All the stuff that is needed at early initialization.
 mod early;
 
@@ -68,8 +74,11 @@ macro_rules! panic {
 /// The starting point of kernel Rust code execution.
 /// Before this point runs some initial assembly code that initializes
 /// the environment where Rust code can start performing.
+///
+/// Boot loader passes the address of Multiboot information structure
+/// and the magic value that proves the structure is valid.
 #[no_mangle]
-pub extern fn main() -> ! {
+pub extern fn main(multiboot_info: usize, multiboot_magic: u32) -> ! {
     /* Things to be done:
      *
      * Setup proper paging.
@@ -89,17 +98,94 @@ pub extern fn main() -> ! {
     logger().println("Kobzar kernel logger greets you!");
     logger().println("Very first initialization begins! Hold on tight ^-^\n");
 
+    // Find out how much RAM the machine has.
+    use early::multiboot::{MultibootInfo, BOOTLOADER_MAGIC};
+    if multiboot_magic != BOOTLOADER_MAGIC {
+        logger().println("Kernel was not loaded by Multiboot boot loader.");
+        halt_forever();
+    }
+    let info = unsafe { MultibootInfo::from_addr(multiboot_info) };
+    if let Some(name) = info.boot_loader_name() {
+        logger().print("Loaded by: ");
+        logger().println(name);
+    }
+    ::mem::init_phys_map(info);
+    print_phys_map();
+
+    // Boot information and modules are in usable RAM and must not be
+    // given away.
+    logger().println("Starting page allocator.");
+    let mut reserved = ::mem::RegionList::new();
+    if info.reserved_regions(&mut reserved).is_err() {
+        logger().println("Too many boot modules.");
+        halt_forever();
+    }
+    unsafe { ::mem::init_page_alloc(&reserved); }
+
     // Setup paging first to enable caching and correct communication
     // with memory mapped devices.
     logger().println("Enabling new initial kernel paging tables.");
-    //::mem::paging::setup();
+    ::mem::paging::setup();
+
+    // Own stacks of critical exceptions are set in TSS.
+    logger().println("Loading kernel GDT.");
+    ::mem::gdt::setup();
+
+    // Gates use the stacks of the TSS so GDT must be loaded first.
+    logger().println("Setting up interrupts.");
+    ::ints::init();
+
+    // Boot stack has no guard page. Overflow of kernel stack hits it's
+    // guard and gets reported by page fault handler.
+    logger().println("Switching to kernel stack.");
+    use mem::kstack::{self, KERNEL_OWNER};
+    use mem::stats::Subsystem;
+    let stack = match kstack::alloc(KERNEL_OWNER, Subsystem::KernelStacks) {
+        Some(stack) => stack,
+        None        => {
+            logger().println("No memory for kernel stack.");
+            halt_forever();
+        },
+    };
+    unsafe { kstack::run_on(stack, kernel_main) }
+}
+
+/// Continuation of `main` on the kernel stack.
+extern fn kernel_main() -> ! {
+    use early::{LoggerTrait, logger};
+
+    // Kernel tables are built on heap collections.
+    logger().println("Checking kernel heap.");
+    ::mem::heap::self_test();
 
     logger().println("Setting up basic CCS table.");
-    //::ccs::setup();
+    ::ccs::setup();
 
     halt_forever();
 }
 
+/// Print physical memory regions that were discovered at boot.
+fn print_phys_map() {
+    use early::logger;
+    use core::fmt::Write;
+    use mem::RegionKind;
+
+    for region in ::mem::phys_map().iter() {
+        let kind = match region.kind() {
+            RegionKind::Usable          => "usable",
+            RegionKind::Reserved        => "reserved",
+            RegionKind::AcpiReclaimable => "ACPI reclaimable",
+            RegionKind::AcpiNvs         => "ACPI NVS",
+            RegionKind::Defective       => "defective",
+        };
+        write!(logger(), "{:016X}:{:016X} - {}\n",
+            region.start(), region.end() - 1, kind).unwrap();
+    }
+
+    let usable = ::mem::phys_map().total_size(RegionKind::Usable);
+    write!(logger(), "Usable RAM: {} MiB\n", usable / 1024 / 1024).unwrap();
+}
+
 #[lang = "eh_personality"]
 #[no_mangle]
 pub extern fn eh_personality() {
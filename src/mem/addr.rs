@@ -1,4 +1,6 @@
 use core::ops::*;
+use core::fmt;
+use super::map::{DIRECT_MAP_BASE, DIRECT_MAP_SIZE};
 
 /// Simple wrapper for memory address.
 #[derive(Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -87,14 +89,14 @@ impl Sub<usize> for Address {
 impl SubAssign for Address {
 
     fn sub_assign(&mut self, rhs: Self) {
-        self.addr = rhs.addr;
+        self.addr -= rhs.addr;
     }
 }
 
 impl SubAssign<usize> for Address {
 
     fn sub_assign(&mut self, rhs: usize) {
-        self.addr = rhs;
+        self.addr -= rhs;
     }
 }
 
@@ -236,3 +238,350 @@ impl Address {
         Address { addr : 0 }
     }
 }
+
+/// Size of the smallest page. Page numbers are counted in such pages.
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Count of bits of physical address that processor supports at most.
+const PHYS_ADDR_BITS: u64 = 52;
+
+/// Count of bits of virtual address that paging translates. Higher bits
+/// must be copies of the highest translated bit.
+const VIRT_ADDR_BITS: u64 = 48;
+
+/// Physical memory address. Cannot be dereferenced directly, memory is
+/// accessed through the direct map of the kernel.
+#[derive(Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PhysAddr {
+    addr    : u64,
+}
+
+/// Virtual memory address. Is always canonical.
+#[derive(Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VirtAddr {
+    addr    : u64,
+}
+
+/// Methods and operators that are common for both address types.
+macro_rules! impl_addr {
+    ($t:ident) => (
+        impl $t {
+
+            /// Address as an integer.
+            pub fn as_u64(&self) -> u64 {
+                self.addr
+            }
+
+            /// Address as an integer.
+            pub fn as_usize(&self) -> usize {
+                self.addr as usize
+            }
+
+            /// Add given offset. None if result overflows or is not a
+            /// valid address.
+            pub fn checked_add(&self, rhs: u64) -> Option<Self> {
+                match self.addr.checked_add(rhs) {
+                    Some(addr)  => Self::try_new(addr),
+                    None        => None,
+                }
+            }
+
+            /// Subtract given offset. None if result overflows or is not
+            /// a valid address.
+            pub fn checked_sub(&self, rhs: u64) -> Option<Self> {
+                match self.addr.checked_sub(rhs) {
+                    Some(addr)  => Self::try_new(addr),
+                    None        => None,
+                }
+            }
+
+            /// Round the address down to given alignment. Alignment must
+            /// be a power of two.
+            pub fn align_down(&self, align: u64) -> Self {
+                assert!(align.is_power_of_two());
+                Self::new(self.addr & !(align - 1))
+            }
+
+            /// Round the address up to given alignment. None if result
+            /// overflows. Alignment must be a power of two.
+            pub fn checked_align_up(&self, align: u64) -> Option<Self> {
+                assert!(align.is_power_of_two());
+                match self.addr.checked_add(align - 1) {
+                    Some(addr)  => Self::try_new(addr & !(align - 1)),
+                    None        => None,
+                }
+            }
+
+            /// Round the address up to given alignment. Alignment must be
+            /// a power of two.
+            ///
+            /// # Panics
+            /// When result overflows.
+            pub fn align_up(&self, align: u64) -> Self {
+                self.checked_align_up(align)
+                        .expect("Address overflow on alignment")
+            }
+
+            /// Whether address is aligned to given power of two.
+            pub fn is_aligned(&self, align: u64) -> bool {
+                self.addr & (align - 1) == 0
+            }
+
+            /// Number of the 4KiB page that contains the address.
+            pub fn page_number(&self) -> u64 {
+                self.addr / PAGE_SIZE
+            }
+
+            /// Offset of the address from the start of it's 4KiB page.
+            pub fn page_offset(&self) -> u64 {
+                self.addr & (PAGE_SIZE - 1)
+            }
+
+            /// Start of the 4KiB page with given number.
+            pub fn from_page_number(number: u64) -> Self {
+                Self::new(number * PAGE_SIZE)
+            }
+        }
+
+        impl Add<u64> for $t {
+
+            type Output = Self;
+
+            fn add(self, rhs: u64) -> Self::Output {
+                self.checked_add(rhs).expect("Address overflow")
+            }
+        }
+
+        impl AddAssign<u64> for $t {
+
+            fn add_assign(&mut self, rhs: u64) {
+                *self = *self + rhs;
+            }
+        }
+
+        impl Sub<u64> for $t {
+
+            type Output = Self;
+
+            fn sub(self, rhs: u64) -> Self::Output {
+                self.checked_sub(rhs).expect("Address underflow")
+            }
+        }
+
+        impl SubAssign<u64> for $t {
+
+            fn sub_assign(&mut self, rhs: u64) {
+                *self = *self - rhs;
+            }
+        }
+
+        /// Distance in bytes between two addresses.
+        impl Sub for $t {
+
+            type Output = u64;
+
+            fn sub(self, rhs: Self) -> u64 {
+                self.addr.checked_sub(rhs.addr).expect("Address underflow")
+            }
+        }
+
+        impl fmt::UpperHex for $t {
+
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::UpperHex::fmt(&self.addr, f)
+            }
+        }
+    )
+}
+
+impl_addr!(PhysAddr);
+impl_addr!(VirtAddr);
+
+impl PhysAddr {
+
+    /// Create physical address without checks. Used in constants.
+    pub const fn new_unchecked(addr: u64) -> Self {
+        PhysAddr { addr }
+    }
+
+    /// Create physical address. None if address is wider than processor
+    /// supports.
+    pub fn try_new(addr: u64) -> Option<Self> {
+        if addr >> PHYS_ADDR_BITS == 0 {
+            Some(PhysAddr { addr })
+        } else {
+            None
+        }
+    }
+
+    /// Create physical address.
+    ///
+    /// # Panics
+    /// When address is wider than processor supports.
+    pub fn new(addr: u64) -> Self {
+        Self::try_new(addr).expect("Invalid physical address")
+    }
+
+    /// Whether this address is accessible through the direct map.
+    pub fn is_direct_mapped(&self) -> bool {
+        self.addr < DIRECT_MAP_SIZE
+    }
+
+    /// Virtual address of the same byte in the direct map of the kernel.
+    ///
+    /// # Panics
+    /// When address is not covered by the direct map.
+    pub fn to_virt(&self) -> VirtAddr {
+        assert!(self.is_direct_mapped(), "Address is not direct mapped");
+        VirtAddr::new(DIRECT_MAP_BASE + self.addr)
+    }
+
+    /// Pointer to the memory at this address in the direct map.
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.to_virt().as_ptr()
+    }
+
+    /// Mutable pointer to the memory at this address in the direct map.
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.to_virt().as_mut_ptr()
+    }
+}
+
+impl VirtAddr {
+
+    /// Create virtual address without checks. Used in constants.
+    pub const fn new_unchecked(addr: u64) -> Self {
+        VirtAddr { addr }
+    }
+
+    /// Whether the address has all bits above translated ones equal to
+    /// the highest translated bit.
+    pub fn is_canonical(addr: u64) -> bool {
+        let high = addr >> (VIRT_ADDR_BITS - 1);
+        high == 0 || high == (1 << (64 - VIRT_ADDR_BITS + 1)) - 1
+    }
+
+    /// Create virtual address. None if address is not canonical.
+    pub fn try_new(addr: u64) -> Option<Self> {
+        if Self::is_canonical(addr) {
+            Some(VirtAddr { addr })
+        } else {
+            None
+        }
+    }
+
+    /// Create virtual address.
+    ///
+    /// # Panics
+    /// When address is not canonical.
+    pub fn new(addr: u64) -> Self {
+        Self::try_new(addr).expect("Non-canonical virtual address")
+    }
+
+    /// Create virtual address by sign extending translated bits. Higher
+    /// bits of given value are ignored.
+    pub fn new_truncate(addr: u64) -> Self {
+        let shift = 64 - VIRT_ADDR_BITS;
+        VirtAddr { addr: ((addr << shift) as i64 >> shift) as u64 }
+    }
+
+    /// Address of given pointer.
+    pub fn from_ptr<T>(ptr: *const T) -> Self {
+        Self::new(ptr as usize as u64)
+    }
+
+    /// Bits of the address that paging tables translate.
+    pub fn translated(&self) -> u64 {
+        self.addr & ((1 << VIRT_ADDR_BITS) - 1)
+    }
+
+    /// Physical address of the byte if this address is in the direct map.
+    pub fn direct_map_phys(&self) -> Option<PhysAddr> {
+        if self.addr >= DIRECT_MAP_BASE
+                && self.addr - DIRECT_MAP_BASE < DIRECT_MAP_SIZE {
+            Some(PhysAddr::new(self.addr - DIRECT_MAP_BASE))
+        } else {
+            None
+        }
+    }
+
+    /// Pointer to the memory at this address.
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.addr as usize as *const T
+    }
+
+    /// Mutable pointer to the memory at this address.
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.addr as usize as *mut T
+    }
+
+    /// Get reference to the value.
+    ///
+    /// # Safety
+    /// Caller must ensure that this address points to a valid value.
+    pub unsafe fn as_ref<'a, T>(&self) -> &'a T {
+        &*self.as_ptr::<T>()
+    }
+
+    /// Get mutable reference to the value.
+    ///
+    /// # Safety
+    /// Caller must ensure that this address points to a valid value.
+    pub unsafe fn as_ref_mut<'a, T>(&self) -> &'a mut T {
+        &mut *self.as_mut_ptr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn aligns_addresses() {
+        let addr = PhysAddr::new(0x1234);
+        assert_eq!(addr.align_down(0x1000).as_u64(), 0x1000);
+        assert_eq!(addr.align_up(0x1000).as_u64(), 0x2000);
+        assert_eq!(addr.align_up(0x4).as_u64(), 0x1234);
+        assert!(addr.is_aligned(0x4) && !addr.is_aligned(0x8));
+
+        let top = PhysAddr::new((1 << PHYS_ADDR_BITS) - 1);
+        assert!(top.checked_align_up(0x1000).is_none());
+        let top = VirtAddr::new(0xFFFF_FFFF_FFFF_F001);
+        assert!(top.checked_align_up(0x1000).is_none());
+    }
+
+    #[test]
+    fn splits_page_numbers() {
+        let addr = VirtAddr::new(0x0000_0000_0012_3456);
+        assert_eq!(addr.page_number(), 0x123);
+        assert_eq!(addr.page_offset(), 0x456);
+        assert!(VirtAddr::from_page_number(0x123) == addr.align_down(0x1000));
+    }
+
+    #[test]
+    fn checks_offsets() {
+        let addr = PhysAddr::new(0x1000);
+        assert_eq!((addr + 0x10).as_u64(), 0x1010);
+        assert_eq!((addr - 0x10).as_u64(), 0x0FF0);
+        assert_eq!(addr + 0x10 - addr, 0x10);
+        assert!(addr.checked_sub(0x1001).is_none());
+        assert!(addr.checked_add(1 << PHYS_ADDR_BITS).is_none());
+    }
+
+    #[test]
+    fn keeps_virtual_addresses_canonical() {
+        assert!(VirtAddr::is_canonical(0x0000_7FFF_FFFF_FFFF));
+        assert!(VirtAddr::is_canonical(0xFFFF_8000_0000_0000));
+        assert!(!VirtAddr::is_canonical(0x0000_8000_0000_0000));
+        assert!(!VirtAddr::is_canonical(0xFFFF_7FFF_FFFF_FFFF));
+
+        // Crossing the hole between halves is not a valid offset.
+        let low = VirtAddr::new(0x0000_7FFF_FFFF_F000);
+        assert!(low.checked_add(0x1000).is_none());
+
+        let addr = VirtAddr::new_truncate(0x0000_8000_0000_1000);
+        assert_eq!(addr.as_u64(), 0xFFFF_8000_0000_1000);
+        assert_eq!(addr.translated(), 0x0000_8000_0000_1000);
+    }
+}
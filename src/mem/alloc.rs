@@ -178,3 +178,407 @@ impl TopLimitedAllocator {
         self.curaddr
     }
 }
+
+/// Maximal amount of free blocks that free-list allocator can track.
+/// The same amount of allocated blocks can exist at once.
+pub const FREE_LIST_CAPACITY: usize = 64;
+
+/// Strategy of choosing free block for allocation.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FitPolicy {
+
+    /// Take the first block with enough space. Fast but leaves small
+    /// fragments at the beginning of the memory.
+    FirstFit,
+
+    /// Take the smallest block with enough space. Keeps big blocks for
+    /// big allocations.
+    BestFit,
+}
+
+/// Range of memory.
+#[derive(Clone, Copy, PartialEq)]
+struct Block {
+
+    /// First byte.
+    start   : Address,
+
+    /// Byte after the last byte.
+    end     : Address,
+}
+
+/// List of blocks of fixed capacity.
+#[derive(Clone, Copy)]
+struct BlockList {
+
+    /// Blocks of the list. Only first `length` are valid.
+    blocks  : [Block; FREE_LIST_CAPACITY],
+
+    /// Count of valid blocks.
+    length  : usize,
+}
+
+/// Allocator that keeps sorted list of free blocks. Released memory is
+/// merged with adjacent free blocks so it can be given again. Allocated
+/// blocks are recorded so only whole blocks can be released. Lists are
+/// stored in the allocator itself so managed memory is never touched.
+#[derive(Clone, Copy)]
+pub struct FreeListAllocator {
+
+    /// Free blocks sorted by address.
+    free        : BlockList,
+
+    /// Allocated blocks in no particular order.
+    used        : BlockList,
+
+    /// Start of the managed memory.
+    minaddr     : Address,
+
+    /// Top limit of the managed memory.
+    maxaddr     : Address,
+
+    /// Alignment of the next allocation.
+    next_align  : usize,
+
+    /// Strategy of choosing free block.
+    policy      : FitPolicy,
+}
+
+const EMPTY_BLOCK: Block = Block {
+    start   : Address::null(),
+    end     : Address::null(),
+};
+
+impl BlockList {
+
+    const fn new() -> Self {
+        BlockList {
+            blocks  : [EMPTY_BLOCK; FREE_LIST_CAPACITY],
+            length  : 0,
+        }
+    }
+
+    /// Whether no more blocks can be inserted.
+    fn is_full(&self) -> bool {
+        self.length == FREE_LIST_CAPACITY
+    }
+
+    /// Insert block at given position of the list. Err is returned when
+    /// the list is full.
+    fn insert(&mut self, index: usize, block: Block) -> Result<(),()> {
+        if self.is_full() {
+            return Err(());
+        }
+
+        let mut i = self.length;
+        while i > index {
+            self.blocks[i] = self.blocks[i - 1];
+            i -= 1;
+        }
+        self.blocks[index] = block;
+        self.length += 1;
+        Ok(())
+    }
+
+    /// Remove block at given position of the list.
+    fn remove(&mut self, index: usize) {
+        for i in index..self.length - 1 {
+            self.blocks[i] = self.blocks[i + 1];
+        }
+        self.length -= 1;
+    }
+
+    /// Position of given block in the list.
+    fn find(&self, block: Block) -> Option<usize> {
+        self.blocks[..self.length].iter().position(|b| *b == block)
+    }
+}
+
+impl Allocator for FreeListAllocator {
+
+    /// Allocate range of bytes. Null is returned when no free block fits
+    /// or when allocated block or the rest of free block cannot be
+    /// recorded because the list is full.
+    fn alloc(&mut self, size: usize) -> Address {
+        let align = self.next_align;
+        self.next_align = 1;
+        if size == 0 || self.used.is_full() {
+            return Address::null();
+        }
+
+        let full = self.free.is_full();
+        let mut found = None;
+        for i in 0..self.free.length {
+            let block = self.free.blocks[i];
+            let aligned = ((block.start + align - 1) / align) * align;
+            if aligned + size > block.end {
+                continue;
+            }
+
+            // Padding and the rest both stay free which takes one more
+            // entry in the list.
+            if full && aligned != block.start && aligned + size != block.end {
+                continue;
+            }
+
+            let better = match found {
+                None            => true,
+                Some((j, _))    => {
+                    let other: Block = self.free.blocks[j];
+                    self.policy == FitPolicy::BestFit
+                            && block.end - block.start
+                            < other.end - other.start
+                },
+            };
+            if better {
+                found = Some((i, aligned));
+                if self.policy == FitPolicy::FirstFit {
+                    break;
+                }
+            }
+        }
+
+        let (i, addr) = match found {
+            Some(found) => found,
+            None        => return Address::null(),
+        };
+
+        let block = self.free.blocks[i];
+        let end = addr + size;
+        if addr == block.start && end == block.end {
+            self.free.remove(i);
+        } else if addr == block.start {
+            self.free.blocks[i].start = end;
+        } else if end == block.end {
+            self.free.blocks[i].end = addr;
+        } else {
+            let rest = Block { start : end, end : block.end };
+            if self.free.insert(i + 1, rest).is_err() {
+                return Address::null();
+            }
+            self.free.blocks[i].end = addr;
+        }
+
+        // Room in the list was checked above.
+        let length = self.used.length;
+        let _ = self.used.insert(length, Block { start : addr, end : end });
+        addr
+    }
+}
+
+impl AllocatorAlign for FreeListAllocator {
+
+    /// Align next allocation to given byte boundary. Padding memory stays
+    /// free so None is always returned. Zero alignment is ignored.
+    fn align(&mut self, val: usize) -> Option<(usize, Address)> {
+        if val != 0 {
+            self.next_align = val;
+        }
+        None
+    }
+}
+
+impl AllocatorTopLimit for FreeListAllocator {
+
+    fn top_limit(&self) -> Address {
+        self.maxaddr
+    }
+}
+
+impl AllocatorRelease for FreeListAllocator {
+
+    /// Release allocated block. Range must be exactly the range of some
+    /// allocated block. Err is also returned when released block cannot
+    /// be recorded because the list of free blocks is full. Nothing is
+    /// changed on error.
+    fn release_range(&mut self, from: Address, to: Address)
+            -> Result<(),()> {
+        let used = match self.used.find(Block { start : from, end : to }) {
+            Some(used)  => used,
+            None        => return Err(()),
+        };
+
+        // Find first block after released range.
+        let mut i = 0;
+        while i < self.free.length && self.free.blocks[i].start < to {
+            i += 1;
+        }
+
+        let merge_prev = i > 0 && self.free.blocks[i - 1].end == from;
+        let merge_next = i < self.free.length
+                && self.free.blocks[i].start == to;
+        if merge_prev && merge_next {
+            self.free.blocks[i - 1].end = self.free.blocks[i].end;
+            self.free.remove(i);
+        } else if merge_prev {
+            self.free.blocks[i - 1].end = to;
+        } else if merge_next {
+            self.free.blocks[i].start = from;
+        } else {
+            try!(self.free.insert(i, Block { start : from, end : to }));
+        }
+
+        self.used.remove(used);
+        Ok(())
+    }
+
+    /// Whether the range is exactly the range of some allocated block.
+    fn is_releasable_range(&self, from: Address, to: Address) -> bool {
+        self.used.find(Block { start : from, end : to }).is_some()
+    }
+}
+
+impl FreeListAllocator {
+
+    /// Create new allocator that manages memory from given start address
+    /// to given end address. All the memory is free.
+    pub fn new(start: Address, end: Address, policy: FitPolicy) -> Self {
+        let mut allocator = FreeListAllocator {
+            free        : BlockList::new(),
+            used        : BlockList::new(),
+            minaddr     : start,
+            maxaddr     : end,
+            next_align  : 1,
+            policy      : policy,
+        };
+
+        if start < end {
+            let _ = allocator.free.insert(0, Block {
+                start   : start,
+                end     : end,
+            });
+        }
+        allocator
+    }
+
+    /// Strategy of choosing free block.
+    pub fn policy(&self) -> FitPolicy {
+        self.policy
+    }
+
+    /// Change strategy of choosing free block.
+    pub fn set_policy(&mut self, policy: FitPolicy) {
+        self.policy = policy;
+    }
+
+    /// Count of free bytes.
+    pub fn free_size(&self) -> usize {
+        let mut size = 0;
+        for block in self.free.blocks[..self.free.length].iter() {
+            let len: usize = (block.end - block.start).into();
+            size += len;
+        }
+        size
+    }
+
+    /// Count of free blocks. Shows how much free memory is fragmented.
+    pub fn free_block_count(&self) -> usize {
+        self.free.length
+    }
+
+    /// Count of allocated blocks.
+    pub fn used_block_count(&self) -> usize {
+        self.used.length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn addr(a: usize) -> Address {
+        Address::from(a)
+    }
+
+    fn allocator(policy: FitPolicy) -> FreeListAllocator {
+        FreeListAllocator::new(addr(0x1000), addr(0x2000), policy)
+    }
+
+    #[test]
+    fn merges_adjacent_blocks() {
+        let mut a = allocator(FitPolicy::FirstFit);
+        let x = a.alloc(0x100);
+        let y = a.alloc(0x100);
+        let z = a.alloc(0x100);
+        assert!(x == addr(0x1000) && y == addr(0x1100) && z == addr(0x1200));
+
+        assert!(a.release_range(x, x + 0x100).is_ok());
+        assert!(a.release_range(z, z + 0x100).is_ok());
+        assert_eq!(a.free_block_count(), 2);
+
+        // Middle block joins both neighbours.
+        assert!(a.release_range(y, y + 0x100).is_ok());
+        assert_eq!(a.free_block_count(), 1);
+        assert_eq!(a.used_block_count(), 0);
+        assert_eq!(a.free_size(), 0x1000);
+    }
+
+    #[test]
+    fn rejects_partial_release() {
+        let mut a = allocator(FitPolicy::FirstFit);
+        let x = a.alloc(0x100);
+        assert!(!a.is_releasable_range(x, x + 0x80));
+        assert!(a.release_range(x, x + 0x80).is_err());
+        assert!(a.release_range(x + 0x80, x + 0x100).is_err());
+        assert!(a.release_range(x, x + 0x200).is_err());
+        assert_eq!(a.free_size(), 0xF00);
+        assert!(a.release_range(x, x + 0x100).is_ok());
+    }
+
+    #[test]
+    fn rejects_double_release() {
+        let mut a = allocator(FitPolicy::FirstFit);
+        let x = a.alloc(0x100);
+        let _ = a.alloc(0x100);
+        assert!(a.release_range(x, x + 0x100).is_ok());
+        assert!(a.release_range(x, x + 0x100).is_err());
+        assert_eq!(a.free_size(), 0xF00);
+        assert_eq!(a.free_block_count(), 2);
+    }
+
+    #[test]
+    fn rejects_never_allocated() {
+        let mut a = allocator(FitPolicy::FirstFit);
+        assert!(a.release_range(addr(0x1000), addr(0x1100)).is_err());
+        assert_eq!(a.free_size(), 0x1000);
+    }
+
+    #[test]
+    fn best_fit_takes_smallest_block() {
+        let mut a = allocator(FitPolicy::BestFit);
+        let x = a.alloc(0x400);
+        let _ = a.alloc(0x100);
+        let y = a.alloc(0x80);
+        let _ = a.alloc(0x100);
+        assert!(a.release_range(x, x + 0x400).is_ok());
+        assert!(a.release_range(y, y + 0x80).is_ok());
+        assert!(a.alloc(0x80) == y);
+
+        a.set_policy(FitPolicy::FirstFit);
+        assert!(a.release_range(y, y + 0x80).is_ok());
+        assert!(a.alloc(0x80) == x);
+    }
+
+    #[test]
+    fn aligns_next_allocation() {
+        let mut a = allocator(FitPolicy::FirstFit);
+        let _ = a.alloc(0x10);
+        assert!(a.align(0x100).is_none());
+        let x = a.alloc(0x10);
+        assert!(x == addr(0x1100));
+
+        // Padding stays free and alignment applies only once.
+        assert_eq!(a.free_block_count(), 2);
+        assert!(a.alloc(0x10) == addr(0x1010));
+    }
+
+    #[test]
+    fn fails_when_no_block_fits() {
+        let mut a = allocator(FitPolicy::FirstFit);
+        assert!(a.alloc(0x1001) == Address::null());
+        assert!(a.alloc(0) == Address::null());
+        assert!(a.alloc(0x1000) == addr(0x1000));
+        assert!(a.alloc(1) == Address::null());
+    }
+}
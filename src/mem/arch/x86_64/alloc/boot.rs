@@ -0,0 +1,95 @@
+use mem::{Region, RegionKind, RegionList};
+use super::super::map::{LOW_MEMORY_END, kernel_start, kernel_end};
+
+/// Physical memory that is free for page allocator after the kernel was
+/// loaded. Also is used as a simple allocator for the data page allocator
+/// itself needs to start.
+pub struct BootMemory {
+
+    /// Free memory regions.
+    free    : RegionList,
+}
+
+impl BootMemory {
+
+    /// Find free memory. All usable regions of the memory map are taken
+    /// except the parts that are covered by any other region of the map,
+    /// the first megabyte of memory, the kernel image and any region of
+    /// given reserved list.
+    pub fn new(map: &RegionList, reserved: &RegionList) -> Self {
+        let mut free = RegionList::new();
+
+        for region in map.iter() {
+            if region.is_usable() {
+                // List has the same capacity so it fits.
+                let _ = free.insert(*region);
+            }
+        }
+
+        let mut mem = BootMemory { free : free };
+
+        for region in map.iter() {
+            if !region.is_usable() {
+                mem.exclude(region.start(), region.end());
+            }
+        }
+
+        mem.exclude(0, LOW_MEMORY_END);
+        mem.exclude(kernel_start(), kernel_end());
+
+        for region in reserved.iter() {
+            mem.exclude(region.start(), region.end());
+        }
+
+        mem
+    }
+
+    /// Exclude the range from free memory. When free region cannot be
+    /// split, it is dropped whole so reserved memory never gets used.
+    fn exclude(&mut self, start: u64, end: u64) {
+        if self.free.exclude(start, end).is_ok() {
+            return;
+        }
+
+        let hole = Region::new(start, end, RegionKind::Reserved);
+        let mut i = 0;
+        while i < self.free.length() {
+            if self.free.get(i).unwrap().overlaps(&hole) {
+                self.free.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Regions of free memory.
+    pub fn regions(&self) -> &RegionList {
+        &self.free
+    }
+
+    /// Take given amount of bytes from free memory. Returned address is
+    /// aligned to given power of two and allocated memory lies entirely
+    /// below given limit. None is returned if no region has enough space.
+    pub fn take(&mut self, size: u64, align: u64, limit: u64) -> Option<u64> {
+        let mut i = 0;
+        while i < self.free.length() {
+            let region = *self.free.get(i).unwrap();
+            i += 1;
+
+            let start = (region.start() + align - 1) & !(align - 1);
+            let end = start + size;
+            if end > region.end() || end > limit {
+                continue;
+            }
+
+            // Memory before aligned address stays free.
+            self.exclude(region.start(), end);
+            let _ = self.free.insert(
+                    Region::new(region.start(), start, RegionKind::Usable));
+
+            return Some(start);
+        }
+
+        None
+    }
+}
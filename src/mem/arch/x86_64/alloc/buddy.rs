@@ -0,0 +1,580 @@
+use super::PageStatus;
+use super::BootMemory;
+use super::frame::*;
+use super::zone::{Zone, ZONE_COUNT};
+use super::super::map::BOOT_MAPPED_END;
+use mem::PhysAddr;
+
+/// Index that marks absence of the frame in free lists.
+const NONE: u32 = !0;
+
+/// Information about single 4KiB frame. Free lists are linked through these
+/// entries so allocator never writes to free memory itself.
+struct FrameInfo {
+
+    /// Status of the block if this frame is the first one in the block.
+    status  : PageStatus,
+
+    /// Next free block of the same order. Valid for free block heads only.
+    next    : u32,
+
+    /// Previous free block of the same order. Valid for free block heads
+    /// only.
+    prev    : u32,
+
+    /// Order of the block that starts at this frame.
+    order   : u8,
+
+    /// Whether this frame starts a block. Frames in the middle of the block
+    /// are never heads.
+    head    : bool,
+
+    /// Whether the block that starts at this frame is free.
+    free    : bool,
+}
+
+/// Contiguous range of physical memory managed by the allocator.
+struct Area {
+
+    /// Address of the first frame.
+    start   : u64,
+
+    /// Count of frames in the area.
+    frames  : u32,
+
+    /// Index of frame information of the first frame in the area.
+    first   : u32,
+}
+
+/// Free blocks of single memory zone.
+struct Pool {
+
+    /// First free block of each order.
+    free_lists  : [u32; MAX_ORDER as usize + 1],
+
+    /// Count of free blocks of each order.
+    free_blocks : [usize; MAX_ORDER as usize + 1],
+
+    /// Count of free frames.
+    free_frames : usize,
+}
+
+/// Buddy system frame allocator. Each free block of order N can be split
+/// in two 'buddy' blocks of order N-1. When both buddies get free again
+/// they are merged back into the block of order N. Blocks never cross
+/// zone boundaries.
+pub struct Buddy {
+
+    /// Array of all memory areas.
+    areas       : *mut Area,
+
+    /// Count of memory areas.
+    area_count  : u32,
+
+    /// Frame information of all frames of all areas.
+    info        : *mut FrameInfo,
+
+    /// Free blocks of each zone.
+    pools       : [Pool; ZONE_COUNT],
+
+    /// Blocks that end above this address are not given.
+    limit       : u64,
+}
+
+impl Pool {
+
+    fn new() -> Self {
+        Pool {
+            free_lists  : [NONE; MAX_ORDER as usize + 1],
+            free_blocks : [0; MAX_ORDER as usize + 1],
+            free_frames : 0,
+        }
+    }
+}
+
+impl Buddy {
+
+    /// Create allocator that manages all free memory. Data of the
+    /// allocator itself is stored in that free memory too.
+    ///
+    /// # Safety
+    /// Free memory regions must be really free and must not be used by
+    /// anything else. Should be called once.
+    pub unsafe fn new(mem: &mut BootMemory) -> Self {
+        use core::mem::size_of;
+
+        // Count areas and frames. Data stored in free memory can only
+        // decrease these numbers so they are used as upper limits.
+        let mut area_count = 0;
+        let mut frame_count = 0;
+        for region in mem.regions().iter() {
+            let (start, end) = Self::region_frames(region.start(),
+                    region.end());
+            if end > start {
+                area_count += 1;
+                frame_count += (end - start) / FRAME_SIZE;
+            }
+        }
+
+        let area_size = area_count * size_of::<Area>() as u64;
+        let info_size = frame_count * size_of::<FrameInfo>() as u64;
+
+        let take = |mem: &mut BootMemory, size| {
+            // Boot code maps only this memory in the direct map.
+            match mem.take(size, 8, BOOT_MAPPED_END) {
+                Some(addr) => PhysAddr::new(addr),
+                None => panic!("No memory for frame allocator data"),
+            }
+        };
+        let areas = take(mem, area_size).as_mut_ptr::<Area>();
+        let info = take(mem, info_size).as_mut_ptr::<FrameInfo>();
+
+        let mut buddy = Buddy {
+            areas       : areas,
+            area_count  : 0,
+            info        : info,
+            pools       : [Pool::new(), Pool::new(), Pool::new()],
+            limit       : BOOT_MAPPED_END,
+        };
+
+        for region in mem.regions().iter() {
+            let (start, end) = Self::region_frames(region.start(),
+                    region.end());
+            if end > start {
+                buddy.add_area(start, end);
+            }
+        }
+
+        buddy
+    }
+
+    /// Add area of free frames. Information of the frames follows the
+    /// information of the previous area.
+    ///
+    /// # Safety
+    /// Arrays of areas and frame information must have room for the area.
+    unsafe fn add_area(&mut self, start: u64, end: u64) {
+        let first = match self.area_count {
+            0       => 0,
+            count   => {
+                let last = self.area(count - 1);
+                last.first + last.frames
+            },
+        };
+
+        let frames = ((end - start) / FRAME_SIZE) as u32;
+        *self.areas.offset(self.area_count as _) = Area {
+            start   : start,
+            frames  : frames,
+            first   : first,
+        };
+        self.area_count += 1;
+
+        for i in first..first + frames {
+            *self.info.offset(i as _) = FrameInfo {
+                status  : Default::default(),
+                next    : NONE,
+                prev    : NONE,
+                order   : 0,
+                head    : false,
+                free    : false,
+            };
+        }
+
+        // Cover the area with the biggest aligned blocks that do not
+        // cross zone boundaries.
+        let mut addr = start;
+        while addr < end {
+            let zone = Zone::of(addr);
+            let limit = if end < zone.top() { end } else { zone.top() };
+            let mut order = MAX_ORDER;
+            while addr % order_size(order) != 0
+                    || addr + order_size(order) > limit {
+                order -= 1;
+            }
+
+            let index = self.index_of(addr).unwrap();
+            self.push_free(index, order, zone);
+            addr += order_size(order);
+        }
+    }
+
+    /// Range of whole frames that region covers.
+    fn region_frames(start: u64, end: u64) -> (u64, u64) {
+        let start = (start + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+        let end = end & !(FRAME_SIZE - 1);
+        (start, end)
+    }
+
+    fn area(&self, index: u32) -> &Area {
+        unsafe { &*self.areas.offset(index as _) }
+    }
+
+    fn info(&self, index: u32) -> &FrameInfo {
+        unsafe { &*self.info.offset(index as _) }
+    }
+
+    fn info_mut(&mut self, index: u32) -> &mut FrameInfo {
+        unsafe { &mut *self.info.offset(index as _) }
+    }
+
+    /// Area that contains given address.
+    fn area_of(&self, addr: u64) -> Option<&Area> {
+        for i in 0..self.area_count {
+            let area = self.area(i);
+            let end = area.start + area.frames as u64 * FRAME_SIZE;
+            if addr >= area.start && addr < end {
+                return Some(area);
+            }
+        }
+
+        None
+    }
+
+    /// Index of frame information of the frame at given address.
+    fn index_of(&self, addr: u64) -> Option<u32> {
+        match self.area_of(addr) {
+            Some(area) => {
+                let offset = (addr - area.start) / FRAME_SIZE;
+                Some(area.first + offset as u32)
+            },
+            None => None,
+        }
+    }
+
+    /// Address of the frame with given index of frame information.
+    fn addr_of(&self, index: u32) -> u64 {
+        for i in 0..self.area_count {
+            let area = self.area(i);
+            if index >= area.first && index < area.first + area.frames {
+                return area.start + (index - area.first) as u64 * FRAME_SIZE;
+            }
+        }
+
+        unreachable!()
+    }
+
+    /// Add block to the free list of given order in the pool of given zone.
+    fn push_free(&mut self, index: u32, order: u8, zone: Zone) {
+        let top = self.pools[zone.index()].free_lists[order as usize];
+        if top != NONE {
+            self.info_mut(top).prev = index;
+        }
+
+        {
+            let info = self.info_mut(index);
+            info.next = top;
+            info.prev = NONE;
+            info.order = order;
+            info.head = true;
+            info.free = true;
+        }
+
+        let pool = &mut self.pools[zone.index()];
+        pool.free_lists[order as usize] = index;
+        pool.free_blocks[order as usize] += 1;
+        pool.free_frames += 1 << order;
+    }
+
+    /// Remove free block from the free list of it's order in the pool of
+    /// given zone.
+    fn remove_free(&mut self, index: u32, zone: Zone) {
+        let (next, prev, order) = {
+            let info = self.info(index);
+            (info.next, info.prev, info.order)
+        };
+
+        if prev == NONE {
+            self.pools[zone.index()].free_lists[order as usize] = next;
+        } else {
+            self.info_mut(prev).next = next;
+        }
+        if next != NONE {
+            self.info_mut(next).prev = prev;
+        }
+
+        self.info_mut(index).free = false;
+        let pool = &mut self.pools[zone.index()];
+        pool.free_blocks[order as usize] -= 1;
+        pool.free_frames -= 1 << order;
+    }
+
+    /// Find the smallest free block of the zone that can be split into
+    /// the block of given order which ends at or below the top limit.
+    /// Returns index of the block and it's order.
+    fn find_free(&self, order: u8, zone: Zone) -> Option<(u32, u8)> {
+        let pool = &self.pools[zone.index()];
+        for cur in order..MAX_ORDER + 1 {
+            let mut index = pool.free_lists[cur as usize];
+            while index != NONE {
+                // Lower half is kept on each split.
+                if self.limit == !0
+                        || self.addr_of(index) + order_size(order)
+                        <= self.limit {
+                    return Some((index, cur));
+                }
+                index = self.info(index).next;
+            }
+        }
+        None
+    }
+
+    /// Index of the first frame of allocated block that contains given
+    /// address.
+    fn allocated_head(&self, addr: u64) -> Option<u32> {
+        let addr = addr & !(FRAME_SIZE - 1);
+        for order in 0..MAX_ORDER + 1 {
+            let head = addr & !(order_size(order) - 1);
+            let index = match self.index_of(head) {
+                Some(index) => index,
+                None        => return None,
+            };
+
+            let info = self.info(index);
+            if info.head && info.order >= order {
+                if info.free || head + order_size(info.order) <= addr {
+                    return None;
+                }
+                return Some(index);
+            }
+        }
+
+        None
+    }
+}
+
+impl FrameAlloc for Buddy {
+
+    // Not inlined so allocation site is found at fixed depth in debug
+    // mode.
+    #[inline(never)]
+    fn alloc_in_zone(&mut self, order: u8, zone: Zone)
+            -> AlResult<PhysAddr> {
+        if order > MAX_ORDER {
+            return Err(AllocError::OrderUnsupported);
+        }
+
+        let (index, mut cur) = match self.find_free(order, zone) {
+            Some(found) => found,
+            None        => return Err(AllocError::NoMorePages),
+        };
+        self.remove_free(index, zone);
+
+        // Split the block until it has requested order. Upper halves
+        // become free buddies.
+        while cur > order {
+            cur -= 1;
+            let buddy = index + (1 << cur);
+            self.push_free(buddy, cur, zone);
+        }
+
+        let info = self.info_mut(index);
+        info.order = order;
+        info.head = true;
+        info.status.set_user(1);
+
+        let addr = PhysAddr::new(self.addr_of(index));
+        frames_allocated(addr, order);
+        Ok(addr)
+    }
+
+    unsafe fn release_contiguous(&mut self, addr: PhysAddr, order: u8)
+            -> ReResult<()> {
+        let addr = addr.as_u64();
+        let mut index = match self.index_of(addr) {
+            Some(index) => index,
+            None        => return Err(ReleaseError::NotAllocated),
+        };
+
+        {
+            let info = self.info(index);
+            if !info.head || info.free || info.order != order {
+                return Err(ReleaseError::NotAllocated);
+            }
+            if info.status.use_count() > 1 {
+                return Err(ReleaseError::UsageCounterNonzero);
+            }
+        }
+
+        // Block is poisoned while it is still allocated.
+        frames_released(PhysAddr::new(addr), order);
+        self.info_mut(index).status.set_user(0);
+
+        // Merge with free buddies of the same zone as long as possible.
+        let zone = Zone::of(addr);
+        let mut addr = addr;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy_addr = addr ^ order_size(order);
+            let buddy = match self.index_of(buddy_addr) {
+                Some(buddy) => buddy,
+                None        => break,
+            };
+
+            // Buddy must be in the same area to be merged.
+            if Zone::of(buddy_addr) != zone
+                    || buddy_addr < addr && buddy + (1 << order) != index
+                    || buddy_addr > addr && index + (1 << order) != buddy {
+                break;
+            }
+
+            {
+                let info = self.info(buddy);
+                if !info.head || !info.free || info.order != order {
+                    break;
+                }
+            }
+
+            self.remove_free(buddy, zone);
+            self.info_mut(buddy).head = false;
+            self.info_mut(index).head = false;
+
+            if buddy_addr < addr {
+                addr = buddy_addr;
+                index = buddy;
+            }
+            order += 1;
+        }
+
+        self.push_free(index, order, zone);
+        Ok(())
+    }
+
+    fn status_mut(&mut self, addr: PhysAddr) -> Option<&mut PageStatus> {
+        match self.allocated_head(addr.as_u64()) {
+            Some(index) => Some(&mut self.info_mut(index).status),
+            None        => None,
+        }
+    }
+
+    fn zone_free_frames(&self, zone: Zone) -> usize {
+        self.pools[zone.index()].free_frames
+    }
+
+    fn zone_frag_stats(&self, zone: Zone) -> FragStats {
+        let pool = &self.pools[zone.index()];
+        let mut stats = FragStats::new();
+        stats.free_blocks = pool.free_blocks;
+        stats.free_frames = pool.free_frames;
+        stats
+    }
+
+    fn set_top_limit(&mut self, limit: Option<u64>) {
+        self.limit = limit.unwrap_or(!0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use core::mem::zeroed;
+
+    /// Count of frames that test allocators manage.
+    const FRAMES: usize = 64;
+
+    /// Start of the test area. It is in DMA32 zone.
+    const START: u64 = 0x100_0000;
+
+    /// Allocator of 256KiB at `START` that keeps it's data in given arrays.
+    unsafe fn buddy(areas: &mut [Area; 1], info: &mut [FrameInfo; FRAMES])
+            -> Buddy {
+        let mut buddy = Buddy {
+            areas       : areas.as_mut_ptr(),
+            area_count  : 0,
+            info        : info.as_mut_ptr(),
+            pools       : [Pool::new(), Pool::new(), Pool::new()],
+            limit       : !0,
+        };
+        buddy.add_area(START, START + FRAMES as u64 * FRAME_SIZE);
+        buddy
+    }
+
+    fn free_blocks(buddy: &Buddy) -> [usize; MAX_ORDER as usize + 1] {
+        buddy.zone_frag_stats(Zone::Dma32).free_blocks
+    }
+
+    fn alloc(buddy: &mut Buddy, order: u8) -> u64 {
+        match buddy.alloc_in_zone(order, Zone::Dma32) {
+            Ok(addr)    => addr.as_u64(),
+            Err(_)      => panic!("Allocation failed"),
+        }
+    }
+
+    fn release(buddy: &mut Buddy, addr: u64, order: u8) -> bool {
+        unsafe { buddy.release_contiguous(PhysAddr::new(addr), order) }
+                .is_ok()
+    }
+
+    #[test]
+    fn splits_block_into_buddies() {
+        let mut areas = unsafe { zeroed() };
+        let mut info = unsafe { zeroed() };
+        let mut buddy = unsafe { buddy(&mut areas, &mut info) };
+        assert_eq!(free_blocks(&buddy)[6], 1);
+
+        assert_eq!(alloc(&mut buddy, 0), START);
+        let blocks = free_blocks(&buddy);
+        for order in 0..6 {
+            assert_eq!(blocks[order], 1);
+        }
+        assert_eq!(blocks[6], 0);
+        assert_eq!(buddy.zone_free_frames(Zone::Dma32), FRAMES - 1);
+
+        // Smallest fitting block is split next.
+        assert_eq!(alloc(&mut buddy, 1), START + 2 * FRAME_SIZE);
+        assert_eq!(alloc(&mut buddy, 0), START + FRAME_SIZE);
+        assert!(buddy.alloc_in_zone(6, Zone::Dma32).is_err());
+        assert!(buddy.alloc_in_zone(0, Zone::Dma).is_err());
+    }
+
+    #[test]
+    fn merges_released_buddies() {
+        let mut areas = unsafe { zeroed() };
+        let mut info = unsafe { zeroed() };
+        let mut buddy = unsafe { buddy(&mut areas, &mut info) };
+
+        let a = alloc(&mut buddy, 0);
+        let b = alloc(&mut buddy, 0);
+        let c = alloc(&mut buddy, 2);
+        assert!(release(&mut buddy, b, 0));
+        assert!(free_blocks(&buddy)[0] == 1);
+
+        // Both buddies merge and then join the free order 1 block.
+        assert!(release(&mut buddy, a, 0));
+        assert!(free_blocks(&buddy)[0] == 0);
+        assert!(free_blocks(&buddy)[2] == 1);
+
+        assert!(release(&mut buddy, c, 2));
+        assert_eq!(free_blocks(&buddy)[6], 1);
+        assert_eq!(buddy.zone_free_frames(Zone::Dma32), FRAMES);
+    }
+
+    #[test]
+    fn rejects_invalid_release() {
+        let mut areas = unsafe { zeroed() };
+        let mut info = unsafe { zeroed() };
+        let mut buddy = unsafe { buddy(&mut areas, &mut info) };
+
+        let a = alloc(&mut buddy, 2);
+        assert!(!release(&mut buddy, a, 1));
+        assert!(!release(&mut buddy, a + FRAME_SIZE, 0));
+        assert!(!release(&mut buddy, START + 0x100_0000, 0));
+
+        assert!(release(&mut buddy, a, 2));
+        assert!(!release(&mut buddy, a, 2));
+        assert_eq!(buddy.zone_free_frames(Zone::Dma32), FRAMES);
+    }
+
+    #[test]
+    fn keeps_blocks_below_top_limit() {
+        let mut areas = unsafe { zeroed() };
+        let mut info = unsafe { zeroed() };
+        let mut buddy = unsafe { buddy(&mut areas, &mut info) };
+
+        let _ = alloc(&mut buddy, 0);
+        buddy.set_top_limit(Some(START + 4 * FRAME_SIZE));
+        assert!(buddy.alloc_in_zone(2, Zone::Dma32).is_err());
+        assert_eq!(alloc(&mut buddy, 1), START + 2 * FRAME_SIZE);
+    }
+}
@@ -3,22 +3,66 @@ use super::Page2m;
 use super::Stack2m;
 use super::PageStatus;
 use super::PsaArray;
+use super::PsArray;
+use super::Range2m;
+use super::BootMemory;
+use super::Heap4kEntry;
+use super::map_heap::{Heap, EntryList, RelativeAddress, P4KS_IN_P2M};
+use super::frame::*;
+use super::zone::{Zone, Fallback, ZONE_COUNT};
+use super::super::map::BOOT_MAPPED_END;
+use mem::PhysAddr;
 
-type AlResult<T> = ::core::result::Result<T, AllocError>;
-type ReResult<T> = ::core::result::Result<T, ReleaseError>;
+/// Size of 2MiB page in bytes.
+const PAGE2M_SIZE: u64 = 0x200000;
 
 /// Pages allocator.
 pub struct Alloc {
 
-    /// Stack that contains all 2MiB pages that are free for allocation.
-    stk2    : Stack2m,
+    /// Stacks that contain all 2MiB pages that are free for allocation.
+    /// There is a separate stack for each zone.
+    stk2    : [Stack2m; ZONE_COUNT],
 
     /// Array that contains 2MiB page ranges that are controlled by memory
     /// controller. Each range itself is a fixed size array with page status
     /// objects of individual pages.
     psa     : PsaArray,
 
-    // TODO
+    /// Heap of 4KiB page status entries of split 2MiB pages.
+    heap    : Heap,
+
+    /// Entries of split pages that have free 4KiB pages. Each zone
+    /// has it's own list.
+    partial : [EntryList; ZONE_COUNT],
+
+    /// Count of free 4KiB pages in split pages of each zone.
+    free4k  : [usize; ZONE_COUNT],
+
+    /// Pages that end above this address are not given.
+    limit   : u64,
+}
+
+/// Usage of single range of 2MiB pages.
+#[derive(Clone, Copy)]
+pub struct RangeStats {
+
+    /// Address of the first page of the range.
+    pub bottom  : u64,
+
+    /// Address after the last page of the range.
+    pub top     : u64,
+
+    /// Zone the range belongs to.
+    pub zone    : Zone,
+
+    /// Count of 2MiB pages in the range.
+    pub pages   : u64,
+
+    /// Count of 2MiB pages that are used whole.
+    pub used    : u64,
+
+    /// Count of 2MiB pages that are split into 4KiB pages.
+    pub split   : u64,
 }
 
 /// Handle that allows to control the 2MiB page status and get page address.
@@ -33,21 +77,6 @@ pub struct Page4kHandle {
     stat    : *mut PageStatus,
 }
 
-/// Errors that allocator can return when some action cannot be
-/// performed for any reason.
-pub enum AllocError {
-
-    /// No more pages are available for allocation.
-    NoMorePages
-}
-
-pub enum ReleaseError {
-
-    /// Page usage counter is not zero. Maybe the page is still used by some
-    /// tables and thus cannot be released.
-    UsageCounterNonzero,
-}
-
 macro_rules! impl_page_handle {
     ($p:ty) => (
         pub fn page(&self) -> $p {
@@ -72,45 +101,309 @@ impl Page4kHandle {
     impl_page_handle!(Page4k);
 }
 
+/// Range of 2MiB pages of given zone that region fully covers, if any.
+fn region_range(start: u64, end: u64, zone: Zone) -> Option<Range2m> {
+    let start = if start > zone.bottom() { start } else { zone.bottom() };
+    let end = if end < zone.top() { end } else { zone.top() };
+    let bottom = (start + PAGE2M_SIZE - 1) & !(PAGE2M_SIZE - 1);
+    let top = end & !(PAGE2M_SIZE - 1);
+
+    if top > bottom {
+        Some(Range2m::new(top, bottom))
+    } else {
+        None
+    }
+}
+
 impl Alloc {
 
-    /// Allocate new 4KiB page.
+    /// Create allocator that manages all free memory. Data of the
+    /// allocator itself is stored in that free memory too.
+    ///
+    /// # Safety
+    /// Free memory regions must be really free and must not be used by
+    /// anything else. Should be called once.
+    pub unsafe fn new(mem: &mut BootMemory) -> Self {
+        use core::mem::size_of;
+
+        // Count ranges and pages. Data stored in free memory can only
+        // decrease these numbers so they are used as upper limits.
+        let mut range_count = 0;
+        let mut zone_pages = [0; ZONE_COUNT];
+        for region in mem.regions().iter() {
+            for i in 0..ZONE_COUNT {
+                let zone = Zone::from_index(i);
+                let range = region_range(region.start(), region.end(), zone);
+                if let Some(range) = range {
+                    range_count += 1;
+                    zone_pages[i] += range.length();
+                }
+            }
+        }
+        let page_count = zone_pages[0] + zone_pages[1] + zone_pages[2];
+
+        let psa_size = range_count * size_of::<PsArray>() as u64;
+        let stk_size = page_count * size_of::<Page2m>() as u64;
+        let pso_size = page_count * size_of::<PageStatus>() as u64;
+        let spl_size = page_count * size_of::<*mut Heap4kEntry>() as u64;
+
+        let take = |mem: &mut BootMemory, size| {
+            // Boot code maps only this memory in the direct map.
+            match mem.take(size, 8, BOOT_MAPPED_END) {
+                Some(addr) => PhysAddr::new(addr),
+                None => panic!("No memory for page allocator data"),
+            }
+        };
+        let psa_arr = take(mem, psa_size).as_mut_ptr::<PsArray>();
+        let stk_arr = take(mem, stk_size).as_mut_ptr::<Page2m>();
+        let pso_arr = take(mem, pso_size).as_mut_ptr::<PageStatus>();
+        let spl_arr = take(mem, spl_size).as_mut_ptr::<*mut Heap4kEntry>();
+
+        // Each zone gets it's own part of the stack array.
+        let mut stk2 = [
+            Stack2m::new(stk_arr),
+            Stack2m::new(stk_arr.offset(zone_pages[0] as _)),
+            Stack2m::new(stk_arr.offset((zone_pages[0] + zone_pages[1]) as _)),
+        ];
+
+        let mut length = 0;
+        let mut pso_next = pso_arr;
+        let mut spl_next = spl_arr;
+        for region in mem.regions().iter() {
+            for zi in 0..ZONE_COUNT {
+                let zone = Zone::from_index(zi);
+                let range = region_range(region.start(), region.end(), zone);
+                let range = match range {
+                    Some(range) => range,
+                    None        => continue,
+                };
+
+                let pages = range.length();
+                let bottom = range.bottom();
+                *psa_arr.offset(length as _) =
+                        PsArray::new(range, pso_next, spl_next);
+                pso_next = pso_next.offset(pages as _);
+                spl_next = spl_next.offset(pages as _);
+                length += 1;
+
+                for i in 0..pages {
+                    stk2[zi].push(Page2m::new(bottom + i * PAGE2M_SIZE));
+                }
+            }
+        }
+
+        Alloc {
+            stk2    : stk2,
+            psa     : PsaArray::new(psa_arr, length),
+            heap    : Heap::new(),
+            partial : [EntryList::new(), EntryList::new(), EntryList::new()],
+            free4k  : [0; ZONE_COUNT],
+            limit   : BOOT_MAPPED_END,
+        }
+    }
+
+    /// Take free 2MiB page of given zone and mark it as used by one user.
+    /// Page ends at or below the top limit. Split pages are taken from
+    /// this function too so their 4KiB pages obey the limit as well.
+    fn pop2m(&mut self, zone: Zone) -> AlResult<Page2m> {
+        let stack = &mut self.stk2[zone.index()];
+        let page = if self.limit == !0 {
+            stack.pop()
+        } else {
+            stack.pop_below(self.limit)
+        };
+        let page = match page {
+            Some(page)  => page,
+            None        => return Err(AllocError::NoMorePages),
+        };
+
+        unsafe { self.psa.page_status_mut_for(page).set_user(1); }
+        Ok(page)
+    }
+
+    /// Take free 2MiB page to store allocator data in. Normal zone is
+    /// tried first so scarce DMA memory is not spent on bookkeeping.
+    fn pop2m_meta(&mut self) -> AlResult<Page2m> {
+        let mut zone = Zone::Normal;
+        loop {
+            if let Ok(page) = self.pop2m(zone) {
+                return Ok(page);
+            }
+
+            zone = match zone.fallback(Fallback::Lower) {
+                Some(zone)  => zone,
+                None        => return Err(AllocError::NoMorePages),
+            };
+        }
+    }
+
+    /// Split free 2MiB page of given zone into 4KiB pages and add it to
+    /// the list of split pages with free 4KiB pages.
+    fn split2m(&mut self, zone: Zone) -> AlResult<()> {
+        if !self.heap.has_space() {
+            // Give heap one more frame to store entries in.
+            let frame = try!(self.pop2m_meta());
+            unsafe { self.heap.extend(frame); }
+        }
+
+        let page = try!(self.pop2m(zone));
+        let entry = self.heap.store(page) as *mut Heap4kEntry;
+
+        unsafe {
+            self.psa.array_with_page_mut_unsafe(page)
+                    .set_heap_entry_for(page, entry);
+            self.partial[zone.index()].push(entry);
+        }
+        self.free4k[zone.index()] += P4KS_IN_P2M;
+
+        Ok(())
+    }
+
+    /// Return split 2MiB page which 4KiB pages are all free back
+    /// to the stack of 2MiB pages.
+    ///
+    /// # Safety
+    /// Entry must be in the list of split pages with free 4KiB pages.
+    unsafe fn merge2m(&mut self, entry: *mut Heap4kEntry) {
+        let page = (*entry).page();
+        let zone = Zone::of(page.addr()).index();
+
+        self.partial[zone].remove(entry);
+        self.heap.remove(&*entry);
+        self.free4k[zone] -= P4KS_IN_P2M;
+
+        self.psa.array_with_page_mut_unsafe(page)
+                .set_heap_entry_for(page, ::core::ptr::null_mut());
+        self.psa.page_status_mut_for(page).set_user(0);
+        self.stk2[zone].push(page);
+    }
+
+    /// Allocate new 4KiB page in given zone. Usage counter of the page is
+    /// set to one which stands for the owner of returned handle.
     ///
     /// # Errors
     /// NoMorePages error occurs when no free pages of given type could be
-    /// allocated.
-    pub fn alloc4k(&mut self) -> AlResult<Page4kHandle> {
-        unimplemented!()
+    /// allocated in the zone.
+    pub fn alloc4k(&mut self, zone: Zone) -> AlResult<Page4kHandle> {
+        let zi = zone.index();
+        if self.partial[zi].is_empty() {
+            try!(self.split2m(zone));
+        }
+
+        let entry = self.partial[zi].top();
+        unsafe {
+            // Entries in the list always have free pages.
+            let reladdr = (*entry).alloc().unwrap();
+            self.free4k[zi] -= 1;
+
+            if (*entry).is_full() {
+                self.partial[zi].remove(entry);
+            }
+
+            let stat = (*entry).status_ptr(&reladdr);
+            let page = reladdr.into_absolute((*entry).page());
+
+            Ok(Page4kHandle {
+                page    : page,
+                stat    : stat,
+            })
+        }
     }
 
-    /// Allocate new 2MiB page.
+    /// Allocate new 2MiB page in given zone. Usage counter of the page is
+    /// set to one which stands for the owner of returned handle.
     ///
     /// # Errors
     /// NoMorePages error occurs when no free pages of given type could be
-    /// allocated.
-    pub fn alloc2m(&mut self) -> AlResult<Page2mHandle> {
-        unimplemented!()
+    /// allocated in the zone.
+    pub fn alloc2m(&mut self, zone: Zone) -> AlResult<Page2mHandle> {
+        let page = try!(self.pop2m(zone));
+        let stat = unsafe { self.psa.page_status_mut_for(page) };
+
+        Ok(Page2mHandle {
+            page    : page,
+            stat    : stat,
+        })
     }
 
-    /// Release previously allocated 4KiB Page.
+    /// Release previously allocated 4KiB Page. When all 4KiB pages of
+    /// the split 2MiB page get free, the 2MiB page is returned to the
+    /// stack of free 2MiB pages.
+    ///
+    /// # Errors
+    /// UsageCounterNonzero error occurs when page is used by someone except
+    /// the owner of the handle. NotAllocated error occurs when the page is
+    /// already free or is not in the split 2MiB page.
     ///
     /// # Safety
     /// Page handle must be the one created by this allocator instance.
     pub unsafe fn release4k(&mut self, page: Page4kHandle) -> ReResult<()> {
-        unimplemented!()
+        let base = page.page().base();
+        let (entry, zone) = match self.psa.array_with_page(base) {
+            Some(arr)   => (arr.heap_entry_for(base), arr.zone().index()),
+            None        => return Err(ReleaseError::NotAllocated),
+        };
+        let reladdr = RelativeAddress::new_by_count(page.page().index() as _);
+        if entry.is_null() || (*entry).is_page_free(&reladdr) {
+            return Err(ReleaseError::NotAllocated);
+        }
+
+        if page.status().use_count() > 1 {
+            return Err(ReleaseError::UsageCounterNonzero);
+        }
+
+        let was_full = (*entry).is_full();
+        (*entry).dealloc(reladdr);
+        self.free4k[zone] += 1;
+
+        if was_full {
+            self.partial[zone].push(entry);
+        }
+
+        if (*entry).is_free() {
+            self.merge2m(entry);
+        }
+
+        Ok(())
     }
 
     /// Release previously allocated 2MiB Page.
     ///
+    /// # Errors
+    /// UsageCounterNonzero error occurs when page is used by someone except
+    /// the owner of the handle. NotAllocated error occurs when the page is
+    /// already free.
+    ///
     /// # Safety
     /// Page handle must be the one created by this allocator instance.
-    pub unsafe fn release2m(&mut self, page: Page2mHandle) -> ReResult<()> {
-        unimplemented!()
+    pub unsafe fn release2m(&mut self, mut page: Page2mHandle)
+            -> ReResult<()> {
+        if page.status().is_free() {
+            return Err(ReleaseError::NotAllocated);
+        }
+        if page.status().use_count() > 1 {
+            return Err(ReleaseError::UsageCounterNonzero);
+        }
+
+        page.status_mut().set_user(0);
+        let zone = Zone::of(page.page().addr());
+        self.stk2[zone.index()].push(page.page());
+
+        Ok(())
     }
 
     /// Amount of free 2MiB pages.
     pub fn free2m_pages(&self) -> usize {
-        unimplemented!()
+        let mut sum = 0;
+        for i in 0..ZONE_COUNT {
+            sum += self.zone_free2m_pages(Zone::from_index(i));
+        }
+        sum
+    }
+
+    /// Amount of free 2MiB pages in given zone.
+    pub fn zone_free2m_pages(&self, zone: Zone) -> usize {
+        self.stk2[zone.index()].count() as usize
     }
 
     /// Amount of free memory in bytes that are covered by 2MiB pages.
@@ -121,7 +414,12 @@ impl Alloc {
 
     /// Amount of free 4KiB pages.
     pub fn free4k_pages(&self) -> usize {
-        unimplemented!()
+        self.free4k[0] + self.free4k[1] + self.free4k[2]
+    }
+
+    /// Amount of free 4KiB pages in split pages of given zone.
+    pub fn zone_free4k_pages(&self, zone: Zone) -> usize {
+        self.free4k[zone.index()]
     }
 
     /// Amount of free memory in bytes that are covered by 4KiB pages.
@@ -134,4 +432,165 @@ impl Alloc {
     pub fn free_memory_size(&self) -> usize {
         self.free2m_bytes() + self.free4k_bytes()
     }
+
+    /// Count of ranges of 2MiB pages that allocator manages.
+    pub fn range_count(&self) -> u32 {
+        self.psa.length()
+    }
+
+    /// Usage of the range with given index.
+    pub fn range_stats(&self, index: u32) -> RangeStats {
+        let arr = &self.psa[index as u64];
+        let (used, split) = arr.usage();
+        RangeStats {
+            bottom  : arr.range().bottom(),
+            top     : arr.range().top(),
+            zone    : arr.zone(),
+            pages   : arr.range().length(),
+            used    : used,
+            split   : split,
+        }
+    }
+
+    /// Count of 2MiB pages that are split into 4KiB pages.
+    pub fn split_pages(&self) -> usize {
+        self.heap.entry_count()
+    }
+
+    /// Count of 2MiB frames that store status of 4KiB pages of split
+    /// pages.
+    pub fn heap_frames(&self) -> usize {
+        self.heap.frame_count()
+    }
+
+    /// Handle of allocated 4KiB page that is stored at given address.
+    /// None if there is no such page.
+    fn page4k_handle(&mut self, addr: u64) -> Option<Page4kHandle> {
+        let base = Page2m::new(addr & !(PAGE2M_SIZE - 1));
+        let entry = match self.psa.array_with_page(base) {
+            Some(arr)   => unsafe { arr.heap_entry_for(base) },
+            None        => return None,
+        };
+        if entry.is_null() {
+            return None;
+        }
+
+        let index = ((addr - base.addr()) / FRAME_SIZE) as usize;
+        let reladdr = RelativeAddress::new_by_count(index);
+        let stat = unsafe { (*entry).status_ptr(&reladdr) };
+        if unsafe { (*stat).is_free() } {
+            return None;
+        }
+
+        Some(Page4kHandle {
+            page    : reladdr.into_absolute(base),
+            stat    : stat,
+        })
+    }
+
+    /// Handle of allocated 2MiB page that is stored at given address.
+    /// None if there is no such page or if page was split.
+    fn page2m_handle(&mut self, addr: u64) -> Option<Page2mHandle> {
+        let page = Page2m::new(addr & !(PAGE2M_SIZE - 1));
+        let arr = match self.psa.array_with_page_mut(page) {
+            Some(arr)   => arr,
+            None        => return None,
+        };
+
+        if unsafe { !arr.heap_entry_for(page).is_null() } {
+            return None;
+        }
+
+        let stat = unsafe { arr.page_status_mut_for(page) as *mut PageStatus };
+        if unsafe { (*stat).is_free() } {
+            return None;
+        }
+
+        Some(Page2mHandle {
+            page    : page,
+            stat    : stat,
+        })
+    }
+}
+
+impl FrameAlloc for Alloc {
+
+    // Not inlined so allocation site is found at fixed depth in debug
+    // mode.
+    #[inline(never)]
+    fn alloc_in_zone(&mut self, order: u8, zone: Zone)
+            -> AlResult<PhysAddr> {
+        let addr = match order {
+            0           => self.alloc4k(zone).map(|h| h.page().addr()),
+            ORDER_2M    => self.alloc2m(zone).map(|h| h.page().addr()),
+            _           => Err(AllocError::OrderUnsupported),
+        };
+        let addr = try!(addr.map(PhysAddr::new));
+        frames_allocated(addr, order);
+        Ok(addr)
+    }
+
+    unsafe fn release_contiguous(&mut self, addr: PhysAddr, order: u8)
+            -> ReResult<()> {
+        let phys = addr;
+        let addr = addr.as_u64();
+
+        // Block is poisoned before it is released as released block can
+        // be taken by other processor right away. Only blocks that will
+        // be released get poisoned.
+        match order {
+            0 => {
+                let page = match self.page4k_handle(addr) {
+                    Some(page)  => page,
+                    None        => return Err(ReleaseError::NotAllocated),
+                };
+                if page.status().use_count() > 1 {
+                    return Err(ReleaseError::UsageCounterNonzero);
+                }
+                frames_released(phys, order);
+                self.release4k(page)
+            },
+            ORDER_2M => {
+                let page = match self.page2m_handle(addr) {
+                    Some(page)  => page,
+                    None        => return Err(ReleaseError::NotAllocated),
+                };
+                if page.status().use_count() > 1 {
+                    return Err(ReleaseError::UsageCounterNonzero);
+                }
+                frames_released(phys, order);
+                self.release2m(page)
+            },
+            _ => Err(ReleaseError::NotAllocated),
+        }
+    }
+
+    fn status_mut(&mut self, addr: PhysAddr) -> Option<&mut PageStatus> {
+        let addr = addr.as_u64();
+        if let Some(handle) = self.page4k_handle(addr) {
+            return Some(unsafe { &mut *handle.stat });
+        }
+
+        match self.page2m_handle(addr) {
+            Some(handle)    => Some(unsafe { &mut *handle.stat }),
+            None            => None,
+        }
+    }
+
+    fn zone_free_frames(&self, zone: Zone) -> usize {
+        self.zone_free2m_pages(zone) * P4KS_IN_P2M
+                + self.zone_free4k_pages(zone)
+    }
+
+    fn zone_frag_stats(&self, zone: Zone) -> FragStats {
+        let mut stats = FragStats::new();
+        stats.free_blocks[0] = self.zone_free4k_pages(zone);
+        stats.free_blocks[ORDER_2M as usize] = self.zone_free2m_pages(zone);
+        stats.free_frames = self.zone_free_frames(zone);
+        stats
+    }
+
+    fn set_top_limit(&mut self, limit: Option<u64>) {
+        self.limit = limit.unwrap_or(!0);
+    }
 }
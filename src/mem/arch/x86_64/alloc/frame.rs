@@ -0,0 +1,241 @@
+use super::PageStatus;
+use mem::PhysAddr;
+use super::zone::{Zone, Fallback, ZONE_COUNT};
+
+/// Size of the smallest frame that allocators give.
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Order of the 2MiB frame. Order is a power of two of the frame count
+/// in the block: order 0 is a 4KiB frame, order 9 is 512 frames.
+pub const ORDER_2M: u8 = 9;
+
+/// Highest order of the block that frame allocators can give. Blocks of
+/// this order are 1GiB in size.
+pub const MAX_ORDER: u8 = 18;
+
+pub type AlResult<T> = ::core::result::Result<T, AllocError>;
+pub type ReResult<T> = ::core::result::Result<T, ReleaseError>;
+
+/// Errors that allocator can return when some action cannot be
+/// performed for any reason.
+pub enum AllocError {
+
+    /// No more pages are available for allocation.
+    NoMorePages,
+
+    /// Allocator is unable to give blocks of requested order.
+    OrderUnsupported,
+}
+
+pub enum ReleaseError {
+
+    /// Page usage counter is not zero. Maybe the page is still used by some
+    /// tables and thus cannot be released.
+    UsageCounterNonzero,
+
+    /// Given block was not allocated by the allocator or the order of the
+    /// block does not match.
+    NotAllocated,
+}
+
+/// Statistics of free memory fragmentation.
+pub struct FragStats {
+
+    /// Count of free blocks of each order.
+    pub free_blocks     : [usize; MAX_ORDER as usize + 1],
+
+    /// Count of free 4KiB frames in all free blocks.
+    pub free_frames     : usize,
+}
+
+/// Allocator of physical memory frames. Frames are given in blocks of
+/// contiguous memory which size is power of two of 4KiB frames. Free
+/// frames of each memory zone are kept in separate pools.
+pub trait FrameAlloc {
+
+    /// Allocate physically contiguous block of given order in given zone.
+    /// Address of the block is aligned to the block size. Usage counter of
+    /// the block is set to one which stands for the owner of the block.
+    ///
+    /// # Errors
+    /// NoMorePages error occurs when no free block of given order could be
+    /// allocated in the zone. OrderUnsupported is returned when allocator
+    /// does not give blocks of given order at all.
+    fn alloc_in_zone(&mut self, order: u8, zone: Zone) -> AlResult<PhysAddr>;
+
+    /// Release previously allocated block.
+    ///
+    /// # Errors
+    /// UsageCounterNonzero error occurs when block is used by someone except
+    /// the owner. NotAllocated error occurs when there is no allocated block
+    /// of given order at given address.
+    ///
+    /// # Safety
+    /// Memory of the block must not be used after the release.
+    unsafe fn release_contiguous(&mut self, addr: PhysAddr, order: u8)
+            -> ReResult<()>;
+
+    /// Page Status of allocated block that contains given address. None if
+    /// address is not in the allocated block of this allocator.
+    fn status_mut(&mut self, addr: PhysAddr) -> Option<&mut PageStatus>;
+
+    /// Amount of free 4KiB frames in given zone.
+    fn zone_free_frames(&self, zone: Zone) -> usize;
+
+    /// Statistics of free memory fragmentation in given zone.
+    fn zone_frag_stats(&self, zone: Zone) -> FragStats;
+
+    /// Give only blocks that end at or below given address. None removes
+    /// the limit. Allocator starts limited to memory that boot code maps
+    /// in the direct map so that it's own data and given blocks are
+    /// accessible before kernel paging is loaded.
+    fn set_top_limit(&mut self, limit: Option<u64>);
+
+    /// Allocate physically contiguous block of given order in given zone.
+    /// When the zone is exhausted lower zones are tried as fallback policy
+    /// allows.
+    ///
+    /// # Errors
+    /// Same as for `alloc_in_zone`. Error of the last tried zone is
+    /// returned.
+    #[inline(always)]
+    fn alloc_constrained(&mut self, order: u8, zone: Zone, policy: Fallback)
+            -> AlResult<PhysAddr> {
+        let mut zone = zone;
+        loop {
+            match self.alloc_in_zone(order, zone) {
+                Err(AllocError::NoMorePages) => (),
+                result => return result,
+            }
+
+            zone = match zone.fallback(policy) {
+                Some(zone)  => zone,
+                None        => return Err(AllocError::NoMorePages),
+            };
+        }
+    }
+
+    /// Allocate physically contiguous block of given order in any zone.
+    /// ISA DMA zone is used only if no other memory is left.
+    #[inline(always)]
+    fn alloc_contiguous(&mut self, order: u8) -> AlResult<PhysAddr> {
+        self.alloc_constrained(order, Zone::Normal, Fallback::Lower)
+    }
+
+    /// Allocate single 4KiB frame.
+    #[inline(always)]
+    fn alloc_frame(&mut self) -> AlResult<PhysAddr> {
+        self.alloc_contiguous(0)
+    }
+
+    /// Release single 4KiB frame.
+    ///
+    /// # Safety
+    /// Memory of the frame must not be used after the release.
+    unsafe fn release_frame(&mut self, addr: PhysAddr) -> ReResult<()> {
+        self.release_contiguous(addr, 0)
+    }
+
+    /// Amount of free 4KiB frames.
+    fn free_frames(&self) -> usize {
+        let mut sum = 0;
+        for i in 0..ZONE_COUNT {
+            sum += self.zone_free_frames(Zone::from_index(i));
+        }
+        sum
+    }
+
+    /// Statistics of free memory fragmentation.
+    fn frag_stats(&self) -> FragStats {
+        let mut stats = FragStats::new();
+        for i in 0..ZONE_COUNT {
+            stats.add(&self.zone_frag_stats(Zone::from_index(i)));
+        }
+        stats
+    }
+
+    /// Amount of free memory in bytes.
+    fn free_memory_size(&self) -> usize {
+        self.free_frames() * FRAME_SIZE as usize
+    }
+}
+
+/// Check the block that allocator gives and record it's allocation site
+/// in debug mode.
+#[cfg(mem_debug)]
+pub use super::super::memdebug::frames_allocated;
+
+/// Poison the block that allocator is about to take back in debug mode.
+#[cfg(mem_debug)]
+pub use super::super::memdebug::frames_released;
+
+/// Check the block that allocator gives. Does nothing unless kernel is
+/// built with 'mem_debug' configuration.
+#[cfg(not(mem_debug))]
+pub fn frames_allocated(_: PhysAddr, _: u8) {
+}
+
+/// Poison the block that allocator is about to take back. Does nothing
+/// unless kernel is built with 'mem_debug' configuration.
+#[cfg(not(mem_debug))]
+pub fn frames_released(_: PhysAddr, _: u8) {
+}
+
+/// Size in bytes of the block of given order.
+pub fn order_size(order: u8) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// Smallest order of the block that can store given amount of bytes.
+pub fn size_order(size: u64) -> u8 {
+    let mut order = 0;
+    while order_size(order) < size {
+        order += 1;
+    }
+    order
+}
+
+impl FragStats {
+
+    /// Statistics with no free blocks.
+    pub fn new() -> Self {
+        FragStats {
+            free_blocks     : [0; MAX_ORDER as usize + 1],
+            free_frames     : 0,
+        }
+    }
+
+    /// Add statistics of other pool to this one.
+    pub fn add(&mut self, other: &FragStats) {
+        for i in 0..self.free_blocks.len() {
+            self.free_blocks[i] += other.free_blocks[i];
+        }
+        self.free_frames += other.free_frames;
+    }
+
+    /// Highest order of free block, if any.
+    pub fn largest_order(&self) -> Option<u8> {
+        let mut order = MAX_ORDER as usize + 1;
+        while order > 0 {
+            order -= 1;
+            if self.free_blocks[order] != 0 {
+                return Some(order as u8);
+            }
+        }
+
+        None
+    }
+
+    /// Percentage of free memory that is not in the largest free block
+    /// order. Zero means all free memory is available for the largest
+    /// allocations and 100 means free memory is split into tiny pieces.
+    pub fn fragmentation(&self) -> u8 {
+        let order = match self.largest_order() {
+            Some(order) => order,
+            None        => return 0,
+        };
+
+        let largest = self.free_blocks[order as usize] << order;
+        (100 - largest * 100 / self.free_frames) as u8
+    }
+}
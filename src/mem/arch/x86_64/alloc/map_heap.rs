@@ -1,14 +1,26 @@
-// TODO remove. Module is probably messed up.
-
 use super::PageStatus;
+use mem::PhysAddr;
 use super::Page4k;
 use super::Page2m;
 
 /// Number of 4KiB pages in one split 2MiB page.
 ///
-/// 2048 - 2MiB page size; divided by 4 - 4KiB page size that this bigger
-/// page was split into; divided by 8 - bits count in one byte.
-pub const P4KS_IN_P2M   : usize = 2048 / 4 / 8;
+/// 2048 - 2MiB page size in KiB; divided by 4 - 4KiB page size that this
+/// bigger page was split into.
+pub const P4KS_IN_P2M   : usize = 2048 / 4;
+
+/// Number of qwords in the bitmap of one split 2MiB page.
+const BITMAP_QWORDS     : usize = P4KS_IN_P2M / 64;
+
+/// Number of heap entries that one 2MiB frame of the heap array stores.
+/// Chunk header and all the entries must fit in the frame.
+const CHUNK_ENTRIES     : usize = 960;
+
+/// Number of qwords in the heap map of one chunk.
+const CHUNK_MAP_QWORDS  : usize = CHUNK_ENTRIES / 64;
+
+/// Size of 2MiB frame that stores one chunk.
+const CHUNK_SIZE        : usize = 0x200000;
 
 const PAGE_ALLOCATED    : bool = false;
 const PAGE_FREE         : bool = true;
@@ -29,39 +41,73 @@ pub struct RelativeAddress {
 #[repr(packed)]
 struct Bitmap {
     /// Array of bytes of the bitmap.
-    arr     : [Qword; P4KS_IN_P2M / 8],
+    arr     : [Qword; BITMAP_QWORDS],
 }
 
 /// 4KiB page status array heap entry.
 pub struct HeapEntry {
     bitmap      : Bitmap,
     status_arr  : [PageStatus; P4KS_IN_P2M],
+
+    /// Split 2MiB page which 4KiB pages are described by this entry.
+    page        : Page2m,
+
+    /// Next entry in the entry list. Null if this entry is the last one.
+    next        : *mut HeapEntry,
+
+    /// Previous entry in the entry list. Null if this entry is the first
+    /// one.
+    prev        : *mut HeapEntry,
+}
+
+/// Doubly linked list of heap entries. Entries are linked through their
+/// own fields so each entry can be stored in one list only.
+pub struct EntryList {
+    top     : *mut HeapEntry,
 }
 
 /// Map stores data about which cells of the array are used and which are
 /// empty. Is used by Heap.
 struct HeapMap {
-    arr     : *mut Qword,
+    arr     : [Qword; CHUNK_MAP_QWORDS],
 }
 
-/// Array that stores heap entries. Used by the Heap.
-struct HeapArray {
+/// Header of the 2MiB frame that stores heap entries. Entries are placed
+/// right after the header. Frames are 2MiB aligned so the chunk of any
+/// entry is found by the entry address.
+struct Chunk {
 
-    /// Array that stores heap entries.
-    arr     : *mut HeapEntry,
+    /// Next chunk of the array. Null if this chunk is the last one.
+    next        : *mut Chunk,
+
+    /// Next chunk that has free entries. Null if this chunk is the last
+    /// one with free entries or if this chunk is full.
+    next_free   : *mut Chunk,
+
+    /// How many chunk entries are free.
+    free        : u32,
+
+    /// Map stores data about which cells of the chunk are used and which
+    /// are empty.
+    map         : HeapMap,
+}
 
-    /// Next heap entry that is free. Null when free entry is unknown.
-    next_free   : *mut HeapEntry,
+/// Array that stores heap entries. Used by the Heap. Array consists of
+/// chunks each stored in separate 2MiB frame so the array can grow by
+/// getting more frames.
+struct HeapArray {
+
+    /// First chunk of the array. Null if array has no chunks.
+    chunks      : *mut Chunk,
 
-    /// Length of the array in bytes.
-    byteslen    : u32,
+    /// First chunk that has free entries. Null when array is full.
+    free_chunks : *mut Chunk,
 
     /// How many array entries are free.
-    free    : u32,
+    free        : u32,
 
-    /// Map stores data about which cells of the array are used and which are
-    /// empty.
-    map     : HeapMap,
+    /// How many chunks the array has.
+    chunk_count : u32,
 }
 
 /// Heap of page maps that store 4KiB page status of divided 2MiB page.
@@ -75,7 +121,7 @@ impl Default for Bitmap {
 
     fn default() -> Self {
         Bitmap {
-            arr     : [Default::default(); P4KS_IN_P2M / 8]
+            arr     : [Default::default(); BITMAP_QWORDS]
         }
     }
 }
@@ -93,7 +139,7 @@ impl Qword {
 
     /// Bit value by given index.
     pub fn bit(&self, index: usize) -> bool {
-        self.val >> index != 0
+        (self.val >> index) & 1 != 0
     }
 
     /// Set bit by given index to specified value.
@@ -107,7 +153,25 @@ impl Qword {
 
     /// Find the first set bit in this Qword.
     pub fn first_set_bit(&self) -> Option<u8> {
-        unimplemented!()
+        let val = self.val;
+        if val == 0 {
+            None
+        } else {
+            // Compiles to single 'tzcnt' or 'bsf' instruction.
+            Some(val.trailing_zeros() as u8)
+        }
+    }
+
+    /// Whether all bits are set.
+    pub fn is_full(&self) -> bool {
+        let val = self.val;
+        val == !0
+    }
+
+    /// Count of set bits.
+    pub fn count_set_bits(&self) -> u32 {
+        let val = self.val;
+        val.count_ones()
     }
 }
 
@@ -173,15 +237,81 @@ impl Bitmap {
     /// Find first set bit and get it's indices. These are: first for qword
     /// which hold set bit and next is bit's index in this qword.
     pub fn first_set_bit(&self) -> Option<(usize, usize)> {
-        unimplemented!()
+        for i in 0..BITMAP_QWORDS {
+            if let Some(bit) = self.arr[i].first_set_bit() {
+                return Some((i, bit as usize));
+            }
+        }
+
+        None
+    }
+
+    /// Whether all bits are set.
+    pub fn is_full(&self) -> bool {
+        for i in 0..BITMAP_QWORDS {
+            if !self.arr[i].is_full() {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Count of set bits.
+    pub fn count_set_bits(&self) -> u32 {
+        let mut count = 0;
+        for i in 0..BITMAP_QWORDS {
+            count += self.arr[i].count_set_bits();
+        }
+        count
     }
 }
 
 impl HeapEntry {
 
+    /// Initialize entry for given page. All 4KiB pages are marked as free.
+    fn init(&mut self, page: Page2m) {
+        use core::ptr::null_mut;
+
+        self.bitmap = Default::default();
+        for status in self.status_arr.iter_mut() {
+            status.set_user(0);
+        }
+        self.page = page;
+        self.next = null_mut();
+        self.prev = null_mut();
+    }
+
     /// Check if all 4KiB pages are free.
     pub fn is_free(&self) -> bool {
-        unimplemented!()
+        // Set bits mark free pages.
+        self.bitmap.is_full()
+    }
+
+    /// Count of free 4KiB pages.
+    pub fn free_pages(&self) -> usize {
+        self.bitmap.count_set_bits() as usize
+    }
+
+    /// Check if all 4KiB pages are allocated.
+    pub fn is_full(&self) -> bool {
+        self.first_free_page().is_none()
+    }
+
+    /// Split 2MiB page which 4KiB pages are described by this entry.
+    pub fn page(&self) -> Page2m {
+        self.page
+    }
+
+    /// Pointer to the status of 4KiB page with given relative address.
+    pub fn status_ptr(&mut self, reladdr: &RelativeAddress)
+            -> *mut PageStatus {
+        &mut self.status_arr[reladdr.count()]
+    }
+
+    /// Whether 4KiB page with given relative address is free.
+    pub fn is_page_free(&self, reladdr: &RelativeAddress) -> bool {
+        self.bitmap.bit(reladdr.count()) == PAGE_FREE
     }
 
     fn first_free_page(&self) -> Option<(usize, usize)> {
@@ -220,8 +350,70 @@ impl HeapEntry {
     }
 }
 
+impl EntryList {
+
+    /// Create empty list.
+    pub fn new() -> Self {
+        EntryList {
+            top     : ::core::ptr::null_mut(),
+        }
+    }
+
+    /// First entry of the list. Null if list is empty.
+    pub fn top(&self) -> *mut HeapEntry {
+        self.top
+    }
+
+    /// Whether the list has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.top.is_null()
+    }
+
+    /// Add entry to the top of the list.
+    ///
+    /// # Safety
+    /// Entry must not be in any list.
+    pub unsafe fn push(&mut self, entry: *mut HeapEntry) {
+        (*entry).prev = ::core::ptr::null_mut();
+        (*entry).next = self.top;
+        if !self.top.is_null() {
+            (*self.top).prev = entry;
+        }
+        self.top = entry;
+    }
+
+    /// Remove entry from the list.
+    ///
+    /// # Safety
+    /// Entry must be in this list.
+    pub unsafe fn remove(&mut self, entry: *mut HeapEntry) {
+        let next = (*entry).next;
+        let prev = (*entry).prev;
+
+        if prev.is_null() {
+            self.top = next;
+        } else {
+            (*prev).next = next;
+        }
+
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+
+        (*entry).next = ::core::ptr::null_mut();
+        (*entry).prev = ::core::ptr::null_mut();
+    }
+}
+
 impl HeapMap {
 
+    /// New map with all cells marked as free.
+    fn new() -> Self {
+        HeapMap {
+            arr     : [Default::default(); CHUNK_MAP_QWORDS],
+        }
+    }
+
     pub fn set_bit(&mut self, index: usize, val: bool) {
         let ptrs = self.qword_by_bit_index_mut(index);
         ptrs.0.set_bit(ptrs.1, val);
@@ -237,52 +429,92 @@ impl HeapMap {
     /// Qword mutable reference with bit with given index.
     fn qword_by_bit_index_mut(&mut self, index: usize) -> (&mut Qword, usize) {
         let indices = self.qword_index(index);
-        unsafe { (&mut *self.arr.offset(indices.0 as _), indices.1) }
+        (&mut self.arr[indices.0], indices.1)
+    }
+
+    /// Index of the first set bit, if any.
+    fn first_set_bit(&self) -> Option<usize> {
+        for i in 0..CHUNK_MAP_QWORDS {
+            if let Some(bit) = self.arr[i].first_set_bit() {
+                return Some(i * 64 + bit as usize);
+            }
+        }
+
+        None
+    }
+}
+
+impl Chunk {
+
+    /// Pointer to the entry with given index.
+    fn entry_ptr(&mut self, index: usize) -> *mut HeapEntry {
+        use core::mem::size_of;
+
+        let base = self as *mut Chunk as usize + size_of::<Chunk>();
+        (base + index * size_of::<HeapEntry>()) as *mut HeapEntry
+    }
+
+    /// Chunk that stores given entry and the index of the entry in it.
+    fn of_entry(entry: &HeapEntry) -> (*mut Chunk, usize) {
+        use core::mem::size_of;
+
+        let addr = entry as *const HeapEntry as usize;
+        let chunk = addr & !(CHUNK_SIZE - 1);
+        let offset = addr - chunk - size_of::<Chunk>();
+        let index = offset / size_of::<HeapEntry>();
+
+        (chunk as *mut Chunk, index)
     }
 }
 
 impl HeapArray {
 
+    /// New array without chunks.
+    fn new() -> Self {
+        use core::ptr::null_mut;
+
+        HeapArray {
+            chunks      : null_mut(),
+            free_chunks : null_mut(),
+            free        : 0,
+            chunk_count : 0,
+        }
+    }
+
     /// Allocate new heap entry in the heap array.
     ///
     /// # Safety
     /// Caller must ensure there is free space in the array. Otherwise
     /// behaviour is undefined.
     pub unsafe fn alloc(&mut self) -> &mut HeapEntry {
-        use core::ptr::null_mut;
-        if self.next_free == null_mut() {
-            self.find_next_free();
-        }
-
-        // Next free entry
-        let entry = &mut *self.next_free;
-        self.next_free = null_mut();
-        // TODO optimize
+        let (chunk, index) = self.find_next_free();
+        let chunk = &mut *chunk;
 
-        // Save changes to bitmap.
-        let index = self.ref_to_index(entry);
-        self.map.set_bit(index, PAGE_ALLOCATED);
+        // Save changes to the map.
+        chunk.map.set_bit(index, PAGE_ALLOCATED);
 
-        // Update free pages counter.
+        // Update free entries counters. Full chunk is removed from the
+        // list of chunks with free entries. It is always the first one.
+        chunk.free -= 1;
         self.free -= 1;
+        if chunk.free == 0 {
+            self.free_chunks = chunk.next_free;
+            chunk.next_free = ::core::ptr::null_mut();
+        }
 
-        entry
-    }
-
-    /// Find next free entry and set HeapArray pointer to this value.
-    fn find_next_free(&mut self) {
-        unimplemented!()
+        &mut *chunk.entry_ptr(index)
     }
 
-    /// Convert entry reference to the array index of that element in array.
-    fn ref_to_index(&self, entry: &HeapEntry) -> usize {
-        use core::mem::size_of;
-
-        let entry_size = size_of::<HeapEntry>();
-        let base = self.base();
-        let addr = entry as *const _ as usize;
+    /// Find next free entry. Returns the chunk with this entry and the
+    /// index of the entry in the chunk.
+    ///
+    /// # Safety
+    /// Array must have free entries.
+    unsafe fn find_next_free(&self) -> (*mut Chunk, usize) {
+        let chunk = self.free_chunks;
+        let index = (*chunk).map.first_set_bit().unwrap();
 
-        (addr - base) / entry_size
+        (chunk, index)
     }
 
     /// Delete this entry from the array.
@@ -290,11 +522,21 @@ impl HeapArray {
     /// # Safety
     /// Caller must ensure this entry exists. Otherwise behaviour undefined.
     pub unsafe fn drop(&mut self, entry_ref: &HeapEntry) {
+        let (chunk, index) = Chunk::of_entry(entry_ref);
+        let chunk = &mut *chunk;
+
         // Mark entry as free in heap map.
-        let index = self.ref_to_index(entry_ref);
-        self.map.set_bit(index, PAGE_FREE);
+        chunk.map.set_bit(index, PAGE_FREE);
+
+        // Chunk that was full gets free entry and so is returned to the
+        // list of chunks with free entries.
+        if chunk.free == 0 {
+            chunk.next_free = self.free_chunks;
+            self.free_chunks = chunk;
+        }
 
-        // Update free pages counter.
+        // Update free entries counters.
+        chunk.free += 1;
         self.free += 1;
     }
 
@@ -303,29 +545,75 @@ impl HeapArray {
         self.free != 0
     }
 
-    /// Extend this array by given amount of bytes.
+    /// Extend this array by given 2MiB frame.
     ///
     /// # Safety
     /// Caller must ensure that array gets extended to free memory region.
     /// Otherwise some data may get corrupted.
-    pub unsafe fn extend(&mut self, by: usize) {
-        self.byteslen += by as u32;
+    pub unsafe fn extend(&mut self, frame: Page2m) {
+        use core::mem::size_of;
+        // Overflow would corrupt the next chunk, so check it in all builds.
+        let entries = CHUNK_ENTRIES * size_of::<HeapEntry>();
+        assert!(size_of::<Chunk>() + entries <= CHUNK_SIZE);
+
+        let chunk = PhysAddr::new(frame.addr()).as_mut_ptr::<Chunk>();
+        *chunk = Chunk {
+            next        : self.chunks,
+            next_free   : self.free_chunks,
+            free        : CHUNK_ENTRIES as u32,
+            map         : HeapMap::new(),
+        };
+
+        self.chunks = chunk;
+        self.free_chunks = chunk;
+        self.free += CHUNK_ENTRIES as u32;
+        self.chunk_count += 1;
+    }
 
-        // TODO extend bitmap too.
-        unimplemented!()
+    /// How many entries are used.
+    pub fn used(&self) -> usize {
+        self.chunk_count as usize * CHUNK_ENTRIES - self.free as usize
     }
 
-    /// Address of the array.
-    pub fn base(&self) -> usize {
-        self.arr as usize
+    /// How many 2MiB frames the array uses.
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_count as usize
     }
 }
 
 impl Heap {
 
-    /// Store given page in the heap.
+    /// Create empty heap that has no space for entries.
+    pub fn new() -> Self {
+        Heap {
+            arr     : HeapArray::new(),
+        }
+    }
+
+    /// Whether the heap can store one more entry without being extended.
+    pub fn has_space(&self) -> bool {
+        self.arr.has_space()
+    }
+
+    /// Give one more 2MiB frame to the heap to store entries in.
+    ///
+    /// # Safety
+    /// Frame must be free and must not be used by anything else while
+    /// the heap lives.
+    pub unsafe fn extend(&mut self, frame: Page2m) {
+        self.arr.extend(frame);
+    }
+
+    /// Store given page in the heap. All 4KiB pages of stored page
+    /// are free.
+    ///
+    /// Heap must have space for new entry.
     pub fn store(&mut self, page: Page2m) -> &mut HeapEntry {
-        unimplemented!()
+        assert!(self.has_space());
+
+        let entry = unsafe { self.arr.alloc() };
+        entry.init(page);
+        entry
     }
 
     /// Remove given heap entry by it's reference.
@@ -334,6 +622,16 @@ impl Heap {
     /// Ensure that given reference is created for the entry of this heap.
     /// Otherwise behaviour of the function is undefined.
     pub unsafe fn remove(&mut self, entry: &HeapEntry) {
-        unimplemented!()
+        self.arr.drop(entry);
+    }
+
+    /// Count of split pages stored in the heap.
+    pub fn entry_count(&self) -> usize {
+        self.arr.used()
+    }
+
+    /// Count of 2MiB frames that heap uses to store entries.
+    pub fn frame_count(&self) -> usize {
+        self.arr.chunk_count()
     }
 }
@@ -19,12 +19,42 @@ pub mod pso;
 /// that were created by dividing 2MiB pages.
 pub mod map_heap;
 
+/// Common interface of physical frame allocators.
+pub mod frame;
+
+/// Physical memory zones. Zones split memory by the limits of addresses
+/// that different classes of devices are able to access.
+pub mod zone;
+
+/// Buddy system frame allocator. Used instead of the page stack allocator
+/// when kernel is built with 'frame_alloc__buddy' configuration.
+pub mod buddy;
+
+/// Free memory discovery and allocation of the data page allocator
+/// needs to start.
+pub mod boot;
+
 pub use self::p2m::Page2m;
 pub use self::p2m::Range as Range2m;
 pub use self::p2m::Stack as Stack2m;
 
 pub use self::p4k::Page4k;
 
+pub use self::boot::BootMemory;
+
+pub use self::frame::FrameAlloc;
+
+pub use self::zone::Zone;
+pub use self::zone::Fallback as ZoneFallback;
+
+/// Frame allocator that kernel uses. Backend is chosen at build time.
+#[cfg(frame_alloc__buddy)]
+pub type KernelFrameAlloc = self::buddy::Buddy;
+
+/// Frame allocator that kernel uses. Backend is chosen at build time.
+#[cfg(not(frame_alloc__buddy))]
+pub type KernelFrameAlloc = self::ctrl::Alloc;
+
 pub use self::pso::PageStatus;
 pub use self::pso::PsArray;
 pub use self::pso::PsaArray;
@@ -1,3 +1,5 @@
+use super::Zone;
+
 /// Stack of page addresses.
 pub struct Stack {
 
@@ -16,14 +18,32 @@ pub struct Page2m {
     addr    : u64
 }
 
-/// 2MiB page ranges.
+/// 2MiB page ranges. Range never crosses zone boundaries and is tagged
+/// with the zone it belongs to.
 pub struct Range {
     bot     : u64,
     top     : u64,
+    zone    : Zone,
 }
 
 impl Stack {
 
+    /// Create empty stack that stores values in the array at given address.
+    ///
+    /// # Safety
+    /// Array must be big enough to store all values that will be pushed.
+    pub unsafe fn new(base: *mut Page2m) -> Self {
+        Stack {
+            top     : base.offset(-1),
+            count   : 0,
+        }
+    }
+
+    /// The count of addresses on the stack.
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
     /// Remove last value from the stack and return it.
     pub fn pop(&mut self) -> Option<Page2m> {
         if self.count == 0 {
@@ -39,6 +59,26 @@ impl Stack {
         }
     }
 
+    /// Remove the value nearest to the top which page ends at or below
+    /// given address. Top value takes it's place.
+    pub fn pop_below(&mut self, limit: u64) -> Option<Page2m> {
+        unsafe {
+            for i in 0..self.count as isize {
+                let ptr = self.top.offset(-i);
+                if (*ptr).addr + 0x200000 > limit {
+                    continue;
+                }
+
+                let val = *ptr;
+                *ptr = *self.top;
+                self.top = self.top.offset(-1);
+                self.count -= 1;
+                return Some(val);
+            }
+        }
+        None
+    }
+
     /// Add new value onto the stack.
     pub fn push(&mut self, val: Page2m) {
         unsafe {
@@ -62,11 +102,13 @@ impl Page2m {
 
 impl Range {
 
-    /// Create new range.
+    /// Create new range. Zone of the range is the zone of it's
+    /// bottom address.
     pub fn new(top: u64, bottom: u64) -> Self {
         Range {
-            top : top,
-            bot : bottom,
+            top     : top,
+            bot     : bottom,
+            zone    : Zone::of(bottom),
         }
     }
 
@@ -87,7 +129,7 @@ impl Range {
     /// Whether this page is within the range.
     pub fn contains(&self, page: Page2m) -> bool {
         let addr = page.addr();
-        self.bot >= addr && self.top <= addr
+        addr >= self.bot && addr < self.top
     }
 
     /// Top value of the range.
@@ -99,4 +141,9 @@ impl Range {
     pub fn bottom(&self) -> u64 {
         self.bot
     }
+
+    /// Zone of physical memory this range belongs to.
+    pub fn zone(&self) -> Zone {
+        self.zone
+    }
 }
@@ -19,6 +19,16 @@ impl Page4k {
         (diff / 4096) as u16
     }
 
+    /// Address of the page.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// 2MiB page which contains this page.
+    pub fn base(&self) -> Page2m {
+        self.base
+    }
+
     pub fn new_by_index(base: Page2m, index: u16) -> Self {
         Page4k {
             base    : base.clone(),
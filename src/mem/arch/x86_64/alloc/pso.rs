@@ -1,5 +1,7 @@
 use super::Page2m;
 use super::Range2m;
+use super::Heap4kEntry;
+use super::Zone;
 
 /// Page Status. Holds the state of individual 2MiB or 4KiB pages.
 /// Stores whether it is allocated or free.
@@ -15,6 +17,9 @@ pub struct PageStatus {
 pub struct PsArray {
     range   : Range2m,
     arr     : *mut PageStatus,
+
+    /// Heap entries of split pages. Null for pages that are not split.
+    split   : *mut *mut Heap4kEntry,
 }
 
 /// PSA array. Contains all Page Status arrays of the system.
@@ -102,6 +107,55 @@ impl ::core::ops::IndexMut<Page2m> for PsArray {
 
 impl PsArray {
 
+    /// Create new array for given range. All pages are marked as free
+    /// and not split.
+    ///
+    /// # Safety
+    /// Given arrays must have space for an entry of each page in the range.
+    pub unsafe fn new(range: Range2m, arr: *mut PageStatus,
+            split: *mut *mut Heap4kEntry) -> Self {
+        use core::ptr::null_mut;
+
+        for i in 0..range.length() {
+            *arr.offset(i as _) = Default::default();
+            *split.offset(i as _) = null_mut();
+        }
+
+        PsArray {
+            range   : range,
+            arr     : arr,
+            split   : split,
+        }
+    }
+
+    /// Heap entry with 4KiB page statuses of given split page. Null if
+    /// page is not split.
+    ///
+    /// # Safety
+    /// Page must be within the range.
+    pub unsafe fn heap_entry_for(&self, page: Page2m) -> *mut Heap4kEntry {
+        *self.split.offset(self.page_to_index(page) as _)
+    }
+
+    /// Set heap entry of given page. Null marks page as not split.
+    ///
+    /// # Safety
+    /// Page must be within the range.
+    pub unsafe fn set_heap_entry_for(&mut self, page: Page2m,
+            entry: *mut Heap4kEntry) {
+        *self.split.offset(self.page_to_index(page) as _) = entry;
+    }
+
+    /// Range of pages that this array stores status for.
+    pub fn range(&self) -> &Range2m {
+        &self.range
+    }
+
+    /// Zone of physical memory pages of this array belong to.
+    pub fn zone(&self) -> Zone {
+        self.range.zone()
+    }
+
     /// Get page that page status at given position is saving status for.
     pub fn page_at_index(&self, index: u64) -> Page2m {
         let addr = self.range.bottom() + index * 0x200000;
@@ -113,6 +167,23 @@ impl PsArray {
         self.range.contains(page)
     }
 
+    /// Count of pages of the range that are used and count of pages that
+    /// are split into 4KiB pages.
+    pub fn usage(&self) -> (u64, u64) {
+        let mut used = 0;
+        let mut split = 0;
+        for i in 0..self.range.length() {
+            unsafe {
+                if !(*self.split.offset(i as _)).is_null() {
+                    split += 1;
+                } else if (*self.arr.offset(i as _)).is_used() {
+                    used += 1;
+                }
+            }
+        }
+        (used, split)
+    }
+
     pub unsafe fn page_status_for(&self, page: Page2m) -> &PageStatus {
         &*self.arr.offset(self.page_to_index(page) as _)
     }
@@ -161,6 +232,47 @@ impl ::core::ops::IndexMut<Page2m> for PsaArray {
 
 impl PsaArray {
 
+    /// Create new PSA array from given array of Page Status arrays.
+    ///
+    /// # Safety
+    /// Given array must contain `length` valid Page Status arrays.
+    pub unsafe fn new(arr: *mut PsArray, length: u32) -> Self {
+        PsaArray {
+            length  : length,
+            arr     : arr,
+        }
+    }
+
+    /// Count of Page Status arrays.
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    /// Find array that contains this page, if any.
+    pub fn array_with_page(&self, page: Page2m) -> Option<&PsArray> {
+        for i in 0..self.length {
+            let i = i as u64;
+            if self[i].contains(page) {
+                return Some(&self[i]);
+            }
+        }
+
+        None
+    }
+
+    /// Find array that contains this page, if any.
+    pub fn array_with_page_mut(&mut self, page: Page2m)
+            -> Option<&mut PsArray> {
+        for i in 0..self.length {
+            let i = i as u64;
+            if self[i].contains(page) {
+                return Some(&mut self[i]);
+            }
+        }
+
+        None
+    }
+
     /// Find array that contains this page.
     ///
     /// # Safety
@@ -0,0 +1,116 @@
+/// End of ISA DMA zone. Legacy ISA DMA controller is able to address only
+/// first 16MiB of physical memory.
+pub const DMA_END: u64 = 0x1000000;
+
+/// End of DMA32 zone. Devices with 32-bit address bus can access only
+/// first 4GiB of physical memory.
+pub const DMA32_END: u64 = 0x100000000;
+
+/// Count of physical memory zones.
+pub const ZONE_COUNT: usize = 3;
+
+/// Physical memory zone. Each zone is a range of physical addresses that
+/// is accessible by some class of devices. Allocators keep separate pools
+/// of free frames for each zone.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Zone {
+
+    /// Memory below 16MiB that is accessible by ISA DMA devices
+    /// like floppy disk controller.
+    Dma,
+
+    /// Memory below 4GiB that is accessible by 32-bit PCI devices.
+    Dma32,
+
+    /// Memory that is not restricted in any way.
+    Normal,
+}
+
+/// Policy of choosing other zone when requested zone is exhausted.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Fallback {
+
+    /// Allocate only in requested zone.
+    Strict,
+
+    /// Try lower zones down to DMA32. ISA DMA memory is scarce and is kept
+    /// for devices that cannot work with other memory.
+    KeepDma,
+
+    /// Try all lower zones down to ISA DMA zone.
+    Lower,
+}
+
+impl Zone {
+
+    /// Zone of memory at given physical address.
+    pub fn of(addr: u64) -> Self {
+        if addr < DMA_END {
+            Zone::Dma
+        } else if addr < DMA32_END {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+
+    /// Zone with given index. Index 0 is the lowest zone.
+    ///
+    /// # Panics
+    /// Index must be less than ZONE_COUNT.
+    pub fn from_index(index: usize) -> Self {
+        match index {
+            0 => Zone::Dma,
+            1 => Zone::Dma32,
+            2 => Zone::Normal,
+            _ => panic!("Invalid zone index"),
+        }
+    }
+
+    /// Index of the zone. Index 0 is the lowest zone.
+    pub fn index(&self) -> usize {
+        match *self {
+            Zone::Dma       => 0,
+            Zone::Dma32     => 1,
+            Zone::Normal    => 2,
+        }
+    }
+
+    /// Lowest address of the zone.
+    pub fn bottom(&self) -> u64 {
+        match *self {
+            Zone::Dma       => 0,
+            Zone::Dma32     => DMA_END,
+            Zone::Normal    => DMA32_END,
+        }
+    }
+
+    /// Address right after the end of the zone.
+    pub fn top(&self) -> u64 {
+        match *self {
+            Zone::Dma       => DMA_END,
+            Zone::Dma32     => DMA32_END,
+            Zone::Normal    => !0,
+        }
+    }
+
+    /// Zone to try when this one is exhausted. None if policy does not
+    /// allow to go any lower.
+    pub fn fallback(&self, policy: Fallback) -> Option<Zone> {
+        match (*self, policy) {
+            (_, Fallback::Strict)               => None,
+            (Zone::Normal, _)                   => Some(Zone::Dma32),
+            (Zone::Dma32, Fallback::Lower)      => Some(Zone::Dma),
+            _                                   => None,
+        }
+    }
+
+    /// Name of the zone to print in logs.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Zone::Dma       => "DMA",
+            Zone::Dma32     => "DMA32",
+            Zone::Normal    => "Normal",
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use mem::VirtAddr;
+
+/// Maximal amount of areas that one address space can have.
+pub const AREA_LIST_CAPACITY: usize = 32;
+
+/// Function that fills the page of backed area with content. Receives
+/// identifier of the backing object, offset of the page from the start of
+/// the area and pointer to the page memory. Returns false if content
+/// cannot be provided.
+pub type FillFn = fn(id: u64, offset: u64, page: *mut u8) -> bool;
+
+/// Source of the content of the pages of backed area. It can be a file
+/// or a channel of some CCS service.
+#[derive(Clone, Copy)]
+pub struct Backing {
+
+    /// Function that provides content of the pages.
+    pub fill    : FillFn,
+
+    /// Identifier of the backing object that is passed to the function.
+    pub id      : u64,
+}
+
+/// The way pages of the area get populated on access.
+#[derive(Clone, Copy)]
+pub enum AreaKind {
+
+    /// Page is mapped to fresh zeroed frame on first access.
+    Anonymous,
+
+    /// Stack that grows down. On access all pages between the faulting
+    /// page and current bottom of the stack are mapped to zeroed frames.
+    /// Field holds lowest address of the mapped part of the stack.
+    Stack(VirtAddr),
+
+    /// Page is mapped to the frame with content provided by backing object.
+    Backed(Backing),
+}
+
+/// Range of virtual memory that is reserved in address space. Pages of
+/// the area are mapped lazily when they are accessed for the first time.
+#[derive(Clone, Copy)]
+pub struct Area {
+
+    /// First byte of the area.
+    start   : VirtAddr,
+
+    /// Byte after the last byte of the area.
+    end     : VirtAddr,
+
+    /// Flags of the pages that get mapped in the area.
+    flags   : u64,
+
+    /// How pages get populated.
+    kind    : AreaKind,
+}
+
+/// List of areas of single address space. Areas never overlap.
+pub struct AreaList {
+
+    /// Array that stores areas. Only first `length` entries are valid.
+    arr     : [Area; AREA_LIST_CAPACITY],
+
+    /// Count of valid entries in the array.
+    length  : usize,
+}
+
+/// Error of inserting new area into the list.
+pub enum AreaListError {
+
+    /// List has no more space for new area.
+    Full,
+
+    /// New area overlaps some area of the list.
+    Overlaps,
+
+    /// Area bounds are not aligned to 4KiB page.
+    Unaligned,
+}
+
+const EMPTY_AREA: Area = Area {
+    start   : VirtAddr::new_unchecked(0),
+    end     : VirtAddr::new_unchecked(0),
+    flags   : 0,
+    kind    : AreaKind::Anonymous,
+};
+
+impl Area {
+
+    /// Create new area. Start is inclusive and end is exclusive. Stack
+    /// areas are created with no mapped pages.
+    pub const fn new(start: VirtAddr, end: VirtAddr, flags: u64,
+            kind: AreaKind) -> Self {
+        Area {
+            start   : start,
+            end     : end,
+            flags   : flags,
+            kind    : kind,
+        }
+    }
+
+    /// Create new stack area with no mapped pages.
+    pub const fn new_stack(start: VirtAddr, end: VirtAddr, flags: u64)
+            -> Self {
+        Area::new(start, end, flags, AreaKind::Stack(end))
+    }
+
+    /// First byte of the area.
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Byte after the last byte of the area.
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    /// Flags of the pages of the area.
+    pub fn flags(&self) -> u64 {
+        self.flags
+    }
+
+    /// How pages of the area get populated.
+    pub fn kind(&self) -> AreaKind {
+        self.kind
+    }
+
+    /// Change the lowest mapped address of the stack area. Does nothing
+    /// for other kinds of areas.
+    pub fn set_stack_bottom(&mut self, bottom: VirtAddr) {
+        if let AreaKind::Stack(_) = self.kind {
+            self.kind = AreaKind::Stack(bottom);
+        }
+    }
+
+    /// Whether given address is in this area.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Whether this area has at least one common byte with given one.
+    pub fn overlaps(&self, other: &Area) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+impl AreaList {
+
+    /// Create empty area list.
+    pub const fn new() -> Self {
+        AreaList {
+            arr     : [EMPTY_AREA; AREA_LIST_CAPACITY],
+            length  : 0,
+        }
+    }
+
+    /// Count of areas in the list.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Get area by given index, if any.
+    pub fn get(&self, index: usize) -> Option<&Area> {
+        if index < self.length {
+            Some(&self.arr[index])
+        } else {
+            None
+        }
+    }
+
+    /// Area that contains given address, if any.
+    pub fn find(&self, addr: VirtAddr) -> Option<&Area> {
+        for i in 0..self.length {
+            if self.arr[i].contains(addr) {
+                return Some(&self.arr[i]);
+            }
+        }
+        None
+    }
+
+    /// Area that contains given address, if any.
+    pub fn find_mut(&mut self, addr: VirtAddr) -> Option<&mut Area> {
+        for i in 0..self.length {
+            if self.arr[i].contains(addr) {
+                return Some(&mut self.arr[i]);
+            }
+        }
+        None
+    }
+
+    /// Add new area to the list.
+    ///
+    /// # Errors
+    /// Full error occurs when list has no more space. Overlaps error occurs
+    /// when area has common memory with some area of the list. Unaligned
+    /// error occurs when area bounds are not page aligned.
+    pub fn insert(&mut self, area: Area) -> Result<(), AreaListError> {
+        if !area.start.is_aligned(0x1000) || !area.end.is_aligned(0x1000) {
+            return Err(AreaListError::Unaligned);
+        }
+
+        for i in 0..self.length {
+            if self.arr[i].overlaps(&area) {
+                return Err(AreaListError::Overlaps);
+            }
+        }
+
+        if self.length == AREA_LIST_CAPACITY {
+            return Err(AreaListError::Full);
+        }
+
+        self.arr[self.length] = area;
+        self.length += 1;
+        Ok(())
+    }
+
+    /// Remove area that starts at given address. Returns removed area.
+    pub fn remove(&mut self, start: VirtAddr) -> Option<Area> {
+        for i in 0..self.length {
+            if self.arr[i].start == start {
+                let area = self.arr[i];
+                self.length -= 1;
+                self.arr[i] = self.arr[self.length];
+                return Some(area);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,324 @@
+use super::alloc::FrameAlloc;
+use super::page_alloc_mut;
+use super::space::*;
+use super::area::{Area, AreaKind};
+use super::kstack;
+use ints::InterruptFrame;
+use mem::VirtAddr;
+
+/// Fault was caused by protection violation. When not set, fault was
+/// caused by not present page.
+pub const FAULT_PRESENT: u64 = 1 << 0;
+
+/// Fault was caused by write access.
+pub const FAULT_WRITE: u64 = 1 << 1;
+
+/// Fault occurred in user mode.
+pub const FAULT_USER: u64 = 1 << 2;
+
+/// Reserved bit was set in some paging entry.
+pub const FAULT_RESERVED: u64 = 1 << 3;
+
+/// Fault was caused by instruction fetch.
+pub const FAULT_FETCH: u64 = 1 << 4;
+
+/// Information about page fault.
+#[derive(Clone, Copy)]
+pub struct PageFault {
+
+    /// Address that was accessed. Read from CR2.
+    pub addr    : VirtAddr,
+
+    /// Error code pushed by processor.
+    pub error   : u64,
+}
+
+/// Reasons why page fault cannot be resolved.
+pub enum FaultReason {
+
+    /// No address space is active.
+    NoSpace,
+
+    /// Address is not in any reserved area.
+    NoArea,
+
+    /// Page is present but access violates it's protection and page is
+    /// not copy-on-write.
+    Protection,
+
+    /// Area does not allow this kind of access.
+    AccessDenied,
+
+    /// Some paging entry has reserved bit set.
+    ReservedBit,
+
+    /// No memory for new frame or paging table.
+    NoMemory,
+
+    /// Backing object failed to provide page content.
+    BackingFailed,
+
+    /// Guard page of the kernel stack of the process with given ID was
+    /// accessed.
+    StackOverflow(u32),
+}
+
+impl PageFault {
+
+    /// Whether the fault was caused by write access.
+    pub fn is_write(&self) -> bool {
+        self.error & FAULT_WRITE != 0
+    }
+
+    /// Whether the fault occurred in user mode.
+    pub fn is_user(&self) -> bool {
+        self.error & FAULT_USER != 0
+    }
+
+    /// Whether the fault was caused by instruction fetch.
+    pub fn is_fetch(&self) -> bool {
+        self.error & FAULT_FETCH != 0
+    }
+
+    /// Whether the page was present.
+    pub fn is_present(&self) -> bool {
+        self.error & FAULT_PRESENT != 0
+    }
+}
+
+impl FaultReason {
+
+    /// Description of the reason to print in fault report.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            FaultReason::NoSpace        => "no active address space",
+            FaultReason::NoArea         => "address is not in reserved area",
+            FaultReason::Protection     => "page protection violation",
+            FaultReason::AccessDenied   => "access is not allowed by area",
+            FaultReason::ReservedBit    => "reserved bit set in paging entry",
+            FaultReason::NoMemory       => "out of memory",
+            FaultReason::BackingFailed  => "backing object failed",
+            FaultReason::StackOverflow(_) => "stack overflow",
+        }
+    }
+}
+
+/// Reason of the fault that could not be resolved because paging table
+/// could not be changed.
+fn map_reason(err: MapError) -> FaultReason {
+    match err {
+        MapError::NoMemory  => FaultReason::NoMemory,
+        _                   => FaultReason::Protection,
+    }
+}
+
+/// Check whether area allows the access that caused the fault.
+fn access_allowed(area: &Area, fault: &PageFault) -> bool {
+    let flags = area.flags();
+    if fault.is_write() && flags & WRITABLE == 0 {
+        return false;
+    }
+    if fault.is_user() && flags & USER == 0 {
+        return false;
+    }
+    if fault.is_fetch() && flags & NO_EXECUTE != 0 {
+        return false;
+    }
+    true
+}
+
+/// Map fresh frame at given page. Frame is filled with zeroes or with the
+/// content from the backing object.
+fn populate(space: &mut AddressSpace, page: VirtAddr, area: &Area)
+        -> Result<(), FaultReason> {
+    let frame = match page_alloc_mut().alloc_frame() {
+        Ok(frame)   => frame,
+        Err(_)      => return Err(FaultReason::NoMemory),
+    };
+
+    let ptr = frame.as_mut_ptr::<u8>();
+    let filled = match area.kind() {
+        AreaKind::Backed(backing) => {
+            (backing.fill)(backing.id, page - area.start(), ptr)
+        },
+        _ => {
+            unsafe { ::core::ptr::write_bytes(ptr, 0, 0x1000); }
+            true
+        },
+    };
+
+    let result = if !filled {
+        Err(FaultReason::BackingFailed)
+    } else {
+        space.map_page(page, frame, PageSize::Size4k, area.flags())
+                .map_err(map_reason)
+    };
+
+    if result.is_err() {
+        unsafe { let _ = page_alloc_mut().release_frame(frame); }
+    }
+    result
+}
+
+/// Resolve write to copy-on-write page. Frame is copied when it is used
+/// by somebody else, otherwise page just becomes writable.
+fn copy_on_write(space: &mut AddressSpace, page: VirtAddr,
+        tr: &Translation) -> Result<(), FaultReason> {
+    let old = tr.phys.align_down(0x1000);
+    let flags = tr.flags & PAGE_FLAGS & !COPY_ON_WRITE | WRITABLE;
+
+    let shared = match page_alloc_mut().status_mut(old) {
+        Some(status)    => status.use_count() > 1,
+        None            => true, // Not known to allocator. Always copy.
+    };
+
+    if !shared {
+        return space.remap_page(page, old, flags).map_err(map_reason);
+    }
+
+    let frame = match page_alloc_mut().alloc_frame() {
+        Ok(frame)   => frame,
+        Err(_)      => return Err(FaultReason::NoMemory),
+    };
+    unsafe {
+        use core::ptr::copy_nonoverlapping;
+        copy_nonoverlapping(old.as_ptr::<u8>(), frame.as_mut_ptr::<u8>(),
+                0x1000);
+    }
+
+    if let Err(e) = space.remap_page(page, frame, flags) {
+        unsafe { let _ = page_alloc_mut().release_frame(frame); }
+        return Err(map_reason(e));
+    }
+
+    // This space does not use old frame any more.
+    if let Some(status) = page_alloc_mut().status_mut(old) {
+        status.dec_user();
+    }
+    Ok(())
+}
+
+/// Resolve page fault in given address space by mapping new page in the
+/// area that contains the faulting address.
+///
+/// # Errors
+/// Reason is returned when fault cannot be resolved.
+pub fn resolve(space: &mut AddressSpace, fault: &PageFault)
+        -> Result<(), FaultReason> {
+    if fault.error & FAULT_RESERVED != 0 {
+        return Err(FaultReason::ReservedBit);
+    }
+
+    let page = fault.addr.align_down(0x1000);
+    if fault.is_present() && fault.is_write() {
+        if let Some(tr) = space.translate(fault.addr) {
+            let user_ok = !fault.is_user() || tr.flags & USER != 0;
+            if tr.flags & COPY_ON_WRITE != 0 && tr.size == PageSize::Size4k
+                    && user_ok {
+                return copy_on_write(space, page, &tr);
+            }
+        }
+    }
+
+    let area = match space.areas().find(fault.addr) {
+        Some(area)  => *area,
+        None        => return Err(FaultReason::NoArea),
+    };
+
+    if !access_allowed(&area, fault) {
+        return Err(FaultReason::AccessDenied);
+    }
+    if fault.is_present() {
+        return Err(FaultReason::Protection);
+    }
+
+    match area.kind() {
+        AreaKind::Stack(bottom) if page < bottom => {
+            // Map all pages between faulting page and current bottom so
+            // the stack stays contiguous.
+            let mut addr = page;
+            while addr < bottom {
+                if space.translate(addr).is_none() {
+                    try!(populate(space, addr, &area));
+                }
+                addr += 0x1000;
+            }
+            space.areas_mut().find_mut(page).unwrap().set_stack_bottom(page);
+            Ok(())
+        },
+        _ => populate(space, page, &area),
+    }
+}
+
+/// Print detailed report of the fault that cannot be resolved.
+fn report(fault: &PageFault, frame: &InterruptFrame, reason: &FaultReason) {
+    use early::logger;
+    use core::fmt::Write;
+
+    let access = if fault.is_fetch() {
+        "instruction fetch"
+    } else if fault.is_write() {
+        "write"
+    } else {
+        "read"
+    };
+    let mode = if fault.is_user() { "user" } else { "kernel" };
+    let page = if fault.is_present() { "present" } else { "not present" };
+
+    if let FaultReason::StackOverflow(pid) = *reason {
+        write!(logger(), "PAGE FAULT at {:016X}: {} in process {}\n",
+            fault.addr, reason.description(), pid).unwrap();
+    } else {
+        write!(logger(), "PAGE FAULT at {:016X}: {}\n",
+            fault.addr, reason.description()).unwrap();
+    }
+    write!(logger(), "  {} access in {} mode, page {}, error code {:X}\n",
+        access, mode, page, fault.error).unwrap();
+    write!(logger(), "  IP {:04X}:{:016X} SP {:04X}:{:016X} FLAGS {:016X}\n",
+        frame.cs, frame.ip, frame.ss, frame.sp, frame.flags).unwrap();
+
+    if let Some(space) = AddressSpace::current() {
+        write!(logger(), "  address space {:016X}\n",
+            space.p4_addr()).unwrap();
+        if let Some(area) = space.areas().find(fault.addr) {
+            write!(logger(), "  area {:016X}:{:016X} flags {:X}\n",
+                area.start(), area.end() - 1, area.flags()).unwrap();
+        }
+    }
+}
+
+/// Handler of page fault exception. Called by interrupt service routine.
+/// Runs on the page fault stack of Interrupt Stack Table so pages of
+/// kernel stacks can be mapped on first touch. Part of the stack that
+/// this handler uses is skipped by nested page faults.
+#[no_mangle]
+pub extern fn page_fault_handler(error: u64, frame: &InterruptFrame) {
+    use super::gdt::{tss_mut, PAGE_FAULT_IST, PAGE_FAULT_NEST_SIZE};
+
+    let addr: u64;
+    unsafe { asm!("mov %cr2, $0" : "=r"(addr) : : : "volatile"); }
+
+    let fault = PageFault {
+        addr    : VirtAddr::new(addr),
+        error   : error,
+    };
+
+    let tss = tss_mut(::ints::cpu_id());
+    let top = tss.ist(PAGE_FAULT_IST);
+    tss.set_ist(PAGE_FAULT_IST, top - PAGE_FAULT_NEST_SIZE);
+
+    let result = if kstack::contains(fault.addr) {
+        kstack::resolve(&fault)
+    } else {
+        match AddressSpace::current() {
+            Some(space) => resolve(space, &fault),
+            None        => Err(FaultReason::NoSpace),
+        }
+    };
+
+    if let Err(reason) = result {
+        report(&fault, frame, &reason);
+        ::halt_forever();
+    }
+    tss.set_ist(PAGE_FAULT_IST, top);
+}
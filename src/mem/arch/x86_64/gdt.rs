@@ -1 +1,241 @@
-// TODO
+use super::map::{GDT, GDT_END};
+use super::kstack::{self, KERNEL_OWNER, KERNEL_STACK_SIZE};
+use super::stats::Subsystem;
+use mem::VirtAddr;
+
+/// Maximal count of processors which get own TSS.
+pub const MAX_CPU_COUNT: usize = 16;
+
+/// Selector of the kernel code segment.
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+
+/// Selector of the kernel data segment.
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+
+/// Selector of the user data segment. User segments go in the order that
+/// SYSRET instruction expects.
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+
+/// Selector of the user code segment.
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+
+/// Index of the first TSS descriptor. Each TSS descriptor takes two
+/// entries.
+const TSS_INDEX: usize = 5;
+
+/// Count of entries of the GDT.
+const GDT_ENTRIES: usize = TSS_INDEX + MAX_CPU_COUNT * 2;
+
+/// Interrupt Stack Table entry used by double fault handler.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+/// Interrupt Stack Table entry used by non-maskable interrupt handler.
+pub const NMI_IST: u8 = 2;
+
+/// Interrupt Stack Table entry used by machine check handler.
+pub const MACHINE_CHECK_IST: u8 = 3;
+
+/// Interrupt Stack Table entry used by page fault handler. Pages of kernel
+/// stacks are mapped on first touch and fault on such page cannot store
+/// interrupt frame on the faulting stack.
+pub const PAGE_FAULT_IST: u8 = 4;
+
+/// Part of the page fault stack taken by each running page fault handler.
+/// Handler moves the stack of the IST entry down by this size while it
+/// runs so nested fault does not overwrite the frame of the outer one.
+/// Third nested fault hits the guard of the stack and becomes double
+/// fault.
+pub const PAGE_FAULT_NEST_SIZE: u64 = KERNEL_STACK_SIZE / 2;
+
+/// Descriptors of the code and data segments. Base and limit are ignored
+/// in long mode.
+const KERNEL_CODE: u64 = 0x00AF_9A00_0000_FFFF;
+const KERNEL_DATA: u64 = 0x00CF_9200_0000_FFFF;
+const USER_DATA: u64 = 0x00CF_F200_0000_FFFF;
+const USER_CODE: u64 = 0x00AF_FA00_0000_FFFF;
+
+/// Type and attributes of the present available 64-bit TSS.
+const TSS_TYPE: u64 = 0x89;
+
+/// Task State Segment. In long mode it stores only stack pointers that
+/// are loaded on privilege change and interrupt.
+#[repr(C, packed)]
+pub struct Tss {
+    reserved0   : u32,
+
+    /// Stack pointers loaded on switch to privilege level of the index.
+    rsp         : [u64; 3],
+
+    reserved1   : u64,
+
+    /// Interrupt Stack Table. Entry 1 is at index 0.
+    ist         : [u64; 7],
+
+    reserved2   : u64,
+    reserved3   : u16,
+
+    /// Offset of I/O permission bitmap. Points behind TSS limit so all
+    /// ports are denied for user mode.
+    iomap_base  : u16,
+}
+
+/// Value that is loaded by LGDT instruction.
+#[repr(C, packed)]
+struct GdtPointer {
+    limit       : u16,
+    base        : u64,
+}
+
+/// TSS of each processor.
+static mut TSS: [Tss; MAX_CPU_COUNT] = [
+    Tss::new(), Tss::new(), Tss::new(), Tss::new(),
+    Tss::new(), Tss::new(), Tss::new(), Tss::new(),
+    Tss::new(), Tss::new(), Tss::new(), Tss::new(),
+    Tss::new(), Tss::new(), Tss::new(), Tss::new(),
+];
+
+impl Tss {
+
+    const fn new() -> Self {
+        Tss {
+            reserved0   : 0,
+            rsp         : [0; 3],
+            reserved1   : 0,
+            ist         : [0; 7],
+            reserved2   : 0,
+            reserved3   : 0,
+            iomap_base  : 0x68,
+        }
+    }
+
+    /// Set the stack of given Interrupt Stack Table entry. Entries are
+    /// counted from one as in IDT gates.
+    pub fn set_ist(&mut self, index: u8, top: VirtAddr) {
+        assert!(index >= 1 && index <= 7);
+        self.ist[index as usize - 1] = top.as_u64();
+    }
+
+    /// Stack of given Interrupt Stack Table entry.
+    pub fn ist(&self, index: u8) -> VirtAddr {
+        assert!(index >= 1 && index <= 7);
+        VirtAddr::new(self.ist[index as usize - 1])
+    }
+
+    /// Set the stack that is loaded when processor switches from user mode
+    /// to the kernel.
+    pub fn set_kernel_stack(&mut self, top: VirtAddr) {
+        self.rsp[0] = top.as_u64();
+    }
+}
+
+/// Selector of the TSS of given processor.
+pub fn tss_selector(cpu: usize) -> u16 {
+    ((TSS_INDEX + cpu * 2) * 8) as u16
+}
+
+/// TSS of given processor.
+pub fn tss_mut(cpu: usize) -> &'static mut Tss {
+    unsafe { &mut TSS[cpu] }
+}
+
+/// Entries of the GDT in the direct map.
+fn entries() -> *mut u64 {
+    GDT.as_mut_ptr()
+}
+
+/// Write GDT with all segment descriptors and TSS descriptors of all
+/// processors.
+unsafe fn build() {
+    use core::mem::size_of;
+
+    assert!(GDT_ENTRIES as u64 * 8 <= GDT_END.as_u64() - GDT.as_u64());
+
+    let gdt = entries();
+    *gdt.offset(0) = 0;
+    *gdt.offset(1) = KERNEL_CODE;
+    *gdt.offset(2) = KERNEL_DATA;
+    *gdt.offset(3) = USER_DATA;
+    *gdt.offset(4) = USER_CODE;
+
+    let limit = size_of::<Tss>() as u64 - 1;
+    for cpu in 0..MAX_CPU_COUNT {
+        let base = &TSS[cpu] as *const Tss as u64;
+        let low = limit & 0xFFFF
+                | (base & 0xFF_FFFF) << 16
+                | TSS_TYPE << 40
+                | (limit >> 16 & 0xF) << 48
+                | (base >> 24 & 0xFF) << 56;
+        let index = (TSS_INDEX + cpu * 2) as isize;
+        *gdt.offset(index) = low;
+        *gdt.offset(index + 1) = base >> 32;
+    }
+}
+
+/// Load GDT register and reload all segment registers with kernel
+/// segments.
+unsafe fn load() {
+    let pointer = GdtPointer {
+        limit   : (GDT_ENTRIES * 8 - 1) as u16,
+        base    : entries() as u64,
+    };
+    asm!("lgdt ($0)" : : "r"(&pointer) : "memory" : "volatile");
+
+    // Code segment is reloaded by far return.
+    asm!("pushq $0
+          leaq 1f(%rip), %rax
+          pushq %rax
+          lretq
+          1:
+          mov $1, %ds
+          mov $1, %es
+          mov $1, %fs
+          mov $1, %gs
+          mov $1, %ss"
+          : : "r"(KERNEL_CODE_SELECTOR as u64), "r"(KERNEL_DATA_SELECTOR)
+          : "rax", "memory" : "volatile");
+}
+
+/// Allocate the stack for Interrupt Stack Table. Stack is placed in the
+/// kernel stack region so overflow hits it's guard page. Frames need not
+/// be contiguous and are all mapped at once. Interrupt stacks are never
+/// released. Returns the top of the stack.
+fn alloc_ist_stack() -> VirtAddr {
+    match kstack::alloc_mapped(KERNEL_OWNER, Subsystem::InterruptStacks) {
+        Some(stack) => {
+            let top = stack.top();
+            ::core::mem::forget(stack);
+            top
+        },
+        None        => panic!("No memory for interrupt stacks"),
+    }
+}
+
+/// Prepare TSS of given processor and load GDT and task register on
+/// current processor. Double fault, NMI and machine check get their own
+/// stacks so they are handled even when kernel stack is broken. Page fault
+/// gets own stack as it maps pages of kernel stacks.
+///
+/// Page allocator and kernel paging must be initialized. `setup` must be
+/// called before this function.
+pub fn setup_cpu(cpu: usize) {
+    assert!(cpu < MAX_CPU_COUNT);
+
+    let tss = tss_mut(cpu);
+    tss.set_ist(DOUBLE_FAULT_IST, alloc_ist_stack());
+    tss.set_ist(NMI_IST, alloc_ist_stack());
+    tss.set_ist(MACHINE_CHECK_IST, alloc_ist_stack());
+    tss.set_ist(PAGE_FAULT_IST, alloc_ist_stack());
+
+    unsafe {
+        load();
+        asm!("ltr $0" : : "r"(tss_selector(cpu)) : : "volatile");
+    }
+}
+
+/// Create kernel GDT and load it on the bootstrap processor.
+///
+/// Page allocator and kernel paging must be initialized.
+pub fn setup() {
+    unsafe { build(); }
+    setup_cpu(0);
+}
@@ -0,0 +1,455 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, Ordering, spin_loop_hint};
+use super::alloc::FrameAlloc;
+use super::alloc::frame::{FRAME_SIZE, size_order};
+use super::page_alloc_mut;
+use super::slab::SlabCache;
+use super::map::HEAP_BASE;
+use super::paging::kernel_space_mut;
+use super::space::{AddressSpace, PageSize, WRITABLE, NO_EXECUTE, GLOBAL};
+use mem::{PhysAddr, VirtAddr};
+
+/// Sizes of objects that are stored in slabs. Bigger objects take whole
+/// pages.
+const SIZE_CLASSES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+/// Count of size classes.
+const CLASS_COUNT: usize = 7;
+
+/// Biggest object that is stored in slabs.
+const MAX_SLAB_OBJECT: usize = 1024;
+
+/// Size of the part of the large object window that holds objects of
+/// single order. Each part is split into slots of the object size.
+const ORDER_WINDOW: u64 = 0x4000_0000;
+
+/// Biggest order of large object. Object of this order takes the whole
+/// part of the window.
+const MAX_LARGE_ORDER: u8 = 18;
+
+/// Count of words of the bitmaps of used slots of all orders. Orders from
+/// 1 to 12 need 2048 words and less, each bigger order needs one word.
+const LARGE_WORDS: usize = 4101;
+
+/// State of the kernel heap.
+struct HeapState {
+
+    /// Slabs of each size class. Objects are aligned to the size of
+    /// their class.
+    caches  : [SlabCache; CLASS_COUNT],
+
+    /// Count of bytes of allocated objects including internal
+    /// fragmentation of size classes.
+    used    : usize,
+
+    /// Count of bytes of frames of large objects.
+    frames  : usize,
+}
+
+/// Kernel heap. Small objects are stored in slabs of fixed size classes.
+/// Large objects take single frame or frames that are mapped at
+/// contiguous virtual addresses of the large object window.
+pub struct KernelHeap;
+
+#[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap;
+
+static mut HEAP_STATE: HeapState = HeapState {
+    caches  : [
+        SlabCache::new(), SlabCache::new(), SlabCache::new(),
+        SlabCache::new(), SlabCache::new(), SlabCache::new(),
+        SlabCache::new(),
+    ],
+    used    : 0,
+    frames  : 0,
+};
+
+/// Taken by the processor that changes heap state. Interrupt handlers
+/// must not use the heap as interrupted code may hold the lock.
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Used slots of the large object window. Bitmaps of all orders follow
+/// each other starting from order 1.
+static mut LARGE_SLOTS: [u64; LARGE_WORDS] = [0; LARGE_WORDS];
+
+/// Pointer to the frame at given physical address.
+pub fn frame_ptr(phys: PhysAddr) -> *mut u8 {
+    phys.as_mut_ptr()
+}
+
+/// Physical address of the frame by given pointer.
+pub fn frame_phys(ptr: *mut u8) -> PhysAddr {
+    VirtAddr::from_ptr(ptr).direct_map_phys().unwrap()
+}
+
+/// Index of the smallest size class that fits the layout. None if object
+/// is too big for slabs.
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = if layout.size() > layout.align() {
+        layout.size()
+    } else {
+        layout.align()
+    };
+
+    if size > MAX_SLAB_OBJECT {
+        return None;
+    }
+    for i in 0..CLASS_COUNT {
+        if SIZE_CLASSES[i] >= size {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Order of the block of frames for object that does not fit slabs.
+fn block_order(layout: &Layout) -> u8 {
+    let size = if layout.size() > layout.align() {
+        layout.size()
+    } else {
+        layout.align()
+    };
+    size_order(size as u64)
+}
+
+impl HeapState {
+
+    /// Allocate object of given size class.
+    unsafe fn alloc_small(&mut self, class: usize) -> *mut u8 {
+        let size = SIZE_CLASSES[class];
+        let obj = self.caches[class].alloc(size, size);
+        if !obj.is_null() {
+            self.used += size;
+        }
+        obj
+    }
+
+    /// Return object of given size class back to it's slab.
+    unsafe fn dealloc_small(&mut self, ptr: *mut u8, class: usize) {
+        self.caches[class].dealloc(ptr);
+        self.used -= SIZE_CLASSES[class];
+    }
+
+    /// Allocate memory for big object. Object of single frame is accessed
+    /// through the direct map. Bigger objects are mapped in the large
+    /// object window so their frames need not be contiguous.
+    unsafe fn alloc_large(&mut self, order: u8) -> *mut u8 {
+        let ptr = if order == 0 {
+            match page_alloc_mut().alloc_frame() {
+                Ok(phys)    => frame_ptr(phys),
+                Err(_)      => null_mut(),
+            }
+        } else {
+            map_large(order)
+        };
+
+        if !ptr.is_null() {
+            let size = (FRAME_SIZE << order) as usize;
+            self.used += size;
+            self.frames += size;
+        }
+        ptr
+    }
+
+    /// Release memory of big object.
+    unsafe fn dealloc_large(&mut self, ptr: *mut u8, order: u8) {
+        let size = (FRAME_SIZE << order) as usize;
+        self.used -= size;
+        self.frames -= size;
+        if order == 0 {
+            let _ = page_alloc_mut().release_frame(frame_phys(ptr));
+        } else {
+            unmap_large(ptr, order);
+        }
+    }
+}
+
+/// Count of slots of given order in it's part of the large object window.
+fn slot_count(order: u8) -> usize {
+    (ORDER_WINDOW >> (12 + order as u64)) as usize
+}
+
+/// Index of the first word and count of words of the bitmap of slots of
+/// given order.
+fn bitmap_range(order: u8) -> (usize, usize) {
+    let mut first = 0;
+    for o in 1..order {
+        first += (slot_count(o) + 63) / 64;
+    }
+    (first, (slot_count(order) + 63) / 64)
+}
+
+/// Virtual address of the slot of given order.
+fn slot_addr(order: u8, slot: usize) -> VirtAddr {
+    let part = HEAP_BASE + (order as u64 - 1) * ORDER_WINDOW;
+    VirtAddr::new(part + slot as u64 * (FRAME_SIZE << order))
+}
+
+/// Find free slot of given order and mark it used.
+unsafe fn reserve_slot(order: u8) -> Option<usize> {
+    let (first, words) = bitmap_range(order);
+    let count = slot_count(order);
+    for word in 0..words {
+        let bits = LARGE_SLOTS[first + word];
+        if bits == !0 {
+            continue;
+        }
+        let slot = word * 64 + (!bits).trailing_zeros() as usize;
+        if slot >= count {
+            return None;
+        }
+        LARGE_SLOTS[first + word] |= 1 << (slot % 64);
+        return Some(slot);
+    }
+    None
+}
+
+/// Mark the slot of given order free.
+unsafe fn free_slot(order: u8, slot: usize) {
+    let (first, _) = bitmap_range(order);
+    LARGE_SLOTS[first + slot / 64] &= !(1 << (slot % 64));
+}
+
+/// Map frames of big object in the large object window. Returns null
+/// when window has no free slot of the order, frames cannot be allocated
+/// or kernel paging is not loaded yet.
+unsafe fn map_large(order: u8) -> *mut u8 {
+    if order > MAX_LARGE_ORDER || AddressSpace::current().is_none() {
+        return null_mut();
+    }
+    let slot = match reserve_slot(order) {
+        Some(slot)  => slot,
+        None        => return null_mut(),
+    };
+
+    let virt = slot_addr(order, slot);
+    let size = FRAME_SIZE << order;
+    let space = kernel_space_mut();
+    let flags = WRITABLE | NO_EXECUTE | GLOBAL;
+    let mut off = 0;
+    while off < size {
+        let mapped = match page_alloc_mut().alloc_frame() {
+            Ok(frame) => {
+                let page = virt + off;
+                let result = space.map_page(page, frame, PageSize::Size4k,
+                        flags);
+                if result.is_err() {
+                    let _ = page_alloc_mut().release_frame(frame);
+                }
+                result.is_ok()
+            },
+            Err(_) => false,
+        };
+
+        if !mapped {
+            let _ = space.unmap_release(virt, off);
+            free_slot(order, slot);
+            return null_mut();
+        }
+        off += FRAME_SIZE;
+    }
+    virt.as_mut_ptr()
+}
+
+/// Unmap big object from the large object window and release it's
+/// frames.
+unsafe fn unmap_large(ptr: *mut u8, order: u8) {
+    let size = FRAME_SIZE << order;
+    let virt = VirtAddr::from_ptr(ptr);
+    if kernel_space_mut().unmap_release(virt, size).is_err() {
+        panic!("Failed to unmap heap object");
+    }
+
+    let part = HEAP_BASE + (order as u64 - 1) * ORDER_WINDOW;
+    free_slot(order, ((virt.as_u64() - part) / size) as usize);
+}
+
+/// Wait until heap lock is free and take it.
+fn lock() {
+    while LOCK.compare_and_swap(false, true, Ordering::Acquire) {
+        spin_loop_hint();
+    }
+}
+
+/// Release heap lock.
+fn unlock() {
+    LOCK.store(false, Ordering::Release);
+}
+
+/// Allocate memory of the heap without debugging checks. Heap lock must
+/// be held.
+pub unsafe fn raw_alloc(layout: Layout) -> *mut u8 {
+    match size_class(&layout) {
+        Some(class) => HEAP_STATE.alloc_small(class),
+        None        => HEAP_STATE.alloc_large(block_order(&layout)),
+    }
+}
+
+/// Release memory of the heap without debugging checks. Heap lock must be
+/// held.
+pub unsafe fn raw_dealloc(ptr: *mut u8, layout: Layout) {
+    match size_class(&layout) {
+        Some(class) => HEAP_STATE.dealloc_small(ptr, class),
+        None        => HEAP_STATE.dealloc_large(ptr, block_order(&layout)),
+    }
+}
+
+#[cfg(not(mem_debug))]
+unsafe impl GlobalAlloc for KernelHeap {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        lock();
+        let ptr = raw_alloc(layout);
+        unlock();
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        lock();
+        raw_dealloc(ptr, layout);
+        unlock();
+    }
+}
+
+/// Heap with poisoning, redzones and allocation site tracking.
+#[cfg(mem_debug)]
+unsafe impl GlobalAlloc for KernelHeap {
+
+    // Not inlined so allocation site is found at fixed depth.
+    #[inline(never)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        lock();
+        let ptr = super::memdebug::alloc(layout);
+        unlock();
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        lock();
+        super::memdebug::dealloc(ptr, layout);
+        unlock();
+    }
+}
+
+impl KernelHeap {
+
+    /// Count of bytes of allocated objects. Includes bytes that are lost
+    /// by rounding object size to it's size class.
+    pub fn used(&self) -> usize {
+        unsafe { HEAP_STATE.used }
+    }
+
+    /// Count of bytes of frames that heap took from frame allocator.
+    pub fn frames(&self) -> usize {
+        lock();
+        let state = unsafe { &HEAP_STATE };
+        let mut slabs = 0;
+        for cache in state.caches.iter() {
+            slabs += cache.slabs();
+        }
+        let frames = state.frames + slabs * FRAME_SIZE as usize;
+        unlock();
+        frames
+    }
+}
+
+/// Allocate, grow and free a vector and a map before the rest of the
+/// kernel relies on the heap. Vector grows from slabs to the large object
+/// window. Panics if contents are wrong or memory is not given back.
+pub fn self_test() {
+    use alloc::vec::Vec;
+    use alloc::collections::BTreeMap;
+
+    let used = kernel_heap().used();
+    {
+        let mut vec = Vec::new();
+        for i in 0..2048u64 {
+            vec.push(i);
+        }
+        for (i, val) in vec.iter().enumerate() {
+            if *val != i as u64 {
+                panic!("Heap vector is corrupted");
+            }
+        }
+
+        let mut map = BTreeMap::new();
+        for i in 0..256u64 {
+            map.insert(i * 7 % 256, i);
+        }
+        for i in 0..128u64 {
+            if map.remove(&(i * 7 % 256)) != Some(i) {
+                panic!("Heap map is corrupted");
+            }
+        }
+        if map.len() != 128 {
+            panic!("Heap map is corrupted");
+        }
+    }
+    if kernel_heap().used() != used {
+        panic!("Heap self test leaked memory");
+    }
+}
+
+/// Kernel heap reference.
+pub fn kernel_heap() -> &'static KernelHeap {
+    &KERNEL_HEAP
+}
+
+/// Called when heap memory cannot be allocated.
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    use early::logger;
+    use core::fmt::Write;
+
+    write!(logger(), "Out of kernel heap memory: {} bytes aligned to {}\n",
+        layout.size(), layout.align()).unwrap();
+    ::halt_forever();
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn class(size: usize, align: usize) -> Option<usize> {
+        size_class(&Layout::from_size_align(size, align).unwrap())
+    }
+
+    #[test]
+    fn selects_smallest_fitting_class() {
+        assert_eq!(class(1, 1), Some(0));
+        assert_eq!(class(16, 8), Some(0));
+        assert_eq!(class(17, 8), Some(1));
+        assert_eq!(class(100, 4), Some(3));
+        assert_eq!(class(1024, 8), Some(CLASS_COUNT - 1));
+        assert_eq!(class(1025, 8), None);
+    }
+
+    #[test]
+    fn alignment_selects_class_too() {
+        assert_eq!(class(8, 64), Some(2));
+        assert_eq!(class(8, 1024), Some(CLASS_COUNT - 1));
+        assert_eq!(class(8, 2048), None);
+        assert_eq!(block_order(&Layout::from_size_align(8, 8192).unwrap()),
+                1);
+    }
+
+    #[test]
+    fn objects_are_aligned_to_their_class() {
+        for i in 0..CLASS_COUNT {
+            let size = SIZE_CLASSES[i];
+            assert!(size.is_power_of_two());
+            assert_eq!(class(size, size), Some(i));
+        }
+        assert_eq!(SIZE_CLASSES[CLASS_COUNT - 1], MAX_SLAB_OBJECT);
+    }
+
+    #[test]
+    fn large_slot_bitmaps_fit() {
+        let (first, words) = bitmap_range(MAX_LARGE_ORDER);
+        assert_eq!(first + words, LARGE_WORDS);
+        assert_eq!(slot_count(MAX_LARGE_ORDER), 1);
+        assert_eq!(bitmap_range(1), (0, 2048));
+    }
+}
@@ -0,0 +1,333 @@
+use super::map::KERNEL_STACKS_BASE;
+use super::alloc::FrameAlloc;
+use super::page_alloc_mut;
+use super::paging::kernel_space_mut;
+use super::space::{PageSize, WRITABLE, NO_EXECUTE, GLOBAL};
+use super::fault::{FaultReason, PageFault};
+use super::stats::{self, Subsystem};
+use mem::VirtAddr;
+
+/// Size of the stack of single thread.
+pub const KERNEL_STACK_SIZE: u64 = 0x4000;
+
+/// Size of the unmapped guard under each stack. Stack overflow hits the
+/// guard instead of the stack of other thread.
+pub const GUARD_SIZE: u64 = 0x1000;
+
+/// Size of the virtual memory reserved for each stack with it's guard.
+const SLOT_SIZE: u64 = KERNEL_STACK_SIZE + GUARD_SIZE;
+
+/// Owner ID of the stacks that belong to the kernel itself, like stacks
+/// of Interrupt Stack Table.
+pub const KERNEL_OWNER: u32 = 0;
+
+/// Maximal count of kernel stacks that can exist at once.
+pub const MAX_KERNEL_STACKS: usize = 1024;
+
+/// Count of words of the bitmap of used slots.
+const BITMAP_WORDS: usize = MAX_KERNEL_STACKS / 64;
+
+/// Slots of the kernel stack region.
+struct StackTable {
+
+    /// Bit is set for each slot that has a stack.
+    used        : [u64; BITMAP_WORDS],
+
+    /// Bit is set for each slot which stack was dropped but not released
+    /// yet.
+    dead        : [u64; BITMAP_WORDS],
+
+    /// ID of the process that owns the stack of the slot.
+    owners      : [u32; MAX_KERNEL_STACKS],
+
+    /// Subsystem that frames of the stack of the slot are charged to.
+    subsystems  : [Subsystem; MAX_KERNEL_STACKS],
+
+    /// Index of the processor that dropped the stack of the slot.
+    reapers     : [u8; MAX_KERNEL_STACKS],
+}
+
+/// Kernel stack of single thread. Pages of the stack are mapped on first
+/// touch by page fault handler, which has own stack. Thread may still run
+/// on the stack when it gets dropped so memory of the stack is released
+/// later by `reap`.
+pub struct KernelStack {
+
+    /// Index of the slot of the stack region.
+    index   : usize,
+}
+
+/// Slots of all kernel stacks.
+static mut STACKS: StackTable = StackTable {
+    used        : [0; BITMAP_WORDS],
+    dead        : [0; BITMAP_WORDS],
+    owners      : [0; MAX_KERNEL_STACKS],
+    subsystems  : [Subsystem::KernelStacks; MAX_KERNEL_STACKS],
+    reapers     : [0; MAX_KERNEL_STACKS],
+};
+
+/// Slots of all kernel stacks.
+fn stacks_mut() -> &'static mut StackTable {
+    unsafe { &mut STACKS }
+}
+
+impl StackTable {
+
+    /// Whether the slot has a stack.
+    fn is_used(&self, index: usize) -> bool {
+        self.used[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Mark the slot used or free.
+    fn set_used(&mut self, index: usize, used: bool) {
+        if used {
+            self.used[index / 64] |= 1 << (index % 64);
+        } else {
+            self.used[index / 64] &= !(1 << (index % 64));
+        }
+    }
+
+    /// Whether the stack of the slot was dropped and waits for release.
+    fn is_dead(&self, index: usize) -> bool {
+        self.dead[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Mark the stack of the slot dropped or released.
+    fn set_dead(&mut self, index: usize, dead: bool) {
+        if dead {
+            self.dead[index / 64] |= 1 << (index % 64);
+        } else {
+            self.dead[index / 64] &= !(1 << (index % 64));
+        }
+    }
+
+    /// Find free slot.
+    fn free_slot(&self) -> Option<usize> {
+        for word in 0..BITMAP_WORDS {
+            let bits = self.used[word];
+            if bits != !0 {
+                return Some(word * 64 + (!bits).trailing_zeros() as usize);
+            }
+        }
+        None
+    }
+}
+
+impl KernelStack {
+
+    /// Lowest address of the guard of the stack.
+    fn slot_start(&self) -> VirtAddr {
+        slot_start(self.index)
+    }
+
+    /// Lowest address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.slot_start() + GUARD_SIZE
+    }
+
+    /// Address right after the stack. Initial stack pointer of the thread.
+    pub fn top(&self) -> VirtAddr {
+        self.slot_start() + SLOT_SIZE
+    }
+
+    /// ID of the process that owns the stack.
+    pub fn owner(&self) -> u32 {
+        stacks_mut().owners[self.index]
+    }
+
+    /// Map all pages of the stack that are not mapped yet. Returns false
+    /// when there is no memory.
+    fn map_all(&self) -> bool {
+        let mut page = self.bottom();
+        while page < self.top() {
+            let mapped = kernel_space_mut().translate(page).is_some();
+            if !mapped && map_page(self.index, page).is_err() {
+                return false;
+            }
+            page += 0x1000;
+        }
+        true
+    }
+}
+
+impl Drop for KernelStack {
+
+    /// Mark the stack dead. Current processor may still run on it, like
+    /// when the thread ends itself, so the stack is released by `reap`.
+    fn drop(&mut self) {
+        let stacks = stacks_mut();
+        stacks.set_dead(self.index, true);
+        stacks.reapers[self.index] = ::ints::cpu_id() as u8;
+    }
+}
+
+/// Lowest address of the slot of given index.
+fn slot_start(index: usize) -> VirtAddr {
+    VirtAddr::new(KERNEL_STACKS_BASE + index as u64 * SLOT_SIZE)
+}
+
+/// Map zeroed frame at given page of the stack of the slot and charge it
+/// to the subsystem of the stack.
+fn map_page(index: usize, page: VirtAddr) -> Result<(), FaultReason> {
+    let frame = match page_alloc_mut().alloc_frame() {
+        Ok(frame)   => frame,
+        Err(_)      => return Err(FaultReason::NoMemory),
+    };
+    unsafe { ::core::ptr::write_bytes(frame.as_mut_ptr::<u8>(), 0, 0x1000); }
+
+    let space = kernel_space_mut();
+    let flags = WRITABLE | NO_EXECUTE | GLOBAL;
+    if space.map_page(page, frame, PageSize::Size4k, flags).is_err() {
+        unsafe { let _ = page_alloc_mut().release_frame(frame); }
+        return Err(FaultReason::NoMemory);
+    }
+    stats::charge(stacks_mut().subsystems[index], 1);
+    Ok(())
+}
+
+/// Unmap the stack of the slot and give the slot back. Frames of the
+/// stack are released.
+fn release_slot(index: usize) {
+    let space = kernel_space_mut();
+    let bottom = slot_start(index) + GUARD_SIZE;
+    let mut mapped = 0;
+    let mut page = bottom;
+    while page < bottom + KERNEL_STACK_SIZE {
+        if space.translate(page).is_some() {
+            mapped += 1;
+        }
+        page += 0x1000;
+    }
+
+    let stacks = stacks_mut();
+    stats::uncharge(stacks.subsystems[index], mapped);
+    if space.unmap_release(bottom, KERNEL_STACK_SIZE).is_err() {
+        panic!("Failed to unmap kernel stack");
+    }
+    stacks.set_dead(index, false);
+    stacks.set_used(index, false);
+}
+
+/// Release stacks that were dropped on current processor except the one
+/// it runs on. Scheduler calls this after it switched away from the
+/// thread that ended. Stack of a thread must not be dropped while other
+/// processor runs on it.
+pub fn reap() {
+    let rsp: u64;
+    unsafe { asm!("mov %rsp, $0" : "=r"(rsp) : : : "volatile"); }
+    let cpu = ::ints::cpu_id() as u8;
+
+    let stacks = stacks_mut();
+    for index in 0..MAX_KERNEL_STACKS {
+        if !stacks.is_dead(index) || stacks.reapers[index] != cpu {
+            continue;
+        }
+        let start = slot_start(index).as_u64();
+        if rsp >= start && rsp <= start + SLOT_SIZE {
+            continue;
+        }
+        release_slot(index);
+    }
+}
+
+/// Allocate kernel stack for the thread of given process. Stacks of the
+/// kernel itself use KERNEL_OWNER. Frames of the stack are charged to
+/// given subsystem. Stack is mapped in the upper half of the kernel space
+/// which is shared by all address spaces when it's pages are touched.
+/// Returns None when all slots are taken.
+pub fn alloc(owner: u32, subsystem: Subsystem) -> Option<KernelStack> {
+    reap();
+
+    let stacks = stacks_mut();
+    let index = match stacks.free_slot() {
+        Some(index) => index,
+        None        => return None,
+    };
+    stacks.set_used(index, true);
+    stacks.owners[index] = owner;
+    stacks.subsystems[index] = subsystem;
+
+    Some(KernelStack {
+        index   : index,
+    })
+}
+
+/// Allocate kernel stack with all pages mapped. Used for stacks that
+/// must never fault like the ones of Interrupt Stack Table. Returns None
+/// when all slots are taken or there is no memory.
+pub fn alloc_mapped(owner: u32, subsystem: Subsystem)
+        -> Option<KernelStack> {
+    let stack = match alloc(owner, subsystem) {
+        Some(stack) => stack,
+        None        => return None,
+    };
+    if !stack.map_all() {
+        let index = stack.index;
+        ::core::mem::forget(stack);
+        release_slot(index);
+        return None;
+    }
+    Some(stack)
+}
+
+/// Whether the address is in the kernel stack region.
+pub fn contains(addr: VirtAddr) -> bool {
+    let end = KERNEL_STACKS_BASE + MAX_KERNEL_STACKS as u64 * SLOT_SIZE;
+    addr.as_u64() >= KERNEL_STACKS_BASE && addr.as_u64() < end
+}
+
+/// ID of the owner process of the stack whose guard contains given
+/// address. None when the address is not in the guard of allocated stack.
+pub fn guard_owner(addr: VirtAddr) -> Option<u32> {
+    if !contains(addr) {
+        return None;
+    }
+
+    let offset = addr.as_u64() - KERNEL_STACKS_BASE;
+    let index = (offset / SLOT_SIZE) as usize;
+    let stacks = stacks_mut();
+    if stacks.is_used(index) && offset % SLOT_SIZE < GUARD_SIZE {
+        Some(stacks.owners[index])
+    } else {
+        None
+    }
+}
+
+/// Resolve page fault in the kernel stack region by mapping the page of
+/// the stack that was touched first time.
+///
+/// # Errors
+/// StackOverflow with the ID of owner process is given when fault hits
+/// the guard, NoArea when the slot has no stack and AccessDenied when the
+/// page is present or is accessed by user or by instruction fetch.
+pub fn resolve(fault: &PageFault) -> Result<(), FaultReason> {
+    if let Some(owner) = guard_owner(fault.addr) {
+        return Err(FaultReason::StackOverflow(owner));
+    }
+
+    let offset = fault.addr.as_u64() - KERNEL_STACKS_BASE;
+    let index = (offset / SLOT_SIZE) as usize;
+    let stacks = stacks_mut();
+    if !stacks.is_used(index) {
+        return Err(FaultReason::NoArea);
+    }
+    if fault.is_present() || fault.is_user() || fault.is_fetch() {
+        return Err(FaultReason::AccessDenied);
+    }
+
+    map_page(index, fault.addr.align_down(0x1000))
+}
+
+/// Continue execution on given stack by calling the function that never
+/// returns. Used to leave the boot stack which has no guard. The stack is
+/// never released.
+///
+/// # Safety
+/// Data on the current stack is not accessible after the call.
+pub unsafe fn run_on(stack: KernelStack, f: extern fn() -> !) -> ! {
+    let top = stack.top().as_u64();
+    ::core::mem::forget(stack);
+    asm!("mov $0, %rsp
+          call *$1" : : "r"(top), "r"(f) : "memory" : "volatile");
+    loop {}
+}
@@ -13,12 +13,7 @@
 //! Memory map of the kernel in selected region:
 //! 00000:003FF - free
 //! 00400:004FF - BIOS Data Area.
-//! 00500:00FFF - CCS basic setup temporary memory.
-//! 01000:01FFF - Local APIC registers.
-//! 02000:02FFF - Paging. Page Table Level 1.
-//! 03000:03FFF - Paging. Page Table Level 2.
-//! 04000:04FFF - Paging. Page Table Level 3.
-//! 05000:05FFF - Paging. Page Table Level 4.
+//! 00500:05FFF - free
 //! 06000:06FFF - IDT.
 //! 07000:08FFF - GDT.
 //! 09000:7BFFF - free
@@ -27,41 +22,143 @@
 //! 7E000:7EFFF - Initial paging. Page Table Level 4 OR allocator memory.
 //! 7F000:7FFFF - Stack.
 //!
+//! Kernel paging tables are taken from the page allocator.
+//!
 //! Note that as soon as main paging tables are set,
 //! memory region of 7C000:7EFFF gets free and is used by kernel memory
 //! allocators.
+//!
+//! Addresses above are physical. Kernel accesses them through the direct
+//! map. Virtual memory of the kernel:
+//! FFFF800000000000:FFFFBFFFFFFFFFFF - direct map of physical memory.
+//! FFFFD00000000000:FFFFD00FFFFFFFFF - device memory, Local APIC included.
+//! FFFFFFFF80000000:FFFFFFFFFFFFFFFF - kernel image.
+//! Lower half of virtual memory is left for user processes.
 
-/// Local APIC base registers address. They are moved from their default
-/// location here. Note that the registers are 4 KiB in size.
-pub const APIC_BASE_ADDRESS: u64 = 0x01000;
+use mem::PhysAddr;
 
-/// The memory used to store information about basic kernel CCS objects and
-/// services when kernel starts up.
-pub const CCS_BASIC_SETUP_ADDRESS: usize = 0x00500;
+/// Address of Interrupt Descriptor Table.
+pub const IDT: PhysAddr = PhysAddr::new_unchecked(0x6000);
 
-/// The bound of the memory that can be used by CCS basic setup data.
-pub const CCS_BASIC_SETUP_ADDRESS_END: usize = 0x00FFF;
+/// Address of Global Descriptor Table.
+pub const GDT: PhysAddr = PhysAddr::new_unchecked(0x7000);
 
-/// Address of the level 1 paging table of the kernel.
-pub const PAGING_P1: usize = 0x2000;
+/// End of the memory of Global Descriptor Table.
+pub const GDT_END: PhysAddr = PhysAddr::new_unchecked(0x9000);
 
-/// Address of the level 2 paging table of the kernel.
-pub const PAGING_P2: usize = 0x3000;
+/// Start of kernel memory allocator. Allocator gives virtual addresses
+/// of this memory in the direct map.
+pub const MEMALLOC_START: usize = 0x7C000;
 
-/// Address of the level 3 paging table of the kernel.
-pub const PAGING_P3: usize = 0x4000;
+/// End of kernel memory allocator (excluding byte at this address).
+pub const MEMALLOC_END: usize = 0x7F000;
 
-/// Address of the level 4 paging table of the kernel.
-pub const PAGING_P4: usize = 0x5000;
+/// End of conventional memory. Video memory and ROM are above it and
+/// are not in the direct map.
+pub const CONVENTIONAL_END: u64 = 0xA0000;
 
-/// Address of Interrupt Descriptor Table.
-pub const IDT: usize = 0x6000;
+/// End of the first megabyte of memory. All fixed structures listed
+/// above, BIOS data and memory mapped devices are below this address so
+/// the whole megabyte is never given to page allocator.
+pub const LOW_MEMORY_END: u64 = 0x100000;
 
-/// Address of Global Descriptor Table.
-pub const GDT: usize = 0x7000;
+/// End of physical memory that is mapped in the direct map by the boot
+/// code. Memory above this address is not accessible until kernel paging
+/// tables are set.
+pub const BOOT_MAPPED_END: u64 = 0x40000000;
 
-/// Start of kernel memory allocator.
-pub const MEMALLOC_START: usize = 0x7C000;
+/// Start of the window of user address spaces where memory allocated by
+/// RAM manager gets mapped.
+pub const USER_RAM_BASE: u64 = 0x0000_1000_0000_0000;
 
-/// End of kernel memory allocator (excluding byte at this address).
-pub const MEMALLOC_END: usize = 0x7F000;
+/// End of the RAM manager window of user address spaces.
+pub const USER_RAM_END: u64 = 0x0000_7000_0000_0000;
+
+/// Start of the window of user address spaces where shared regions get
+/// mapped. Window starts right after the RAM manager window.
+pub const USER_SHARED_BASE: u64 = USER_RAM_END;
+
+/// End of the shared regions window of user address spaces.
+pub const USER_SHARED_END: u64 = 0x0000_7F00_0000_0000;
+
+/// Virtual address where physical memory is mapped. Byte at physical
+/// address X is accessible at virtual address `DIRECT_MAP_BASE + X`.
+pub const DIRECT_MAP_BASE: u64 = 0xFFFF_8000_0000_0000;
+
+/// Size of the virtual memory reserved for the direct map. Only existing
+/// physical memory is actually mapped.
+pub const DIRECT_MAP_SIZE: u64 = 0x0000_4000_0000_0000;
+
+/// Virtual address of the region where kernel stacks of the threads are
+/// allocated. Region starts right after the direct map.
+pub const KERNEL_STACKS_BASE: u64 = 0xFFFF_C000_0000_0000;
+
+/// Virtual address of the window where device memory gets mapped by
+/// `map_mmio`.
+pub const MMIO_BASE: u64 = 0xFFFF_D000_0000_0000;
+
+/// Size of the window for device memory.
+pub const MMIO_SIZE: u64 = 0x0000_0010_0000_0000;
+
+/// Virtual address of the kernel image area. Kernel is linked to run at
+/// this address plus it's physical load address.
+pub const KERNEL_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+
+/// Virtual address of the window where large objects of the kernel heap
+/// are mapped. Pages of such objects need not be physically contiguous.
+pub const HEAP_BASE: u64 = 0xFFFF_E000_0000_0000;
+
+/// Size of the window for large heap objects.
+pub const HEAP_SIZE: u64 = 0x0000_0010_0000_0000;
+
+extern {
+    /// First byte of the kernel image in the kernel image area. Defined
+    /// by the linker script.
+    static _kernel_start: u8;
+
+    /// Byte after the last byte of the kernel image. Defined by the linker
+    /// script.
+    static _kernel_end: u8;
+
+    // Page aligned boundaries of the sections of the kernel image.
+    // Defined by the linker script.
+    static _text_start: u8;
+    static _text_end: u8;
+    static _rodata_start: u8;
+    static _rodata_end: u8;
+    static _data_start: u8;
+    static _data_end: u8;
+}
+
+/// Physical address of the byte of the kernel image.
+fn image_phys(sym: &u8) -> u64 {
+    sym as *const u8 as u64 - KERNEL_BASE
+}
+
+/// Physical address of the first byte of the loaded kernel image.
+pub fn kernel_start() -> u64 {
+    unsafe { &_kernel_start as *const u8 as u64 - KERNEL_BASE }
+}
+
+/// Physical address of the byte after the last byte of the loaded kernel
+/// image.
+pub fn kernel_end() -> u64 {
+    unsafe { &_kernel_end as *const u8 as u64 - KERNEL_BASE }
+}
+
+/// Physical bounds of the kernel code. Pages are read-only and
+/// executable.
+pub fn text_range() -> (u64, u64) {
+    unsafe { (image_phys(&_text_start), image_phys(&_text_end)) }
+}
+
+/// Physical bounds of the read-only data of the kernel.
+pub fn rodata_range() -> (u64, u64) {
+    unsafe { (image_phys(&_rodata_start), image_phys(&_rodata_end)) }
+}
+
+/// Physical bounds of the writable data of the kernel, both initialized
+/// and zeroed.
+pub fn data_range() -> (u64, u64) {
+    unsafe { (image_phys(&_data_start), image_phys(&_data_end)) }
+}
@@ -0,0 +1,613 @@
+use core::alloc::Layout;
+use core::cmp::min;
+use core::mem::size_of;
+use core::ptr::{null_mut, write_bytes};
+use super::alloc::frame::order_size;
+use super::heap;
+use super::map::BOOT_MAPPED_END;
+use super::space::AddressSpace;
+use mem::PhysAddr;
+use early::logger;
+use core::fmt::Write;
+
+/// Byte that fills freed memory.
+pub const POISON_BYTE: u8 = 0x6B;
+
+/// Byte that fills redzones around heap objects.
+pub const REDZONE_BYTE: u8 = 0xBB;
+
+/// Byte that fills newly allocated heap objects so reads of memory that
+/// was never written are easy to spot.
+pub const ALLOC_BYTE: u8 = 0xA5;
+
+/// Size of the redzone that is placed before and after each heap object.
+pub const REDZONE_SIZE: usize = 16;
+
+/// Count of return addresses recorded for each heap object and frame
+/// block. Frames of the allocators are skipped before recording.
+pub const CALLER_DEPTH: usize = 8;
+
+/// Count of return addresses from `alloc` to the code that called
+/// `__rust_alloc`: into `alloc`, `KernelHeap::alloc` and `__rust_alloc`.
+/// Frames of 'alloc' crate like `RawVec` are recorded when they are not
+/// inlined.
+const HEAP_SKIP: usize = 3;
+
+/// Count of return addresses from `frames_allocated` to the code that
+/// called frame allocator: into `frames_allocated` and `alloc_in_zone`.
+/// Other allocation methods of `FrameAlloc` are inlined.
+const FRAME_SKIP: usize = 2;
+
+/// Maximal count of different allocation sites in the leak report.
+const MAX_SITES: usize = 32;
+
+/// Maximal count of allocated frame blocks whose allocation site is
+/// recorded.
+const MAX_FRAME_BLOCKS: usize = 4096;
+
+/// Magic value of the header of allocated object.
+const ALIVE_MAGIC: u64 = 0xA11C_A7ED_0B1E_C700;
+
+/// Magic value of the record of released heap block or frame block.
+const FREED_MAGIC: u64 = 0xF4EE_D0B1_EC70_0000;
+
+/// Header that is placed before the front redzone of each heap object.
+/// All allocated objects are linked in the list so leaks can be found.
+struct Header {
+
+    /// ALIVE_MAGIC while object is allocated.
+    magic   : u64,
+
+    /// Size of the object requested by the caller.
+    size    : usize,
+
+    /// Offset of the object from the start of the block.
+    front   : usize,
+
+    /// Sequence number of the allocation.
+    id      : u64,
+
+    /// Return addresses of the code that allocated the object.
+    callers : [usize; CALLER_DEPTH],
+
+    /// Next allocated object.
+    next    : *mut Header,
+
+    /// Previous allocated object.
+    prev    : *mut Header,
+}
+
+/// Record that is written over the start of released heap block or frame
+/// block after the block was poisoned. Only blocks with this record are
+/// checked for poison when they get allocated again as memory that was
+/// never released may hold any bytes.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Freed {
+
+    /// Allocator may overwrite it by it's own data.
+    link    : u64,
+
+    /// FREED_MAGIC.
+    magic   : u64,
+
+    /// Count of poisoned bytes from the start of the block.
+    len     : usize,
+
+    /// Return addresses of the code that allocated the block before it
+    /// was released. Zero if allocation site was not recorded.
+    callers : [usize; CALLER_DEPTH],
+}
+
+/// Block of frames given by frame allocator. Frame blocks have no room
+/// for redzones so free frames around them serve as ones. They are
+/// poisoned and overflow into them is found when they get allocated.
+#[derive(Clone, Copy)]
+struct FrameBlock {
+
+    /// Physical address of the block.
+    addr    : u64,
+
+    /// Order of the block.
+    order   : u8,
+
+    /// Sequence number of the allocation.
+    id      : u64,
+
+    /// Return addresses of the code that allocated the block.
+    callers : [usize; CALLER_DEPTH],
+}
+
+/// Allocated objects of single allocation site.
+#[derive(Clone, Copy)]
+struct Site {
+
+    /// Return addresses of the site.
+    callers : [usize; CALLER_DEPTH],
+
+    /// Count of objects allocated by the site.
+    count   : usize,
+
+    /// Sum of the object sizes.
+    bytes   : usize,
+}
+
+/// List of allocated heap objects. The last allocated object is the
+/// first.
+static mut LIVE: *mut Header = 0 as *mut Header;
+
+/// Sequence number of the next allocation of heap object or frames.
+static mut NEXT_ID: u64 = 1;
+
+/// Count of heap objects that are allocated.
+static mut LIVE_COUNT: usize = 0;
+
+/// Allocated frame blocks. Only first FRAME_COUNT entries are valid.
+static mut FRAME_BLOCKS: [FrameBlock; MAX_FRAME_BLOCKS] = [FrameBlock {
+    addr    : 0,
+    order   : 0,
+    id      : 0,
+    callers : [0; CALLER_DEPTH],
+}; MAX_FRAME_BLOCKS];
+
+/// Count of valid entries of FRAME_BLOCKS.
+static mut FRAME_COUNT: usize = 0;
+
+/// Count of allocated frame blocks that did not fit FRAME_BLOCKS.
+static mut FRAME_UNTRACKED: usize = 0;
+
+/// Read return addresses of the callers by walking the frame pointer
+/// chain. First is the address in the function that called this one.
+/// Given count of addresses is skipped before recording. Chain ends on
+/// null or misaligned frame pointer or on frame that is not above the
+/// previous one. Kernel must be built with frame pointers.
+#[inline(never)]
+fn backtrace(skip: usize) -> [usize; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    let mut rbp: usize;
+    let mut found = 0;
+    unsafe {
+        asm!("mov %rbp, $0" : "=r"(rbp) : : : "volatile");
+        while found < skip + CALLER_DEPTH {
+            if rbp == 0 || rbp & 7 != 0 {
+                break;
+            }
+            let frame = rbp as *const usize;
+            if found >= skip {
+                callers[found - skip] = *frame.offset(1);
+            }
+            found += 1;
+
+            let next = *frame;
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+    }
+    callers
+}
+
+/// Offset of the first byte that is not equal to given one. None if all
+/// bytes are equal to it.
+unsafe fn find_other(ptr: *const u8, len: usize, byte: u8) -> Option<usize> {
+    for i in 0..len {
+        if *ptr.offset(i as isize) != byte {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Poison the block of given size and put the record of release over it.
+unsafe fn poison(ptr: *mut u8, len: usize, callers: &[usize; CALLER_DEPTH]) {
+    write_bytes(ptr, POISON_BYTE, len);
+    if len >= size_of::<Freed>() {
+        *(ptr as *mut Freed) = Freed {
+            link    : 0,
+            magic   : FREED_MAGIC,
+            len     : len,
+            callers : *callers,
+        };
+    }
+}
+
+/// Record of release at the start of the block of given size. None if
+/// the block was not released by this module.
+unsafe fn freed_record(ptr: *const u8, len: usize) -> Option<Freed> {
+    if len < size_of::<Freed>() {
+        return None;
+    }
+    let freed = *(ptr as *const Freed);
+    if freed.magic == FREED_MAGIC && freed.len >= size_of::<Freed>() {
+        Some(freed)
+    } else {
+        None
+    }
+}
+
+/// Offset of the first byte of released block of given size that was
+/// modified after release. Only bytes that were poisoned by the release
+/// are checked. None if the block is intact or was not released.
+unsafe fn find_modified(ptr: *const u8, len: usize) -> Option<(usize, Freed)> {
+    let freed = match freed_record(ptr, len) {
+        Some(freed) => freed,
+        None        => return None,
+    };
+    let head = size_of::<Freed>();
+    let rest = ptr.offset(head as isize);
+    match find_other(rest, min(len, freed.len) - head, POISON_BYTE) {
+        Some(i) => Some((i + head, freed)),
+        None    => None,
+    }
+}
+
+/// Print return addresses on the kernel logger.
+fn print_callers(callers: &[usize; CALLER_DEPTH]) {
+    for caller in callers.iter() {
+        if *caller != 0 {
+            write!(logger(), " {:016X}", caller).unwrap();
+        }
+    }
+    write!(logger(), "\n").unwrap();
+}
+
+/// Print the error about heap object that is being released and halt.
+/// Allocation site is printed when the header is valid.
+unsafe fn heap_error(msg: &str, ptr: *mut u8, header: *const Header,
+        offset: Option<isize>) -> ! {
+    write!(logger(), "Heap error: {} at {:016X}", msg, ptr as usize)
+        .unwrap();
+    if let Some(offset) = offset {
+        write!(logger(), ", byte {:+}", offset).unwrap();
+    }
+    write!(logger(), "\n  released by:").unwrap();
+    print_callers(&backtrace(HEAP_SKIP));
+
+    if !header.is_null() && (*header).magic == ALIVE_MAGIC {
+        write!(logger(), "  allocation {} of {} bytes by:", (*header).id,
+            (*header).size).unwrap();
+        print_callers(&(*header).callers);
+    }
+    panic!("Heap corruption");
+}
+
+/// Layout of the block that holds the object with redzones and header.
+/// Returns the layout and the offset of the object in the block.
+fn debug_layout(layout: &Layout) -> Option<(Layout, usize)> {
+    let align = layout.align();
+    let front = (size_of::<Header>() + REDZONE_SIZE + align - 1)
+            / align * align;
+    let size = front + layout.size() + REDZONE_SIZE;
+    match Layout::from_size_align(size, align) {
+        Ok(block)   => Some((block, front)),
+        Err(_)      => None,
+    }
+}
+
+/// Allocate heap object with redzones around it. Object is filled with
+/// ALLOC_BYTE and allocation site is recorded.
+///
+/// # Panics
+/// When reused memory was modified after it was released.
+#[inline(never)]
+pub unsafe fn alloc(layout: Layout) -> *mut u8 {
+    let (block_layout, front) = match debug_layout(&layout) {
+        Some(found) => found,
+        None        => return null_mut(),
+    };
+
+    let block = heap::raw_alloc(block_layout);
+    if block.is_null() {
+        return null_mut();
+    }
+
+    let callers = backtrace(HEAP_SKIP);
+    if let Some((i, freed)) = find_modified(block, block_layout.size()) {
+        write!(logger(),
+            "Heap error: use after free at {:016X}, byte {}\n",
+            block as usize, i).unwrap();
+        write!(logger(), "  released block was allocated by:").unwrap();
+        print_callers(&freed.callers);
+        write!(logger(), "  reused by:").unwrap();
+        print_callers(&callers);
+        panic!("Heap corruption");
+    }
+
+    let ptr = block.offset(front as isize);
+    let header = block as *mut Header;
+    *header = Header {
+        magic   : ALIVE_MAGIC,
+        size    : layout.size(),
+        front   : front,
+        id      : NEXT_ID,
+        callers : callers,
+        next    : LIVE,
+        prev    : null_mut(),
+    };
+    if !LIVE.is_null() {
+        (*LIVE).prev = header;
+    }
+    LIVE = header;
+    NEXT_ID += 1;
+    LIVE_COUNT += 1;
+
+    let head = size_of::<Header>();
+    write_bytes(block.offset(head as isize), REDZONE_BYTE, front - head);
+    write_bytes(ptr, ALLOC_BYTE, layout.size());
+    write_bytes(ptr.offset(layout.size() as isize), REDZONE_BYTE,
+            REDZONE_SIZE);
+    ptr
+}
+
+/// Check redzones of the heap object, poison it and release.
+///
+/// # Panics
+/// When object was not allocated, was already released, size does not
+/// match or redzones were overwritten.
+pub unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+    let (block_layout, front) = match debug_layout(&layout) {
+        Some(found) => found,
+        None        => heap_error("invalid layout", ptr, null_mut(), None),
+    };
+    let block = ptr.offset(-(front as isize));
+    let header = block as *mut Header;
+
+    if (*header).magic != ALIVE_MAGIC {
+        heap_error("double free or invalid pointer", ptr, null_mut(), None);
+    }
+    if (*header).size != layout.size() || (*header).front != front {
+        heap_error("size mismatch", ptr, header, None);
+    }
+
+    let head = size_of::<Header>();
+    let zone = block.offset(head as isize);
+    if let Some(i) = find_other(zone, front - head, REDZONE_BYTE) {
+        let offset = i as isize - (front - head) as isize;
+        heap_error("front redzone overwritten", ptr, header, Some(offset));
+    }
+    let tail = ptr.offset(layout.size() as isize);
+    if let Some(i) = find_other(tail, REDZONE_SIZE, REDZONE_BYTE) {
+        heap_error("back redzone overwritten", ptr, header,
+            Some((layout.size() + i) as isize));
+    }
+
+    if (*header).prev.is_null() {
+        LIVE = (*header).next;
+    } else {
+        (*(*header).prev).next = (*header).next;
+    }
+    if !(*header).next.is_null() {
+        (*(*header).next).prev = (*header).prev;
+    }
+    LIVE_COUNT -= 1;
+
+    let callers = (*header).callers;
+    poison(block, block_layout.size(), &callers);
+    heap::raw_dealloc(block, block_layout);
+}
+
+/// Sequence number of the next heap or frame allocation. Can be given to
+/// `dump_leaks` to report only memory allocated after this call.
+pub fn leak_mark() -> u64 {
+    unsafe { NEXT_ID }
+}
+
+/// Count of heap objects that are allocated.
+pub fn live_objects() -> usize {
+    unsafe { LIVE_COUNT }
+}
+
+impl Site {
+
+    /// Site with no objects.
+    const fn empty() -> Self {
+        Site {
+            callers : [0; CALLER_DEPTH],
+            count   : 0,
+            bytes   : 0,
+        }
+    }
+}
+
+/// Sites of the leak report.
+struct Report {
+
+    /// Sites that fit the report.
+    sites   : [Site; MAX_SITES],
+
+    /// Count of valid sites.
+    used    : usize,
+
+    /// Sum of the sites that do not fit the report.
+    other   : Site,
+}
+
+impl Report {
+
+    /// Empty report.
+    fn new() -> Self {
+        Report {
+            sites   : [Site::empty(); MAX_SITES],
+            used    : 0,
+            other   : Site::empty(),
+        }
+    }
+
+    /// Add allocated memory to the site of given callers.
+    fn add(&mut self, callers: &[usize; CALLER_DEPTH], bytes: usize) {
+        let found = self.sites[..self.used].iter().position(|s| {
+            s.callers == *callers
+        });
+        let site = match found {
+            Some(i) => &mut self.sites[i],
+            None if self.used < MAX_SITES => {
+                self.sites[self.used].callers = *callers;
+                self.used += 1;
+                &mut self.sites[self.used - 1]
+            },
+            None => &mut self.other,
+        };
+        site.count += 1;
+        site.bytes += bytes;
+    }
+
+    /// Print the sites on the kernel logger. Kind names the memory.
+    fn print(&self, kind: &str) {
+        for site in self.sites[..self.used].iter() {
+            write!(logger(), "  {} {}, {} bytes by:", site.count, kind,
+                site.bytes).unwrap();
+            print_callers(&site.callers);
+        }
+        if self.other.count != 0 {
+            write!(logger(), "  {} {}, {} bytes by other sites\n",
+                self.other.count, kind, self.other.bytes).unwrap();
+        }
+    }
+}
+
+/// Print heap objects and frame blocks that are still allocated and were
+/// allocated since given mark. Objects and blocks are grouped by
+/// allocation site. Sites that do not fit the report are summed in the
+/// last line.
+pub fn dump_leaks(mark: u64) {
+    // Reports are big so only one is kept on the stack at once.
+    {
+        let mut heap = Report::new();
+        unsafe {
+            let mut header = LIVE;
+            while !header.is_null() {
+                let h = &*header;
+                header = h.next;
+                if h.id >= mark {
+                    heap.add(&h.callers, h.size);
+                }
+            }
+        }
+        write!(logger(), "Heap objects since {}: {} sites\n", mark,
+            heap.used).unwrap();
+        heap.print("objects");
+    }
+
+    let mut frames = Report::new();
+    unsafe {
+        for block in FRAME_BLOCKS[..FRAME_COUNT].iter() {
+            if block.id >= mark {
+                frames.add(&block.callers, order_size(block.order) as usize);
+            }
+        }
+    }
+    write!(logger(), "Frame blocks since {}: {} sites\n", mark,
+        frames.used).unwrap();
+    frames.print("blocks");
+    let untracked = unsafe { FRAME_UNTRACKED };
+    if untracked != 0 {
+        write!(logger(), "  {} blocks were not recorded\n", untracked)
+            .unwrap();
+    }
+}
+
+/// Whether memory of the block is accessible through the direct map.
+/// Before kernel paging is loaded only memory mapped by boot code is.
+fn is_accessible(addr: PhysAddr, order: u8) -> bool {
+    AddressSpace::current().is_some()
+            || addr.as_u64() + order_size(order) <= BOOT_MAPPED_END
+}
+
+/// Allocated frame block that ends right at given address. The block may
+/// have overflowed into the memory at this address.
+unsafe fn block_below(addr: u64) -> Option<&'static FrameBlock> {
+    FRAME_BLOCKS[..FRAME_COUNT].iter().find(|b| {
+        b.addr + order_size(b.order) == addr
+    })
+}
+
+/// Check that the block of frames was not modified since it was released
+/// and record allocation site of the block. Called by frame allocator for
+/// each allocated block.
+///
+/// # Panics
+/// When poisoned block was modified.
+#[inline(never)]
+pub fn frames_allocated(addr: PhysAddr, order: u8) {
+    let callers = backtrace(FRAME_SKIP);
+    if is_accessible(addr, order) {
+        check_poison(addr, order, &callers);
+    }
+
+    unsafe {
+        if FRAME_COUNT == MAX_FRAME_BLOCKS {
+            FRAME_UNTRACKED += 1;
+            return;
+        }
+        FRAME_BLOCKS[FRAME_COUNT] = FrameBlock {
+            addr    : addr.as_u64(),
+            order   : order,
+            id      : NEXT_ID,
+            callers : callers,
+        };
+        FRAME_COUNT += 1;
+        NEXT_ID += 1;
+    }
+}
+
+/// Check that released block of frames was not modified and remove the
+/// record of release. Callers are the code that allocates the block now.
+///
+/// # Panics
+/// When poisoned block was modified.
+fn check_poison(addr: PhysAddr, order: u8, callers: &[usize; CALLER_DEPTH]) {
+    let len = order_size(order) as usize;
+    unsafe {
+        let ptr = addr.as_mut_ptr::<u8>();
+        if let Some((i, freed)) = find_modified(ptr, len) {
+            write!(logger(),
+                "Frame error: use after free at {:016X}, byte {}\n",
+                addr.as_u64(), i).unwrap();
+            write!(logger(), "  released block was allocated by:").unwrap();
+            print_callers(&freed.callers);
+            write!(logger(), "  reused by:").unwrap();
+            print_callers(callers);
+            if let Some(below) = block_below(addr.as_u64()) {
+                write!(logger(), "  block {:016X} below allocated by:",
+                    below.addr).unwrap();
+                print_callers(&below.callers);
+            }
+            panic!("Frame corruption");
+        }
+
+        if freed_record(ptr, len).is_some() {
+            (*(ptr as *mut Freed)).magic = 0;
+        }
+    }
+}
+
+/// Fill the block of frames with poison and move it's allocation site to
+/// the record of release. Called by frame allocator for each block that
+/// is going to be released while the block is still allocated.
+pub fn frames_released(addr: PhysAddr, order: u8) {
+    unsafe {
+        let found = FRAME_BLOCKS[..FRAME_COUNT].iter().position(|b| {
+            b.addr == addr.as_u64()
+        });
+        let callers = match found {
+            Some(i) => {
+                let callers = FRAME_BLOCKS[i].callers;
+                FRAME_COUNT -= 1;
+                FRAME_BLOCKS[i] = FRAME_BLOCKS[FRAME_COUNT];
+                callers
+            },
+            None => {
+                if FRAME_UNTRACKED > 0 {
+                    FRAME_UNTRACKED -= 1;
+                }
+                [0; CALLER_DEPTH]
+            },
+        };
+
+        if is_accessible(addr, order) {
+            poison(addr.as_mut_ptr::<u8>(), order_size(order) as usize,
+                &callers);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use super::map::{MMIO_BASE, MMIO_SIZE};
+use super::paging::kernel_space_mut;
+use super::space::*;
+use mem::{PhysAddr, VirtAddr};
+use core::ptr::{read_volatile, write_volatile};
+
+/// IA32_PAT model specific register.
+const IA32_PAT: u32 = 0x277;
+
+/// Memory types that PAT entries can select.
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+
+/// Value of IA32_PAT. Entries are selected by PCD and PWT bits of the
+/// page entry. Index 2 which is UC- by default becomes write-combining so
+/// PAT bit, which is the same bit as the huge page bit in upper levels,
+/// is never needed. Upper four entries repeat the lower ones.
+const PAT_VALUE: u64 = (PAT_WB | PAT_WT << 8 | PAT_WC << 16 | PAT_UC << 24)
+        * 0x1_0000_0001;
+
+/// Maximal count of MMIO regions that can be mapped at once.
+const MAX_REGIONS: usize = 64;
+
+/// Caching policy of mapped device memory.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CacheType {
+
+    /// Every access goes to the device. For device registers.
+    Uncacheable,
+
+    /// Writes are combined in buffers and are not ordered. For frame
+    /// buffers.
+    WriteCombining,
+
+    /// Reads are cached, writes go to memory immediately.
+    WriteThrough,
+
+    /// Normal caching as for RAM.
+    WriteBack,
+}
+
+/// Device memory mapped in MMIO window of the kernel space. Memory gets
+/// unmapped when region is dropped.
+pub struct MmioRegion {
+
+    /// Physical address of the first byte of the region.
+    phys    : PhysAddr,
+
+    /// Virtual address of the first byte of the region.
+    virt    : VirtAddr,
+
+    /// Size of the region in bytes.
+    size    : u64,
+
+    /// Slot of the window that holds mapped pages.
+    slot    : usize,
+}
+
+/// Range of the MMIO window that is used by some region.
+#[derive(Clone, Copy)]
+struct Slot {
+
+    /// First page of the range.
+    start   : u64,
+
+    /// Size of the range. Multiple of page size.
+    size    : u64,
+}
+
+/// Used ranges of the MMIO window.
+static mut SLOTS: [Option<Slot>; MAX_REGIONS] = [None; MAX_REGIONS];
+
+impl CacheType {
+
+    /// Flags of the page entry that select PAT entry of this type.
+    pub fn page_flags(&self) -> u64 {
+        match *self {
+            CacheType::WriteBack        => 0,
+            CacheType::WriteThrough     => WRITE_THROUGH,
+            CacheType::WriteCombining   => CACHE_DISABLE,
+            CacheType::Uncacheable      => CACHE_DISABLE | WRITE_THROUGH,
+        }
+    }
+}
+
+impl Slot {
+
+    /// Whether slot overlaps given range.
+    fn overlaps(&self, start: u64, size: u64) -> bool {
+        start < self.start + self.size && self.start < start + size
+    }
+}
+
+/// Find free range of the MMIO window and store it in free slot. Returns
+/// index of the slot and start of the range.
+fn reserve(size: u64) -> Option<(usize, u64)> {
+    let slots = unsafe { &mut SLOTS };
+    let index = match slots.iter().position(|s| s.is_none()) {
+        Some(index) => index,
+        None        => return None,
+    };
+
+    // First fit. Start is moved behind each overlapping range until
+    // range is free.
+    let mut start = MMIO_BASE;
+    'search: loop {
+        if start + size > MMIO_BASE + MMIO_SIZE {
+            return None;
+        }
+        for slot in slots.iter() {
+            if let Some(slot) = *slot {
+                if slot.overlaps(start, size) {
+                    start = slot.start + slot.size;
+                    continue 'search;
+                }
+            }
+        }
+        break;
+    }
+
+    slots[index] = Some(Slot { start: start, size: size });
+    Some((index, start))
+}
+
+/// Load PAT register with cache types used by the kernel. Must be called
+/// on each processor before memory with write-combining type is accessed.
+pub fn setup_pat() {
+    unsafe {
+        asm!("wbinvd
+              wrmsr" : : "{ecx}"(IA32_PAT), "{eax}"(PAT_VALUE as u32),
+              "{edx}"((PAT_VALUE >> 32) as u32) : "memory" : "volatile");
+    }
+}
+
+/// Map device memory with given cache type. Address and size are not
+/// required to be page aligned. Memory is never executable and is not
+/// accessible from user mode.
+///
+/// # Errors
+/// NoVirtualSpace error occurs when MMIO window has no free range of the
+/// size. NoMemory error occurs when paging table cannot be allocated.
+pub fn map_mmio(phys: PhysAddr, size: u64, cache_type: CacheType)
+        -> MapResult<MmioRegion> {
+    let first = phys.align_down(0x1000);
+    let end = (phys + size).align_up(0x1000);
+    let mapped = end - first;
+
+    let (slot, start) = match reserve(mapped) {
+        Some(found) => found,
+        None        => return Err(MapError::NoVirtualSpace),
+    };
+
+    let flags = WRITABLE | NO_EXECUTE | GLOBAL | cache_type.page_flags();
+    let start = VirtAddr::new(start);
+    let space = kernel_space_mut();
+    if let Err(e) = space.map(start, first, mapped, flags) {
+        let _ = space.unmap(start, mapped);
+        unsafe { SLOTS[slot] = None; }
+        return Err(e);
+    }
+
+    Ok(MmioRegion {
+        phys    : phys,
+        virt    : start + phys.page_offset(),
+        size    : size,
+        slot    : slot,
+    })
+}
+
+impl MmioRegion {
+
+    /// Physical address of the region.
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Virtual address the region is mapped at.
+    pub fn virt(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// Size of the region in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Pointer to the value at given offset.
+    ///
+    /// # Panics
+    /// When value does not fit the region or is not aligned.
+    fn ptr<T>(&self, offset: u64) -> *mut T {
+        use core::mem::{size_of, align_of};
+
+        assert!(offset + size_of::<T>() as u64 <= self.size,
+                "MMIO access out of region");
+        let addr = self.virt + offset;
+        assert!(addr.is_aligned(align_of::<T>() as u64),
+                "Unaligned MMIO access");
+        addr.as_mut_ptr()
+    }
+
+    /// Read the value at given offset. Read is never omitted or merged by
+    /// the compiler.
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        unsafe { read_volatile(self.ptr(offset)) }
+    }
+
+    /// Write the value at given offset. Write is never omitted or merged
+    /// by the compiler.
+    pub fn write<T: Copy>(&mut self, offset: u64, val: T) {
+        unsafe { write_volatile(self.ptr(offset), val) }
+    }
+
+    /// Read 8-bit value at given offset.
+    pub fn read_u8(&self, offset: u64) -> u8 {
+        self.read(offset)
+    }
+
+    /// Read 16-bit value at given offset.
+    pub fn read_u16(&self, offset: u64) -> u16 {
+        self.read(offset)
+    }
+
+    /// Read 32-bit value at given offset.
+    pub fn read_u32(&self, offset: u64) -> u32 {
+        self.read(offset)
+    }
+
+    /// Read 64-bit value at given offset.
+    pub fn read_u64(&self, offset: u64) -> u64 {
+        self.read(offset)
+    }
+
+    /// Write 8-bit value at given offset.
+    pub fn write_u8(&mut self, offset: u64, val: u8) {
+        self.write(offset, val)
+    }
+
+    /// Write 16-bit value at given offset.
+    pub fn write_u16(&mut self, offset: u64, val: u16) {
+        self.write(offset, val)
+    }
+
+    /// Write 32-bit value at given offset.
+    pub fn write_u32(&mut self, offset: u64, val: u32) {
+        self.write(offset, val)
+    }
+
+    /// Write 64-bit value at given offset.
+    pub fn write_u64(&mut self, offset: u64, val: u64) {
+        self.write(offset, val)
+    }
+}
+
+impl Drop for MmioRegion {
+
+    fn drop(&mut self) {
+        let slot = unsafe { SLOTS[self.slot].take().unwrap() };
+        let start = VirtAddr::new(slot.start);
+        if kernel_space_mut().unmap(start, slot.size).is_err() {
+            panic!("Failed to unmap MMIO region");
+        }
+    }
+}
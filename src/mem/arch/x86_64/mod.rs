@@ -3,32 +3,125 @@ pub mod map;
 
 /// Memory allocator for system. Manages pages of memory for further use.
 pub mod alloc;
+pub use self::alloc::FrameAlloc;
 
 /// Memory pages of the kernel.
 pub mod paging;
 
+/// Virtual address spaces. Mapping, unmapping and protection of pages.
+pub mod space;
+
+/// Reserved areas of address spaces which pages are mapped on demand.
+pub mod area;
+
+/// Page fault handler. Maps pages of reserved areas on first access.
+pub mod fault;
+
+/// Kernel heap. Global allocator for 'alloc' crate types.
+pub mod heap;
+
+/// Caches of objects of single type stored in page-sized slabs.
+pub mod slab;
+
 /// Global Descriptor Table of the kernel.
 pub mod gdt;
 
-use super::TopLimitedAllocator;
+/// Kernel stacks of the threads protected by guard pages.
+pub mod kstack;
 
-/// Main kernel memory allocator.
-/// Is allowed to be used only when kernel paging was re-initialized.
-static mut MAIN_ALLOC: TopLimitedAllocator = new_main_alloc();
+/// Mapping of device memory with chosen cache type.
+pub mod mmio;
+
+/// Accounting of memory used by kernel subsystems.
+pub mod stats;
+
+/// Invalidation of TLB entries on all processors that use changed pages.
+pub mod tlb;
+
+/// Process-context identifiers that tag TLB entries of address spaces.
+pub mod pcid;
+
+/// Dump of paging tables and step by step address translation.
+pub mod ptdump;
+
+/// Allocator debug mode. Used when kernel is built with 'mem_debug'
+/// configuration.
+#[cfg(mem_debug)]
+pub mod memdebug;
+
+use super::{FreeListAllocator, FitPolicy};
+use super::RegionList;
+
+/// Main kernel memory allocator. Memory given by it can be released.
+/// Is None until kernel paging gets re-initialized.
+static mut MAIN_ALLOC: Option<FreeListAllocator> = None;
 
-const fn new_main_alloc() -> TopLimitedAllocator {
+/// Create main kernel memory allocator. Memory is accessed through the
+/// direct map so it must be called when kernel paging was re-initialized.
+fn init_main_alloc() {
     use super::super::Address;
 
-    TopLimitedAllocator::new(Address::new_from_usize(map::MEMALLOC_START),
-            Address::new_from_usize(map::MEMALLOC_END))
+    let start = map::DIRECT_MAP_BASE as usize + map::MEMALLOC_START;
+    let end = map::DIRECT_MAP_BASE as usize + map::MEMALLOC_END;
+    let alloc = FreeListAllocator::new(Address::from(start),
+            Address::from(end), FitPolicy::FirstFit);
+    unsafe { MAIN_ALLOC = Some(alloc); }
 }
 
 /// Main kernel memory allocator reference.
 /// Is allowed to be used only when kernel paging was re-initialized.
-pub fn main_alloc() -> &'static TopLimitedAllocator {
-    unsafe { &MAIN_ALLOC }
+pub fn main_alloc() -> &'static FreeListAllocator {
+    unsafe { MAIN_ALLOC.as_ref().unwrap() }
+}
+
+pub fn main_alloc_mut() -> &'static mut FreeListAllocator {
+    unsafe { MAIN_ALLOC.as_mut().unwrap() }
+}
+
+/// Physical memory regions discovered at boot.
+static mut PHYS_MAP: RegionList = RegionList::new();
+
+/// Physical memory regions discovered at boot. The list is empty until
+/// `init_phys_map` gets called.
+pub fn phys_map() -> &'static RegionList {
+    unsafe { &PHYS_MAP }
+}
+
+/// Fill the list of physical memory regions with the information provided
+/// by Multiboot compliant boot loader.
+pub fn init_phys_map(info: &::early::multiboot::MultibootInfo) {
+    let map = unsafe { &mut PHYS_MAP };
+    map.clear();
+
+    if info.memory_regions(map).is_err() {
+        use early::{LoggerTrait, logger};
+        logger().println("Too many memory regions. Some RAM will be unused.");
+    }
+}
+
+/// Page allocator of the system. Is None until `init_page_alloc` gets
+/// called.
+static mut PAGE_ALLOC: Option<alloc::KernelFrameAlloc> = None;
+
+/// Create page allocator that manages all free RAM found in the physical
+/// memory map. Regions of reserved list are never given by the allocator.
+///
+/// # Safety
+/// Must be called once, after `init_phys_map`. Memory of reserved list
+/// must cover all the data that is still in use (like boot modules).
+pub unsafe fn init_page_alloc(reserved: &RegionList) {
+    let mut mem = alloc::BootMemory::new(phys_map(), reserved);
+    PAGE_ALLOC = Some(alloc::KernelFrameAlloc::new(&mut mem));
+}
+
+/// Page allocator reference.
+/// Is allowed to be used only after `init_page_alloc` call.
+pub fn page_alloc() -> &'static alloc::KernelFrameAlloc {
+    unsafe { PAGE_ALLOC.as_ref().unwrap() }
 }
 
-pub fn main_alloc_mut() -> &'static mut TopLimitedAllocator {
-    unsafe { &mut MAIN_ALLOC }
+/// Page allocator mutable reference.
+/// Is allowed to be used only after `init_page_alloc` call.
+pub fn page_alloc_mut() -> &'static mut alloc::KernelFrameAlloc {
+    unsafe { PAGE_ALLOC.as_mut().unwrap() }
 }
@@ -1,194 +1,173 @@
-use arch::tables::paging::*;
-use mem::map::{PAGING_P4, PAGING_P3, PAGING_P2, PAGING_P1};
-
-/// Get reference to P1 page struct of the kernel.
-fn p1() -> &'static mut P1 {
-    unsafe {
-        let a: *mut P1 = ::core::mem::transmute(PAGING_P1);
-        &mut *a
-    }
+use super::space::*;
+use super::map::{LOW_MEMORY_END, CONVENTIONAL_END};
+use super::map::{KERNEL_BASE, text_range, rodata_range, data_range};
+use super::{phys_map, page_alloc_mut};
+use super::alloc::FrameAlloc;
+use super::mmio::setup_pat;
+use super::pcid;
+use mem::{PhysAddr, VirtAddr, RegionKind};
+
+/// Address space of the kernel. Is None until `setup` gets called.
+static mut KERNEL_SPACE: Option<AddressSpace> = None;
+
+/// Address space of the kernel.
+/// Is allowed to be used only after `setup` call.
+pub fn kernel_space() -> &'static AddressSpace {
+    unsafe { KERNEL_SPACE.as_ref().unwrap() }
 }
 
-/// Get reference to P2 page struct of the kernel.
-fn p2() -> &'static mut P2 {
-    unsafe {
-        let a: *mut P2 = ::core::mem::transmute(PAGING_P2);
-        &mut *a
-    }
+/// Address space of the kernel.
+/// Is allowed to be used only after `setup` call.
+pub fn kernel_space_mut() -> &'static mut AddressSpace {
+    unsafe { KERNEL_SPACE.as_mut().unwrap() }
 }
 
-/// Get reference to P3 page struct of the kernel.
-fn p3() -> &'static mut P3 {
-    unsafe {
-        let a: *mut P3 = ::core::mem::transmute(PAGING_P3);
-        &mut *a
+/// Map physical memory range at virtual addresses of the same memory
+/// in the direct map.
+fn direct(space: &mut AddressSpace, start: u64, end: u64, flags: u64) {
+    let phys = PhysAddr::new(start);
+    if space.map(phys.to_virt(), phys, end - start, flags).is_err() {
+        panic!("Failed to map kernel memory");
     }
 }
 
-/// Get reference to P4 page struct of the kernel.
-fn p4() -> &'static mut P4 {
-    unsafe {
-        let a: *mut P4 = ::core::mem::transmute(PAGING_P4);
-        &mut *a
+/// Map section of the kernel image with given flags.
+fn section(space: &mut AddressSpace, range: (u64, u64), flags: u64) {
+    let (start, end) = range;
+    let virt = VirtAddr::new(KERNEL_BASE + start);
+    if space.map(virt, PhysAddr::new(start), end - start, flags).is_err() {
+        panic!("Failed to map kernel image");
     }
 }
 
-/// Initialize and load kernel paging table. Creates regions for
-/// normal data and code. Also, disables cache for regions with
-/// mapped I/O devices.
-pub fn setup() {
-    unsafe {
-        use core::intrinsics::write_bytes;
-        // Set all pages in tables to zero.
-        write_bytes(p1() as *const _ as *mut P1, 0, 1);
-        write_bytes(p2() as *const _ as *mut P2, 0, 1);
-        write_bytes(p3() as *const _ as *mut P3, 0, 1);
-        write_bytes(p4() as *const _ as *mut P4, 0, 1);
+/// Make the frames of given section read-only in the direct map so the
+/// section cannot be changed through it. Pages stay global and not
+/// executable.
+fn protect_direct(space: &mut AddressSpace, range: (u64, u64)) {
+    let (start, end) = range;
+    let virt = PhysAddr::new(start).to_virt();
+    if space.clear_flags(virt, end - start, WRITABLE).is_err() {
+        panic!("Failed to protect kernel image");
     }
+}
 
-    use arch::tables::*;
-
-    unsafe {
-        // Setup P4 entry. This entry covers the first 512 GiB of RAM.
-        let p4e = p4().entry_handle(0).unwrap().variant();
-        let p4e = match p4e { P4EVariant::P4E(a) => a };
-
-        let flags =
-            PageFlag::rw        () | // Readable and Writable.
-            PageFlag::present   () ;
-
-        // US flag is off.
-        // NOT accessible for user-mode processes.
-        // This page table must be used only by the kernel. No
-        // user-space process must never use this table. So
-        // this flag must not be set.
-
-        let addr = p3() as *const _ as u64;
-        let b: u64 = PageFlag::p4addr().into();
-        if addr != addr & b {
-            panic!("Address invalid");
-        }
-
-        p4e.data_rewrite(flags | PageFlag::from(addr));
+/// Enable No-Execute bit in paging entries. Entries with this bit set
+/// cause page fault with reserved bit error when it is disabled. Does
+/// nothing when processor does not support the bit. Entries are then
+/// created without it.
+fn enable_nx() {
+    if !is_nx_supported() {
+        return;
     }
 
     unsafe {
-        // Setup P3 entries.
-        // First entry covers first 1GiB of RAM.
-        let p3e = p3().entry_handle(0).unwrap().variant();
-        let p3e = match p3e { P3EVariant::P3E(a) => a };
-
-        let flags =
-            PageFlag::rw        () | // Readable and Writable.
-            PageFlag::present   () ;
-
-        let addr = PageFlag::from(p2() as *const _ as u64);
-
-        p3e.data_rewrite(flags | addr);
+        asm!("rdmsr
+              or $$0x800, %eax
+              wrmsr" : : "{ecx}"(0xC0000080u32) : "eax", "edx" : "volatile");
     }
+}
 
+/// Enable global pages. TLB entries of pages with global flag are not
+/// flushed on address space switch.
+fn enable_global_pages() {
     unsafe {
-        // Each P2 entry covers 2MiB region.
-        let p2e = p2().entry_handle(0).unwrap();
-
-        let mut val = P2ERef::default();
-
-        let flags =
-            PageFlag::rw        () |
-            PageFlag::present   () ;
-
-        let addr = PageFlag::from(p1() as *const _ as u64);
-
-        val.data_rewrite(flags | addr);
-
-        p2e.set_ref(val);
+        asm!("mov %cr4, %rax
+              or $$0x80, %rax
+              mov %rax, %cr4" : : : "rax" : "volatile");
     }
+}
 
-    unsafe {
-        // 0x00000 - 0x00FFF
-        let p = match p1().entry_handle(0).unwrap().variant() {
-            P1EVariant::P1E(a) => a
-        };
-
-        let flags =
-            PageFlag::rw        () |
-            PageFlag::present   () ;
-
-        let addr = PageFlag::from(0x00000);
-
-        p.data_rewrite(flags | addr);
-
-        // 0x01000 - 0x01FFF: APIC registers.
-        // Assertion fail when memory map was changed by someone.
-        // Code below must be reviewed in such a case and changed too.
-        assert!(super::map::APIC_BASE_ADDRESS == 0x01000);
-        let p = match p1().entry_handle(1).unwrap().variant() {
-            P1EVariant::P1E(a) => a
-        };
-
-        let flags =
-            PageFlag::rw        () |
-            PageFlag::pwt       () | // Write-through.
-            PageFlag::pcd       () | // Disbale caching.
-            PageFlag::present   () ;
-
-        let addr = PageFlag::from(0x01000);
-
-        p.data_rewrite(flags | addr);
-
-        let flags =
-            PageFlag::rw        () |
-            PageFlag::present   () ;
-
-        // Conventional memory.
-        for i in 0x02..0x9F {
-            let p = match p1().entry_handle(i).unwrap().variant() {
-                P1EVariant::P1E(a) => a
-            };
-
-            let addr = PageFlag::from(0x1000 * i as u64);
-
-            p.data_rewrite(flags | addr);
+/// Initialize and load kernel paging table. Conventional memory and all
+/// usable RAM is mapped in the direct map and the kernel image is mapped
+/// in the kernel image area. Also, loads PAT. Device memory, Local APIC
+/// and VGA text buffer included, is mapped only by `map_mmio`.
+/// Identity mapping of the boot code is not created.
+/// Tables of the upper half are shared with all other address spaces.
+///
+/// No page is both writable and executable. Kernel code is read-only,
+/// data is not executable, the direct map is never executable.
+///
+/// Page allocator must be initialized as paging tables are taken from it.
+/// Until new tables are loaded allocator gives only frames that boot code
+/// mapped, then it's limit is removed.
+/// Physical memory map must be initialized.
+pub fn setup() {
+    detect_features();
+
+    let mut space = match AddressSpace::new_kernel() {
+        Ok(space)   => space,
+        Err(_)      => panic!("No memory for kernel paging tables"),
+    };
+
+    // US flag is off for all pages.
+    // NOT accessible for user-mode processes.
+    // Kernel pages are the same in all spaces so they are global.
+    let flags = WRITABLE | NO_EXECUTE | GLOBAL;
+
+    // Conventional memory with BIOS data, IDT, GDT and kernel allocator
+    // memory. Video memory and ROM above it are device memory.
+    direct(&mut space, 0x00000, CONVENTIONAL_END, flags);
+
+    // All RAM above the first megabyte. Holes between regions may be
+    // device memory which must be mapped by `map_mmio` with proper cache
+    // type so only usable regions are mapped. Page allocator data and all
+    // allocated frames are in these regions.
+    for region in phys_map().iter() {
+        if region.kind() != RegionKind::Usable {
+            continue;
         }
 
-        let flags =
-            PageFlag::rw        () |
-            PageFlag::pwt       () |
-            PageFlag::pcd       () |
-            PageFlag::present   () ;
-
-        // I/O devices are mapped in this region.
-        for i in 0xA0..0xFF {
-            let p = match p1().entry_handle(i).unwrap().variant() {
-                P1EVariant::P1E(a) => a
-            };
-
-            let addr = PageFlag::from(0x1000 * i as u64);
-
-            p.data_rewrite(flags | addr);
+        let start = if region.start() > LOW_MEMORY_END {
+            region.start()
+        } else {
+            LOW_MEMORY_END
+        };
+        let start = PhysAddr::new(start).align_up(0x1000).as_u64();
+        let end = PhysAddr::new(region.end()).align_down(0x1000).as_u64();
+        if start < end {
+            direct(&mut space, start, end, flags);
         }
+    }
 
-        let flags =
-            PageFlag::rw        () |
-            PageFlag::present   () ;
+    // Kernel image. Bounds of sections are page aligned by the linker
+    // script. Same frames are also accessible in the direct map.
+    section(&mut space, text_range(), GLOBAL);
+    section(&mut space, rodata_range(), NO_EXECUTE | GLOBAL);
+    section(&mut space, data_range(), WRITABLE | NO_EXECUTE | GLOBAL);
+    protect_direct(&mut space, text_range());
+    protect_direct(&mut space, rodata_range());
 
-        // Map second megabyte where OS code is stored.
-        for i in 0x100..0x200 {
-            let p = match p1().entry_handle(i).unwrap().variant() {
-                P1EVariant::P1E(a) => a
-            };
+    // Paging entries above have NX bit set if it is supported.
+    enable_nx();
 
-            let addr: PageFlag = (0x1000 * i as u64).into();
+    // Cache types of the entries above are selected by PAT.
+    setup_pat();
 
-            p.data_rewrite(flags | addr);
-        }
+    // Make read-only pages write protected for the kernel too. Otherwise
+    // kernel writes to copy-on-write pages would not cause page faults.
+    unsafe {
+        asm!("mov %cr0, %rax
+              or $$0x10000, %rax
+              mov %rax, %cr0" : : : "rax" : "volatile");
     }
 
     // Save P4 address to CR3 and so start using new paging.
     unsafe {
-        use arch::cr::{Cr3, Reg};
-        let mut cr3 = Cr3::read();
-        cr3.set_addr(p4() as *const _ as _);
-        cr3.save();
+        KERNEL_SPACE = Some(space);
+        kernel_space_mut().activate();
     }
+
+    // All allocated frames are accessible through the direct map now.
+    page_alloc_mut().set_top_limit(None);
+
+    // Kernel allocator memory is in the direct map too.
+    super::init_main_alloc();
+
+    // Video memory is no longer in the direct map.
+    ::early::map_text_buffer();
+
+    // Global pages are enabled only when boot mappings are gone. Space
+    // switches then keep kernel pages in TLB.
+    enable_global_pages();
+    pcid::setup();
 }
@@ -0,0 +1,124 @@
+use super::gdt::MAX_CPU_COUNT;
+use super::tlb::flush_global;
+use ints::cpu_id;
+
+/// Count of process-context identifiers. PCID is 12 bits of CR3.
+const PCID_COUNT: u16 = 4096;
+
+/// Bit of CR3 which tells processor to keep TLB entries of loaded PCID.
+pub const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// CR4 bit that enables PCID.
+const CR4_PCIDE: u64 = 1 << 17;
+
+/// CPUID.1:ECX bit that reports PCID support.
+const CPUID_PCID: u32 = 1 << 17;
+
+/// Whether PCID was enabled by `setup`.
+static mut ENABLED: bool = false;
+
+/// PCID allocator of single processor. PCIDs are given one by one and
+/// when they end all are recycled at once with new generation.
+#[derive(Clone, Copy)]
+struct CpuPcids {
+
+    /// Generation of given PCIDs. Incremented when PCIDs are recycled.
+    generation  : u64,
+
+    /// Next PCID to give. PCID 0 is used when PCID is not enabled and
+    /// is never given.
+    next        : u16,
+}
+
+/// PCID of the address space on single processor.
+#[derive(Clone, Copy)]
+pub struct PcidSlot {
+
+    /// PCID that space uses on the processor.
+    pcid        : u16,
+
+    /// Generation of the PCID. Zero if space has no valid PCID.
+    generation  : u64,
+}
+
+/// PCID allocators of all processors.
+static mut CPUS: [CpuPcids; MAX_CPU_COUNT] = [CpuPcids {
+    generation  : 1,
+    next        : 1,
+}; MAX_CPU_COUNT];
+
+impl PcidSlot {
+
+    /// Slot without PCID.
+    pub const fn new() -> Self {
+        PcidSlot {
+            pcid        : 0,
+            generation  : 0,
+        }
+    }
+
+    /// Drop the PCID so TLB entries of it are never used again. Space
+    /// gets new PCID on the next switch.
+    pub fn invalidate(&mut self) {
+        self.generation = 0;
+    }
+}
+
+/// Whether processor supports PCID.
+pub fn is_supported() -> bool {
+    let ecx: u32;
+    unsafe {
+        asm!("cpuid" : "={ecx}"(ecx) : "{eax}"(1u32) : "ebx", "edx"
+                : "volatile");
+    }
+    ecx & CPUID_PCID != 0
+}
+
+/// Whether address spaces are tagged with PCIDs.
+pub fn is_enabled() -> bool {
+    unsafe { ENABLED }
+}
+
+/// Enable PCID on current processor if it is supported. Loaded CR3 must
+/// have PCID 0.
+pub fn setup() {
+    if !is_supported() {
+        return;
+    }
+
+    unsafe {
+        asm!("mov %cr4, %rax
+              or $0, %rax
+              mov %rax, %cr4" : : "r"(CR4_PCIDE) : "rax" : "volatile");
+        ENABLED = true;
+    }
+}
+
+/// Value of CR3 that loads the space with given level 4 table on current
+/// processor. PCID of the space is kept and loaded without flush when
+/// it is of the current generation. Otherwise new PCID is given and is
+/// flushed on load.
+pub fn cr3_value(p4: u64, slots: &mut [PcidSlot; MAX_CPU_COUNT]) -> u64 {
+    if !is_enabled() {
+        return p4;
+    }
+
+    let cpu = cpu_id();
+    let state = unsafe { &mut CPUS[cpu] };
+    let slot = &mut slots[cpu];
+    if slot.generation == state.generation {
+        return p4 | slot.pcid as u64 | CR3_NO_FLUSH;
+    }
+
+    if state.next == PCID_COUNT {
+        // PCIDs of old generation may still have entries in TLB.
+        state.generation += 1;
+        state.next = 1;
+        unsafe { flush_global(); }
+    }
+
+    slot.pcid = state.next;
+    slot.generation = state.generation;
+    state.next += 1;
+    p4 | slot.pcid as u64
+}
@@ -0,0 +1,245 @@
+use super::space::*;
+use mem::{PhysAddr, VirtAddr};
+use early::logger;
+use core::fmt;
+use core::fmt::Write;
+
+/// PAT bit of the entry that maps 2MiB or 1GiB page. In other entries
+/// this bit is part of the address.
+const HUGE_PAT: u64 = 1 << 12;
+
+/// Flags that are compared when neighbour pages get merged. Accessed and
+/// dirty bits differ from page to page and are ignored.
+const MERGE_FLAGS: u64 = PAGE_FLAGS | HUGE | HUGE_PAT;
+
+/// Contiguous virtual memory that is mapped to contiguous physical memory
+/// with the same flags and page size.
+#[derive(Clone, Copy)]
+pub struct Range {
+
+    /// First byte of the range.
+    pub virt    : VirtAddr,
+
+    /// Physical address of the first byte.
+    pub phys    : PhysAddr,
+
+    /// Size of the range in bytes.
+    pub size    : u64,
+
+    /// Entry of the first page of the range without the address.
+    pub flags   : u64,
+
+    /// Size of the pages of the range.
+    pub page    : PageSize,
+}
+
+/// Entries that processor reads to translate single virtual address.
+#[derive(Clone, Copy)]
+pub struct Walk {
+
+    /// Entries of P4, P3, P2 and P1 tables in this order.
+    pub entries : [u64; 4],
+
+    /// Count of entries that were read. Last read entry is either not
+    /// present or maps a page.
+    pub count   : usize,
+}
+
+/// Display adapter that prints flags of the raw entry of the table of
+/// given level. Bit 7 is page size bit in level 2 and 3 tables and PAT
+/// bit in level 1 tables. PAT bit of 2MiB and 1GiB pages is bit 12.
+pub struct Flags(pub u64, pub u8);
+
+impl fmt::Display for Flags {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (bit7, pat) = match self.1 {
+            1                           => ("PAT", 0),
+            2 | 3 if self.0 & HUGE != 0 => ("PS", HUGE_PAT),
+            2 | 3                       => ("PS", 0),
+            _                           => ("", 0),
+        };
+        let names = [
+            (PRESENT,       "P"),
+            (WRITABLE,      "RW"),
+            (USER,          "US"),
+            (WRITE_THROUGH, "PWT"),
+            (CACHE_DISABLE, "PCD"),
+            (HUGE,          bit7),
+            (GLOBAL,        "G"),
+            (COPY_ON_WRITE, "COW"),
+            (pat,           "PAT"),
+            (NO_EXECUTE,    "NX"),
+        ];
+        let mut first = true;
+        for &(bit, name) in names.iter() {
+            if self.0 & bit != 0 && !name.is_empty() {
+                if !first {
+                    try!(f.write_str(" "));
+                }
+                try!(f.write_str(name));
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Range {
+
+    /// Whether the page directly continues the range.
+    fn continues(&self, virt: u64, phys: u64, flags: u64, page: PageSize)
+            -> bool {
+        self.virt.translated() + self.size == virt
+                && self.phys.as_u64() + self.size == phys
+                && self.flags & MERGE_FLAGS == flags & MERGE_FLAGS
+                && self.page == page
+    }
+}
+
+/// Entries of the table at given physical address.
+///
+/// # Safety
+/// Address must point to the paging table.
+unsafe fn entries<'a>(phys: u64) -> &'a [u64; ENTRIES] {
+    &*PhysAddr::new(phys).as_ptr::<[u64; ENTRIES]>()
+}
+
+/// Call the function for each page of the table and it's children.
+///
+/// # Safety
+/// Address must point to the paging table of given level.
+unsafe fn walk_table<F>(phys: u64, level: u8, base: u64, f: &mut F)
+        where F: FnMut(u64, u64, u64, PageSize) {
+    let size = level_size(level);
+    for (i, entry) in entries(phys).iter().enumerate() {
+        let entry = *entry;
+        if entry & PRESENT == 0 {
+            continue;
+        }
+
+        let virt = base + i as u64 * size;
+        if level == 1 || (level < 4 && entry & HUGE != 0) {
+            let page = PageSize::of_level(level);
+            let phys = entry & ADDR_MASK & !(size - 1);
+            f(virt, phys, entry & !phys, page);
+        } else {
+            walk_table(entry & ADDR_MASK, level - 1, virt, f);
+        }
+    }
+}
+
+/// Call the function for each range of merged pages of the hierarchy
+/// with given level 4 table. Ranges are given in order of addresses.
+///
+/// # Safety
+/// Address must point to the level 4 paging table.
+pub unsafe fn for_each_range<F>(p4: PhysAddr, mut f: F)
+        where F: FnMut(&Range) {
+    let mut current: Option<Range> = None;
+    walk_table(p4.as_u64(), 4, 0, &mut |virt, phys, flags, page| {
+        if let Some(ref mut range) = current {
+            if range.continues(virt, phys, flags, page) {
+                range.size += page.bytes();
+                return;
+            }
+            f(range);
+        }
+        current = Some(Range {
+            virt    : VirtAddr::new_truncate(virt),
+            phys    : PhysAddr::new(phys),
+            size    : page.bytes(),
+            flags   : flags,
+            page    : page,
+        });
+    });
+
+    if let Some(ref range) = current {
+        f(range);
+    }
+}
+
+/// Read entries that translate given address in the hierarchy with given
+/// level 4 table.
+///
+/// # Safety
+/// Address must point to the level 4 paging table.
+pub unsafe fn walk(p4: PhysAddr, virt: VirtAddr) -> Walk {
+    let mut walk = Walk {
+        entries : [0; 4],
+        count   : 0,
+    };
+    walk_entries(p4, virt.translated(), |_, entry| {
+        walk.entries[walk.count] = entry;
+        walk.count += 1;
+    });
+    walk
+}
+
+impl Walk {
+
+    /// Physical address that the walk resolved to. None if address is
+    /// not mapped.
+    pub fn phys(&self, virt: VirtAddr) -> Option<PhysAddr> {
+        let entry = self.entries[self.count - 1];
+        if entry & PRESENT == 0 {
+            return None;
+        }
+
+        let size = level_size(5 - self.count as u8);
+        let base = entry & ADDR_MASK & !(size - 1);
+        Some(PhysAddr::new(base + (virt.as_u64() & (size - 1))))
+    }
+}
+
+/// Print merged ranges of the hierarchy with given level 4 table.
+///
+/// # Safety
+/// Address must point to the level 4 paging table.
+pub unsafe fn dump<W: Write>(out: &mut W, p4: PhysAddr) -> fmt::Result {
+    let mut result = Ok(());
+    try!(write!(out, "Page tables at {:016X}:\n", p4.as_u64()));
+    for_each_range(p4, |range| {
+        if result.is_ok() {
+            result = write!(out, "  {:016X}-{:016X} -> {:016X} {} {}\n",
+                range.virt.as_u64(), range.virt.as_u64() + range.size - 1,
+                range.phys.as_u64(), range.page.name(),
+                Flags(range.flags, range.page.level()));
+        }
+    });
+    result
+}
+
+/// Print each entry that translates given address in the hierarchy with
+/// given level 4 table.
+///
+/// # Safety
+/// Address must point to the level 4 paging table.
+pub unsafe fn dump_translation<W: Write>(out: &mut W, p4: PhysAddr,
+        virt: VirtAddr) -> fmt::Result {
+    let walk = walk(p4, virt);
+    try!(write!(out, "Translation of {:016X}:\n", virt.as_u64()));
+    for i in 0..walk.count {
+        let level = 4 - i as u8;
+        let entry = walk.entries[i];
+        try!(write!(out, "  P{}[{:3}] = {:016X} {}\n", level,
+            index(virt.translated(), level), entry,
+            Flags(entry, level)));
+    }
+
+    match walk.phys(virt) {
+        Some(phys)  => write!(out, "  -> {:016X}\n", phys.as_u64()),
+        None        => write!(out, "  not mapped\n"),
+    }
+}
+
+/// Print merged ranges of the address space on the kernel logger.
+pub fn log_space(space: &AddressSpace) {
+    unsafe { dump(logger(), space.p4_addr()).unwrap(); }
+}
+
+/// Print translation of the address in the address space on the kernel
+/// logger.
+pub fn log_translation(space: &AddressSpace, virt: VirtAddr) {
+    unsafe { dump_translation(logger(), space.p4_addr(), virt).unwrap(); }
+}
@@ -0,0 +1,357 @@
+use core::marker::PhantomData;
+use core::mem::{size_of, align_of};
+use core::ptr::null_mut;
+use super::alloc::FrameAlloc;
+use super::alloc::frame::FRAME_SIZE;
+use super::heap::{frame_ptr, frame_phys};
+use super::page_alloc_mut;
+use super::stats::{self, Subsystem};
+
+/// Hook that is called for the object of the cache. Constructor is called
+/// right after the object was placed in the cache and destructor right
+/// before the object gets removed from it.
+pub type ObjectHook<T> = fn(obj: &mut T);
+
+/// Object that is free. Free objects of the slab are linked in the list.
+struct FreeObject {
+    next    : *mut FreeObject,
+}
+
+/// Header of 4KiB slab page. Objects are placed after the header in slots
+/// of equal size.
+struct Slab {
+
+    /// Next slab of the cache with free objects.
+    next    : *mut Slab,
+
+    /// Previous slab of the cache with free objects.
+    prev    : *mut Slab,
+
+    /// First free slot of the slab.
+    free    : *mut FreeObject,
+
+    /// Count of allocated objects.
+    used    : u32,
+
+    /// Count of objects that slab can hold.
+    total   : u32,
+}
+
+/// Slabs of slots of single size. Used by both object caches and size
+/// classes of the kernel heap. Slot size and alignment are given by the
+/// owner on each allocation and must never change.
+pub struct SlabCache {
+
+    /// Slabs with free slots.
+    partial : *mut Slab,
+
+    /// Count of slabs of this cache.
+    slabs   : usize,
+}
+
+/// Usage statistics of the object cache.
+#[derive(Clone, Copy)]
+pub struct CacheStats {
+
+    /// Size of the slot of single object including alignment padding.
+    pub slot_size   : usize,
+
+    /// Count of objects that were ever placed in the cache.
+    pub allocated   : usize,
+
+    /// Count of objects that were ever removed from the cache.
+    pub released    : usize,
+
+    /// Count of objects that are currently in the cache.
+    pub in_use      : usize,
+
+    /// Maximal count of objects that were in the cache at once.
+    pub peak        : usize,
+
+    /// Count of slabs (4KiB frames) that cache holds.
+    pub slabs       : usize,
+
+    /// Count of allocations that failed because of lack of memory.
+    pub failed      : usize,
+}
+
+/// Cache of objects of single type. Objects are stored in 4KiB slabs that
+/// are taken from the page allocator when cache grows and are given back
+/// when they get empty.
+pub struct ObjectCache<T> {
+
+    /// Name of the cache to print in statistics.
+    name    : &'static str,
+
+    /// Hook called for each new object.
+    ctor    : Option<ObjectHook<T>>,
+
+    /// Hook called for each object that is removed.
+    dtor    : Option<ObjectHook<T>>,
+
+    /// Slabs of the objects.
+    cache   : SlabCache,
+
+    /// Usage statistics.
+    stats   : CacheStats,
+
+    _marker : PhantomData<T>,
+}
+
+impl Slab {
+
+    /// Slab that contains given object.
+    fn of(ptr: *mut u8) -> *mut Slab {
+        (ptr as usize & !(FRAME_SIZE as usize - 1)) as *mut Slab
+    }
+
+    /// Whether all objects are allocated.
+    fn is_full(&self) -> bool {
+        self.used == self.total
+    }
+}
+
+impl SlabCache {
+
+    /// Create cache without slabs.
+    pub const fn new() -> Self {
+        SlabCache {
+            partial : 0 as *mut Slab,
+            slabs   : 0,
+        }
+    }
+
+    /// Count of slabs (4KiB frames) that cache holds.
+    pub fn slabs(&self) -> usize {
+        self.slabs
+    }
+
+    /// Offset of the first slot in the slab with given slot alignment.
+    fn first_offset(align: usize) -> usize {
+        (size_of::<Slab>() + align - 1) / align * align
+    }
+
+    /// Add slab to the list of slabs with free objects.
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    /// Remove slab from the list of slabs with free objects.
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+    }
+
+    /// Take new frame from page allocator and make a slab in it with all
+    /// slots free.
+    unsafe fn grow(&mut self, size: usize, align: usize) -> bool {
+        let frame = match page_alloc_mut().alloc_frame() {
+            Ok(frame)   => frame_ptr(frame),
+            Err(_)      => return false,
+        };
+
+        let first = Self::first_offset(align);
+        let total = (FRAME_SIZE as usize - first) / size;
+        assert!(total > 0, "Object does not fit the slab");
+
+        // Link all slots into free list.
+        let mut free = null_mut();
+        let mut i = total;
+        while i > 0 {
+            i -= 1;
+            let obj = frame.offset((first + i * size) as _) as *mut FreeObject;
+            (*obj).next = free;
+            free = obj;
+        }
+
+        let slab = frame as *mut Slab;
+        *slab = Slab {
+            next    : null_mut(),
+            prev    : null_mut(),
+            free    : free,
+            used    : 0,
+            total   : total as u32,
+        };
+        self.push(slab);
+        self.slabs += 1;
+        true
+    }
+
+    /// Take free slot of given size and alignment. Slot must be able to
+    /// store a pointer. Returns null if there is no memory for new slab.
+    pub unsafe fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        if self.partial.is_null() && !self.grow(size, align) {
+            return null_mut();
+        }
+
+        let slab = self.partial;
+        let obj = (*slab).free;
+        (*slab).free = (*obj).next;
+        (*slab).used += 1;
+        if (*slab).is_full() {
+            self.remove(slab);
+        }
+        obj as *mut u8
+    }
+
+    /// Give the slot back. Slab is released when it gets empty and cache
+    /// has other slabs with free slots, so allocation right after release
+    /// does not need new frame.
+    ///
+    /// # Safety
+    /// Slot must be taken from this cache.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let slab = Slab::of(ptr);
+        let was_full = (*slab).is_full();
+
+        let free = ptr as *mut FreeObject;
+        (*free).next = (*slab).free;
+        (*slab).free = free;
+        (*slab).used -= 1;
+
+        if was_full {
+            self.push(slab);
+        }
+
+        let other = self.partial != slab || !(*slab).next.is_null();
+        if (*slab).used == 0 && other {
+            self.remove(slab);
+            self.slabs -= 1;
+            let _ = page_alloc_mut().release_frame(frame_phys(slab as _));
+        }
+    }
+}
+
+impl CacheStats {
+
+    const fn new() -> Self {
+        CacheStats {
+            slot_size   : 0,
+            allocated   : 0,
+            released    : 0,
+            in_use      : 0,
+            peak        : 0,
+            slabs       : 0,
+            failed      : 0,
+        }
+    }
+
+    /// Count of bytes of frames that are taken by the cache.
+    pub fn frames_size(&self) -> usize {
+        self.slabs * FRAME_SIZE as usize
+    }
+}
+
+impl<T> ObjectCache<T> {
+
+    /// Create empty cache with given hooks. No memory is taken until the
+    /// first object is placed in the cache.
+    pub const fn new(name: &'static str, ctor: Option<ObjectHook<T>>,
+            dtor: Option<ObjectHook<T>>) -> Self {
+        ObjectCache {
+            name    : name,
+            ctor    : ctor,
+            dtor    : dtor,
+            cache   : SlabCache::new(),
+            stats   : CacheStats::new(),
+            _marker : PhantomData,
+        }
+    }
+
+    /// Name of the cache.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Usage statistics of the cache.
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = self.stats;
+        stats.slot_size = Self::slot_size();
+        stats.slabs = self.cache.slabs();
+        stats
+    }
+
+    /// Alignment of the object slots.
+    fn slot_align() -> usize {
+        let align = align_of::<T>();
+        if align > align_of::<FreeObject>() {
+            align
+        } else {
+            align_of::<FreeObject>()
+        }
+    }
+
+    /// Size of the slot of single object. Slot must be able to store
+    /// free list link when object is not allocated.
+    fn slot_size() -> usize {
+        let size = if size_of::<T>() > size_of::<FreeObject>() {
+            size_of::<T>()
+        } else {
+            size_of::<FreeObject>()
+        };
+        let align = Self::slot_align();
+        (size + align - 1) / align * align
+    }
+
+    /// Place the object in the cache. Constructor hook is called for the
+    /// placed object. Returns None if there is no memory for new slab.
+    pub fn insert(&mut self, val: T) -> Option<*mut T> {
+        unsafe {
+            let slabs = self.cache.slabs();
+            let obj = self.cache.alloc(Self::slot_size(), Self::slot_align());
+            if obj.is_null() {
+                self.stats.failed += 1;
+                return None;
+            }
+            if self.cache.slabs() > slabs {
+                stats::charge(Subsystem::Slabs, 1);
+            }
+
+            let obj = obj as *mut T;
+            ::core::ptr::write(obj, val);
+            if let Some(ctor) = self.ctor {
+                ctor(&mut *obj);
+            }
+
+            self.stats.allocated += 1;
+            self.stats.in_use += 1;
+            if self.stats.in_use > self.stats.peak {
+                self.stats.peak = self.stats.in_use;
+            }
+            Some(obj)
+        }
+    }
+
+    /// Remove the object from the cache and drop it. Destructor hook is
+    /// called before the object is dropped. Slab is released when it
+    /// gets empty and cache has other slabs with free objects.
+    ///
+    /// # Safety
+    /// Object must be placed in this cache and must not be used after
+    /// this call.
+    pub unsafe fn remove(&mut self, obj: *mut T) {
+        if let Some(dtor) = self.dtor {
+            dtor(&mut *obj);
+        }
+        ::core::ptr::drop_in_place(obj);
+
+        let slabs = self.cache.slabs();
+        self.cache.dealloc(obj as *mut u8);
+        if self.cache.slabs() < slabs {
+            stats::uncharge(Subsystem::Slabs, 1);
+        }
+        self.stats.released += 1;
+        self.stats.in_use -= 1;
+    }
+}
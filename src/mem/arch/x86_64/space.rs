@@ -0,0 +1,981 @@
+use super::alloc::FrameAlloc;
+use super::alloc::frame::{ORDER_2M, MAX_ORDER};
+use super::page_alloc_mut;
+use super::area::AreaList;
+use super::stats::{self, Subsystem};
+use super::tlb::{self, FlushBatch, BATCH_SIZE};
+use super::pcid::{self, PcidSlot};
+use super::gdt::MAX_CPU_COUNT;
+use super::paging::kernel_space;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mem::{PhysAddr, VirtAddr};
+use arch::tables::paging::PageFlag;
+use arch::cr::{Cr3, Reg};
+
+/// Entry is present and maps a page or a table.
+pub const PRESENT: u64 = 1 << 0;
+
+/// Page is writable.
+pub const WRITABLE: u64 = 1 << 1;
+
+/// Page is accessible from user mode.
+pub const USER: u64 = 1 << 2;
+
+/// Writes go directly to memory.
+pub const WRITE_THROUGH: u64 = 1 << 3;
+
+/// Page is not cached.
+pub const CACHE_DISABLE: u64 = 1 << 4;
+
+/// Set by processor when page is accessed.
+pub const ACCESSED: u64 = 1 << 5;
+
+/// Set by processor when page is written.
+pub const DIRTY: u64 = 1 << 6;
+
+/// Entry of level 2 or 3 table maps 2MiB or 1GiB page instead of a table.
+pub const HUGE: u64 = 1 << 7;
+
+/// Translation is not flushed from TLB on address space switch.
+pub const GLOBAL: u64 = 1 << 8;
+
+/// Page is shared in copy-on-write mode. Processor ignores this bit. Such
+/// pages are read-only and get copied on the first write.
+pub const COPY_ON_WRITE: u64 = 1 << 9;
+
+/// Code can not be executed from the page.
+pub const NO_EXECUTE: u64 = 1 << 63;
+
+/// Flags that caller is allowed to set for mapped pages.
+pub const PAGE_FLAGS: u64 = WRITABLE | USER | WRITE_THROUGH | CACHE_DISABLE
+        | GLOBAL | COPY_ON_WRITE | NO_EXECUTE;
+
+/// Bits of the entry that store physical address.
+pub const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Count of entries in each table.
+pub const ENTRIES: usize = 512;
+
+/// Index of the first level 4 entry of the upper half of virtual memory.
+/// Tables of the upper half belong to the kernel space and are shared by
+/// all other spaces.
+const KERNEL_HALF: usize = 256;
+
+/// Bit of EDX of CPUID function 80000001h that shows whether processor
+/// supports 1GiB pages.
+const CPUID_PAGE_1G: u32 = 1 << 26;
+
+/// Bit of EDX of CPUID function 80000001h that shows whether processor
+/// supports No-Execute bit.
+const CPUID_NX: u32 = 1 << 20;
+
+pub type MapResult<T> = ::core::result::Result<T, MapError>;
+
+/// Errors that can occur when address space gets changed.
+pub enum MapError {
+
+    /// No memory for new paging table.
+    NoMemory,
+
+    /// Some page of the range is already mapped.
+    AlreadyMapped,
+
+    /// Some page of the range is not mapped.
+    NotMapped,
+
+    /// Address or size is not aligned to 4KiB page boundary.
+    Unaligned,
+
+    /// Some page of the range is a huge page that the operation cannot
+    /// be applied to.
+    HugePage,
+
+    /// No free range of virtual memory of requested size.
+    NoVirtualSpace,
+}
+
+/// Sizes of the pages that can be mapped.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PageSize {
+    Size4k,
+    Size2m,
+    Size1g,
+}
+
+/// Result of translation of virtual address.
+#[derive(Clone, Copy)]
+pub struct Translation {
+
+    /// Physical address that virtual address is mapped to.
+    pub phys    : PhysAddr,
+
+    /// Size of the page that contains the address.
+    pub size    : PageSize,
+
+    /// Flags of the page.
+    pub flags   : u64,
+}
+
+/// Address space that is loaded in CR3. Null until some space gets
+/// activated.
+static mut CURRENT: *mut AddressSpace = 0 as *mut AddressSpace;
+
+/// Whether `map` may use 1GiB pages. Set by `detect_features`.
+static mut PAGE_1G: bool = false;
+
+/// Flags that are never written to entries as processor does not support
+/// them. Set by `detect_features`.
+static mut UNSUPPORTED: u64 = 0;
+
+/// Change that is applied to each page of the range.
+#[derive(Clone, Copy, PartialEq)]
+enum Change {
+
+    /// Page is unmapped.
+    Unmap,
+
+    /// Flags of the page are replaced.
+    Replace(u64),
+
+    /// Given flags are cleared, other flags are kept.
+    Clear(u64),
+}
+
+/// Paging table of any level.
+struct Table {
+    entries : [u64; ENTRIES],
+}
+
+/// Virtual address space. Owns level 4 paging table and all intermediate
+/// tables of the space. Tables are taken from the frame allocator and
+/// are released when they get empty.
+pub struct AddressSpace {
+
+    /// Physical address of level 4 table.
+    p4      : u64,
+
+    /// Reserved areas of the space which pages get mapped on first access.
+    areas   : AreaList,
+
+    /// Processors that have this space loaded. Their TLBs must be flushed
+    /// when mapping changes.
+    active  : AtomicUsize,
+
+    /// PCID of the space on each processor.
+    pcids   : [PcidSlot; MAX_CPU_COUNT],
+}
+
+impl PageSize {
+
+    /// Size of the page in bytes.
+    pub fn bytes(&self) -> u64 {
+        match *self {
+            PageSize::Size4k => 0x1000,
+            PageSize::Size2m => 0x200000,
+            PageSize::Size1g => 0x40000000,
+        }
+    }
+
+    /// Short name of the size to print.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PageSize::Size4k => "4K",
+            PageSize::Size2m => "2M",
+            PageSize::Size1g => "1G",
+        }
+    }
+
+    /// Order of the block of frames that page of this size takes.
+    pub fn order(&self) -> u8 {
+        match *self {
+            PageSize::Size4k => 0,
+            PageSize::Size2m => ORDER_2M,
+            PageSize::Size1g => MAX_ORDER,
+        }
+    }
+
+    /// Level of the table which entries map pages of this size.
+    pub fn level(&self) -> u8 {
+        match *self {
+            PageSize::Size4k => 1,
+            PageSize::Size2m => 2,
+            PageSize::Size1g => 3,
+        }
+    }
+
+    /// Size of the pages that entries of given table level map.
+    pub fn of_level(level: u8) -> Self {
+        match level {
+            1 => PageSize::Size4k,
+            2 => PageSize::Size2m,
+            3 => PageSize::Size1g,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Size of memory that one entry of the table of given level covers.
+pub fn level_size(level: u8) -> u64 {
+    0x1000 << (9 * (level as u64 - 1))
+}
+
+/// Index of the entry that maps given address in the table of given level.
+pub fn index(virt: u64, level: u8) -> usize {
+    ((virt >> (12 + 9 * (level as u64 - 1))) & 0x1FF) as usize
+}
+
+/// Read entries that translate given address in the hierarchy with given
+/// level 4 table. Function gets level and value of each read entry.
+/// Returns the last read entry and it's level. The entry is either not
+/// present or maps a page.
+///
+/// # Safety
+/// Address must point to the level 4 paging table.
+pub unsafe fn walk_entries<F>(p4: PhysAddr, virt: u64, mut f: F) -> (u64, u8)
+        where F: FnMut(u8, u64) {
+    let mut phys = p4.as_u64();
+    let mut level = 4;
+    loop {
+        let entry = table(phys).entries[index(virt, level)];
+        f(level, entry);
+        if entry & PRESENT == 0 || level == 1
+                || (level < 4 && entry & HUGE != 0) {
+            return (entry, level);
+        }
+
+        phys = entry & ADDR_MASK;
+        level -= 1;
+    }
+}
+
+/// Table at given physical address.
+///
+/// # Safety
+/// Address must point to the paging table.
+unsafe fn table<'a>(phys: u64) -> &'a mut Table {
+    &mut *PhysAddr::new(phys).as_mut_ptr::<Table>()
+}
+
+/// Entry that maps page at given physical address with given flags.
+fn page_entry(phys: u64, flags: u64) -> u64 {
+    let entry =
+        PageFlag::from(phys)    |
+        PageFlag::from(flags)   |
+        PageFlag::present   ()  ;
+    entry.into()
+}
+
+/// Entry that points to the table at given physical address. Access rights
+/// of each level are combined so intermediate tables allow everything and
+/// only the last entry defines rights of the page.
+fn table_entry(phys: u64) -> u64 {
+    let entry =
+        PageFlag::from(phys)    |
+        PageFlag::from(USER)    |
+        PageFlag::rw        ()  |
+        PageFlag::present   ()  ;
+    entry.into()
+}
+
+/// Check whether processor supports 1GiB pages and No-Execute bit. Until
+/// this call `map` uses only 4KiB and 2MiB pages. No-Execute flag given
+/// for new entries is dropped when processor does not support it.
+pub fn detect_features() {
+    let edx: u32;
+    unsafe {
+        asm!("cpuid" : "={edx}"(edx) : "{eax}"(0x80000001u32) : "ebx", "ecx"
+                : "volatile");
+        PAGE_1G = edx & CPUID_PAGE_1G != 0;
+        if edx & CPUID_NX == 0 {
+            UNSUPPORTED |= NO_EXECUTE;
+        }
+    }
+}
+
+/// Whether processor supports No-Execute bit. Valid after
+/// `detect_features` call.
+pub fn is_nx_supported() -> bool {
+    unsafe { UNSUPPORTED & NO_EXECUTE == 0 }
+}
+
+/// Flags of the entry that can be set from flags given by the caller.
+fn entry_flags(flags: u64) -> u64 {
+    flags & PAGE_FLAGS & !unsafe { UNSUPPORTED }
+}
+
+/// Allocate new zeroed table.
+fn alloc_table() -> MapResult<u64> {
+    let phys = match page_alloc_mut().alloc_frame() {
+        Ok(phys)    => phys,
+        Err(_)      => return Err(MapError::NoMemory),
+    };
+
+    unsafe {
+        use core::ptr::write_bytes;
+        write_bytes(phys.as_mut_ptr::<Table>(), 0, 1);
+    }
+    stats::charge(Subsystem::PageTables, 1);
+    Ok(phys.as_u64())
+}
+
+/// Return table back to frame allocator.
+///
+/// # Safety
+/// Table must not be used by any entry.
+unsafe fn free_table(phys: u64) {
+    let _ = page_alloc_mut().release_frame(PhysAddr::new(phys));
+    stats::uncharge(Subsystem::PageTables, 1);
+}
+
+/// Drop one reference to the frame that was mapped with page of given
+/// size. Frame is returned to the frame allocator when it has no other
+/// users.
+///
+/// # Panics
+/// When the frame was not given by the frame allocator as a block of
+/// this size.
+fn drop_frame(phys: PhysAddr, size: PageSize) {
+    let alloc = page_alloc_mut();
+    let last = match alloc.status_mut(phys) {
+        Some(status) => {
+            if status.use_count() > 1 {
+                status.dec_user();
+                false
+            } else {
+                true
+            }
+        },
+        None => panic!("Dropped frame that is not known to allocator"),
+    };
+
+    if last {
+        let result = unsafe { alloc.release_contiguous(phys, size.order()) };
+        if result.is_err() {
+            panic!("Dropped frame that was not allocated");
+        }
+    }
+}
+
+impl Table {
+
+    /// Whether no entry of the table is present.
+    fn is_empty(&self) -> bool {
+        for entry in self.entries.iter() {
+            if *entry & PRESENT != 0 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl AddressSpace {
+
+    /// Create address space with empty lower half. Upper half shares the
+    /// tables of the kernel space so kernel mappings, kernel stacks
+    /// included, are the same in each space. Kernel paging must be set.
+    ///
+    /// # Errors
+    /// NoMemory error occurs when level 4 table cannot be allocated.
+    pub fn new() -> MapResult<Self> {
+        let space = try!(Self::empty());
+        unsafe {
+            let kernel = &table(kernel_space().p4).entries;
+            let entries = &mut table(space.p4).entries;
+            entries[KERNEL_HALF..].copy_from_slice(&kernel[KERNEL_HALF..]);
+        }
+        Ok(space)
+    }
+
+    /// Create the kernel space. Level 3 tables of the whole upper half are
+    /// allocated now so the entries of level 4 table never change and can
+    /// be copied to other spaces.
+    ///
+    /// # Errors
+    /// NoMemory error occurs when some table cannot be allocated.
+    pub fn new_kernel() -> MapResult<Self> {
+        let space = try!(Self::empty());
+        for i in KERNEL_HALF..ENTRIES {
+            let p3 = try!(alloc_table());
+            unsafe { table(space.p4).entries[i] = table_entry(p3); }
+        }
+        Ok(space)
+    }
+
+    /// Create space with empty level 4 table.
+    fn empty() -> MapResult<Self> {
+        Ok(AddressSpace {
+            p4      : try!(alloc_table()),
+            areas   : AreaList::new(),
+            active  : AtomicUsize::new(0),
+            pcids   : [PcidSlot::new(); MAX_CPU_COUNT],
+        })
+    }
+
+    /// Address space that is currently loaded in CR3, if it was loaded
+    /// by `activate`.
+    pub fn current() -> Option<&'static mut AddressSpace> {
+        unsafe {
+            if CURRENT.is_null() {
+                None
+            } else {
+                Some(&mut *CURRENT)
+            }
+        }
+    }
+
+    /// Reserved areas of the space.
+    pub fn areas(&self) -> &AreaList {
+        &self.areas
+    }
+
+    /// Reserved areas of the space.
+    pub fn areas_mut(&mut self) -> &mut AreaList {
+        &mut self.areas
+    }
+
+    /// Physical address of level 4 table. This value is loaded into CR3
+    /// to switch to this address space.
+    pub fn p4_addr(&self) -> PhysAddr {
+        PhysAddr::new(self.p4)
+    }
+
+    /// Count of paging tables of the space including level 4 table.
+    /// Tables of the kernel half are shared by all spaces and are not
+    /// counted.
+    pub fn table_count(&self) -> usize {
+        unsafe fn count(phys: u64, level: u8) -> usize {
+            let mut sum = 1;
+            if level > 1 {
+                for entry in table(phys).entries.iter() {
+                    if *entry & PRESENT != 0 && *entry & HUGE == 0 {
+                        sum += count(*entry & ADDR_MASK, level - 1);
+                    }
+                }
+            }
+            sum
+        }
+
+        let mut sum = 1;
+        unsafe {
+            for entry in table(self.p4).entries[..KERNEL_HALF].iter() {
+                if *entry & PRESENT != 0 {
+                    sum += count(*entry & ADDR_MASK, 3);
+                }
+            }
+        }
+        sum
+    }
+
+    /// Whether this address space is currently loaded in CR3.
+    pub fn is_active(&self) -> bool {
+        let cr3: u64 = Cr3::read().into();
+        cr3 & ADDR_MASK == self.p4
+    }
+
+    /// Load this address space into CR3.
+    ///
+    /// # Safety
+    /// Currently executed code and data must be mapped in this space.
+    /// Space must not be moved or dropped while it is active.
+    pub unsafe fn activate(&mut self) {
+        let bit = tlb::cpu_bit(::ints::cpu_id());
+        if !CURRENT.is_null() {
+            (*CURRENT).active.fetch_and(!bit, Ordering::SeqCst);
+        }
+        self.active.fetch_or(bit, Ordering::SeqCst);
+        tlb::set_online();
+
+        // TLB entries of the space are kept when it's PCID is still valid.
+        let cr3 = pcid::cr3_value(self.p4, &mut self.pcids);
+        Cr3::from(cr3).save();
+        CURRENT = self;
+    }
+
+    /// Processors that have this space loaded.
+    pub fn active_set(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Invalidate changed pages on all processors that use this space.
+    /// Processors that do not have the space loaded may still keep the
+    /// entries tagged with it's PCID so the PCID is dropped there.
+    fn shootdown(&mut self, batch: &FlushBatch) {
+        if batch.is_empty() {
+            return;
+        }
+
+        let active = self.active_set();
+        for cpu in 0..MAX_CPU_COUNT {
+            if active & tlb::cpu_bit(cpu) == 0 {
+                self.pcids[cpu].invalidate();
+            }
+        }
+        tlb::shootdown(active, batch);
+    }
+
+    /// Map range of virtual memory to given physical memory. Biggest pages
+    /// that alignment of addresses allows are used. 1GiB pages are used
+    /// only when processor supports them.
+    ///
+    /// # Errors
+    /// Unaligned error occurs when addresses or size are not aligned to
+    /// 4KiB. AlreadyMapped error occurs when some page of the range is
+    /// already mapped. NoMemory error occurs when paging table cannot be
+    /// allocated. No new pages stay mapped on error.
+    pub fn map(&mut self, virt: VirtAddr, phys: PhysAddr, size: u64,
+            flags: u64) -> MapResult<()> {
+        let start = virt;
+        let virt = virt.as_u64();
+        let phys = phys.as_u64();
+        if (virt | phys | size) & 0xFFF != 0 {
+            return Err(MapError::Unaligned);
+        }
+
+        let mut done = 0;
+        while done < size {
+            let left = size - done;
+            let v = virt.wrapping_add(done);
+            let p = phys + done;
+
+            let mut page = if unsafe { PAGE_1G } {
+                PageSize::Size1g
+            } else {
+                PageSize::Size2m
+            };
+            while (v | p) & (page.bytes() - 1) != 0 || left < page.bytes() {
+                page = PageSize::of_level(page.level() - 1);
+            }
+
+            let (v, p) = (VirtAddr::new(v), PhysAddr::new(p));
+            if let Err(e) = self.map_page(v, p, page, flags) {
+                let _ = self.unmap(start, done);
+                return Err(e);
+            }
+            done += page.bytes();
+        }
+
+        Ok(())
+    }
+
+    /// Map single page of given size.
+    ///
+    /// # Errors
+    /// Unaligned error occurs when addresses are not aligned to the page
+    /// size. AlreadyMapped error occurs when some memory of the page is
+    /// already mapped. NoMemory error occurs when paging table cannot be
+    /// allocated.
+    pub fn map_page(&mut self, virt: VirtAddr, phys: PhysAddr,
+            page: PageSize, flags: u64) -> MapResult<()> {
+        let virt = virt.as_u64();
+        let phys = phys.as_u64();
+        if (virt | phys) & (page.bytes() - 1) != 0 {
+            return Err(MapError::Unaligned);
+        }
+
+        let level = page.level();
+        let entry = try!(self.create_entry(virt, level));
+        if *entry & PRESENT != 0 {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        let huge = if level > 1 { HUGE } else { 0 };
+        *entry = page_entry(phys, entry_flags(flags) | huge);
+        Ok(())
+    }
+
+    /// Unmap range of virtual memory. Pages that are only partially
+    /// covered by the range are split. Memory that is not mapped is
+    /// skipped. Tables that get empty are released.
+    ///
+    /// # Errors
+    /// Unaligned error occurs when address or size are not aligned to
+    /// 4KiB. NoMemory error occurs when table for split page cannot be
+    /// allocated.
+    pub fn unmap(&mut self, virt: VirtAddr, size: u64) -> MapResult<()> {
+        if (virt.as_u64() | size) & 0xFFF != 0 {
+            return Err(MapError::Unaligned);
+        }
+
+        let result = self.update(virt.as_u64(), size, Change::Unmap);
+
+        // Addresses of the upper half are sign extended. Tables are walked
+        // with the bits that paging actually translates.
+        let start = virt.translated();
+        let p4 = self.p4;
+        unsafe { self.prune(p4, 4, 0, start, start + size); }
+        result
+    }
+
+    /// Unmap range of virtual memory and drop references to the frames of
+    /// fully covered pages. Frame is released when this space was the last
+    /// user of it.
+    ///
+    /// # Errors
+    /// Same as for `unmap`.
+    pub fn unmap_release(&mut self, virt: VirtAddr, size: u64)
+            -> MapResult<()> {
+        if (virt.as_u64() | size) & 0xFFF != 0 {
+            return Err(MapError::Unaligned);
+        }
+
+        // Frames are dropped only after they were unmapped on all
+        // processors. Range is unmapped in parts so that frames of each
+        // part fit the array.
+        let mut frames = [(PhysAddr::new(0), PageSize::Size4k); BATCH_SIZE];
+        let mut count = 0;
+        let mut part = 0;
+        let mut off = 0;
+        while off < size {
+            let addr = virt + off;
+            let mut step = 0x1000;
+            if let Some(tr) = self.translate(addr) {
+                let bytes = tr.size.bytes();
+                if addr.is_aligned(bytes) && size - off >= bytes {
+                    frames[count] = (tr.phys, tr.size);
+                    count += 1;
+                    step = bytes;
+                }
+            }
+            off += step;
+
+            if count == BATCH_SIZE || off >= size {
+                let result = self.unmap(virt + part, off - part);
+                for &(phys, page) in frames[..count].iter() {
+                    drop_frame(phys, page);
+                }
+                try!(result);
+                count = 0;
+                part = off;
+            }
+        }
+        Ok(())
+    }
+
+    /// Share pages of the range with other address space in copy-on-write
+    /// mode. Writable pages become read-only in both spaces and get copied
+    /// on the first write. Usage counter of each shared frame is
+    /// incremented. Usage is counted per allocated block so only ranges
+    /// mapped with 4KiB pages can be shared.
+    ///
+    /// # Errors
+    /// Unaligned error occurs when addresses or size are not aligned to
+    /// 4KiB. NotMapped error occurs when some page of the range is not
+    /// mapped in this space. HugePage error occurs when some page of the
+    /// range is a huge page. AlreadyMapped error occurs when some page is
+    /// already mapped in other space. NoMemory error occurs when paging
+    /// table cannot be allocated. Pages that were shared before the error
+    /// stay shared.
+    pub fn share_cow(&mut self, virt: VirtAddr, size: u64,
+            other: &mut AddressSpace, other_virt: VirtAddr) -> MapResult<()> {
+        if (virt.as_u64() | other_virt.as_u64() | size) & 0xFFF != 0 {
+            return Err(MapError::Unaligned);
+        }
+
+        let mut batch = FlushBatch::new();
+        let result = self.share_pages(virt, size, other, other_virt,
+                &mut batch);
+        self.shootdown(&batch);
+        result
+    }
+
+    /// Share pages of the range and collect pages that became read-only.
+    fn share_pages(&mut self, virt: VirtAddr, size: u64,
+            other: &mut AddressSpace, other_virt: VirtAddr,
+            batch: &mut FlushBatch) -> MapResult<()> {
+        let mut off = 0;
+        while off < size {
+            let addr = virt.as_u64().wrapping_add(off);
+            let (entry, level) = match self.find_entry(addr) {
+                Ok(found)   => found,
+                Err(_)      => return Err(MapError::NotMapped),
+            };
+
+            if level > 1 {
+                return Err(MapError::HugePage);
+            }
+
+            let phys = *entry & ADDR_MASK;
+            let mut flags = *entry & PAGE_FLAGS;
+            if flags & WRITABLE != 0 {
+                flags = flags & !WRITABLE | COPY_ON_WRITE;
+                *entry = page_entry(phys, flags);
+                batch.add(addr);
+            }
+
+            let dst = other_virt + off;
+            let phys = PhysAddr::new(phys);
+            try!(other.map_page(dst, phys, PageSize::Size4k, flags));
+            if let Some(status) = page_alloc_mut().status_mut(phys) {
+                status.inc_user();
+            }
+            off += 0x1000;
+        }
+
+        Ok(())
+    }
+
+    /// Replace frame and flags of the page that is mapped with 4KiB page.
+    ///
+    /// # Errors
+    /// Unaligned error occurs when addresses are not aligned to 4KiB.
+    /// NotMapped error occurs when address is not mapped with 4KiB page.
+    pub fn remap_page(&mut self, virt: VirtAddr, phys: PhysAddr, flags: u64)
+            -> MapResult<()> {
+        let virt = virt.as_u64();
+        let phys = phys.as_u64();
+        if (virt | phys) & 0xFFF != 0 {
+            return Err(MapError::Unaligned);
+        }
+
+        match self.find_entry(virt) {
+            Ok((entry, 1)) => *entry = page_entry(phys, entry_flags(flags)),
+            _ => return Err(MapError::NotMapped),
+        }
+
+        let mut batch = FlushBatch::new();
+        batch.add(virt);
+        self.shootdown(&batch);
+        Ok(())
+    }
+
+    /// Change flags of all pages of the range. Pages that are only
+    /// partially covered by the range are split.
+    ///
+    /// # Errors
+    /// Unaligned error occurs when address or size are not aligned to
+    /// 4KiB. NotMapped error occurs when some memory of the range is not
+    /// mapped. NoMemory error occurs when table for split page cannot be
+    /// allocated.
+    pub fn protect(&mut self, virt: VirtAddr, size: u64, flags: u64)
+            -> MapResult<()> {
+        if (virt.as_u64() | size) & 0xFFF != 0 {
+            return Err(MapError::Unaligned);
+        }
+
+        self.update(virt.as_u64(), size, Change::Replace(flags))
+    }
+
+    /// Clear given flags of all pages of the range. Other flags, like
+    /// cache type and global bit, are kept. Pages that are only partially
+    /// covered by the range are split.
+    ///
+    /// # Errors
+    /// Same as for `protect`.
+    pub fn clear_flags(&mut self, virt: VirtAddr, size: u64, flags: u64)
+            -> MapResult<()> {
+        if (virt.as_u64() | size) & 0xFFF != 0 {
+            return Err(MapError::Unaligned);
+        }
+
+        self.update(virt.as_u64(), size, Change::Clear(flags))
+    }
+
+    /// Physical address and page information of given virtual address.
+    /// None if address is not mapped.
+    pub fn translate(&self, virt: VirtAddr) -> Option<Translation> {
+        let virt = virt.as_u64();
+        let (entry, level) = unsafe {
+            walk_entries(self.p4_addr(), virt, |_, _| {})
+        };
+        if entry & PRESENT == 0 {
+            return None;
+        }
+
+        let size = PageSize::of_level(level);
+        let offset = virt & (size.bytes() - 1);
+        let base = entry & ADDR_MASK & !(size.bytes() - 1);
+        Some(Translation {
+            phys    : PhysAddr::new(base + offset),
+            size    : size,
+            flags   : entry & !ADDR_MASK,
+        })
+    }
+
+    /// Find entry of the table of given level that maps given address.
+    /// Missing intermediate tables are allocated.
+    fn create_entry(&mut self, virt: u64, level: u8) -> MapResult<&mut u64> {
+        let mut phys = self.p4;
+        let mut cur = 4;
+        while cur > level {
+            let entry = unsafe {
+                &mut table(phys).entries[index(virt, cur)]
+            };
+
+            if *entry & PRESENT == 0 {
+                *entry = table_entry(try!(alloc_table()));
+            } else if *entry & HUGE != 0 {
+                return Err(MapError::AlreadyMapped);
+            }
+
+            phys = *entry & ADDR_MASK;
+            cur -= 1;
+        }
+
+        Ok(unsafe { &mut table(phys).entries[index(virt, level)] })
+    }
+
+    /// Find the last level entry that maps given address. Level of the
+    /// table is returned along with the entry. If address is not mapped,
+    /// level of the table with missing entry is returned as error.
+    fn find_entry(&mut self, virt: u64) -> Result<(&mut u64, u8), u8> {
+        let mut phys = self.p4;
+        let mut level = 4;
+        loop {
+            let entry = unsafe {
+                &mut table(phys).entries[index(virt, level)]
+            };
+            if *entry & PRESENT == 0 {
+                return Err(level);
+            }
+
+            if level == 1 || (level < 4 && *entry & HUGE != 0) {
+                return Ok((entry, level));
+            }
+
+            phys = *entry & ADDR_MASK;
+            level -= 1;
+        }
+    }
+
+    /// Replace huge page entry with the table of smaller pages that map the
+    /// same memory with the same flags.
+    fn split(entry: &mut u64, level: u8) -> MapResult<()> {
+        let phys = try!(alloc_table());
+        let tab = unsafe { table(phys) };
+
+        let base = *entry & ADDR_MASK & !(level_size(level) - 1);
+        let flags = if level - 1 == 1 {
+            *entry & !ADDR_MASK & !HUGE
+        } else {
+            *entry & !ADDR_MASK
+        };
+
+        let step = level_size(level - 1);
+        for i in 0..ENTRIES {
+            tab.entries[i] = (base + i as u64 * step) | flags;
+        }
+
+        *entry = table_entry(phys);
+        Ok(())
+    }
+
+    /// Unmap or change flags of pages in the range. Changed pages are
+    /// invalidated on all processors even if some page failed.
+    fn update(&mut self, virt: u64, size: u64, change: Change)
+            -> MapResult<()> {
+        let mut batch = FlushBatch::new();
+        let result = self.update_pages(virt, size, change, &mut batch);
+        self.shootdown(&batch);
+        result
+    }
+
+    /// Unmap or change flags of pages in the range and collect changed
+    /// pages.
+    fn update_pages(&mut self, virt: u64, size: u64, change: Change,
+            batch: &mut FlushBatch) -> MapResult<()> {
+        let mut addr = virt;
+        let mut left = size;
+        while left > 0 {
+            let (entry, level) = match self.find_entry(addr) {
+                Ok(found) => found,
+                Err(level) => {
+                    if change != Change::Unmap {
+                        return Err(MapError::NotMapped);
+                    }
+
+                    // Skip memory that missing entry would cover.
+                    let covered = level_size(level);
+                    let skip = covered - (addr & (covered - 1));
+                    if skip >= left {
+                        break;
+                    }
+                    addr = addr.wrapping_add(skip);
+                    left -= skip;
+                    continue;
+                }
+            };
+
+            let page = level_size(level);
+            if addr & (page - 1) != 0 || left < page {
+                // Range covers only part of the page.
+                try!(Self::split(entry, level));
+                continue;
+            }
+
+            match change {
+                Change::Unmap => *entry = 0,
+                Change::Replace(flags) => {
+                    let huge = *entry & HUGE;
+                    let phys = *entry & ADDR_MASK;
+                    *entry = page_entry(phys, entry_flags(flags) | huge);
+                },
+                Change::Clear(flags) => *entry &= !(flags & PAGE_FLAGS),
+            }
+
+            batch.add(addr);
+            addr = addr.wrapping_add(page);
+            left -= page;
+        }
+
+        Ok(())
+    }
+
+    /// Release tables in the range that have no present entries.
+    /// Returns whether given table got empty. Level 4 table and shared
+    /// level 3 tables of the upper half are never released.
+    ///
+    /// # Safety
+    /// Table must be one of the tables of this address space.
+    unsafe fn prune(&mut self, phys: u64, level: u8, base: u64, start: u64,
+            end: u64) -> bool {
+        let size = level_size(level);
+        for i in 0..ENTRIES {
+            let from = base + i as u64 * size;
+            let to = from + size;
+            if to <= start || from >= end {
+                continue;
+            }
+
+            let entry = table(phys).entries[i];
+            if level == 1 || entry & PRESENT == 0 || entry & HUGE != 0 {
+                continue;
+            }
+
+            let child = entry & ADDR_MASK;
+            let shared = level == 4 && i >= KERNEL_HALF;
+            if self.prune(child, level - 1, from, start, end) && !shared {
+                table(phys).entries[i] = 0;
+                free_table(child);
+            }
+        }
+
+        table(phys).is_empty()
+    }
+}
+
+impl Drop for AddressSpace {
+
+    fn drop(&mut self) {
+        unsafe fn free(phys: u64, level: u8) {
+            if level > 1 {
+                for entry in table(phys).entries.iter() {
+                    if *entry & PRESENT != 0 && *entry & HUGE == 0 {
+                        free(*entry & ADDR_MASK, level - 1);
+                    }
+                }
+            }
+            free_table(phys);
+        }
+
+        // Upper half is owned by the kernel space which is never dropped.
+        unsafe {
+            for entry in table(self.p4).entries[..KERNEL_HALF].iter() {
+                if *entry & PRESENT != 0 {
+                    free(*entry & ADDR_MASK, 3);
+                }
+            }
+            free_table(self.p4);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use super::alloc::FrameAlloc;
+use super::heap::kernel_heap;
+use super::{page_alloc, phys_map};
+use mem::RegionKind;
+use early::logger;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Count of subsystems that frames are accounted for.
+pub const SUBSYSTEM_COUNT: usize = 5;
+
+/// Subsystems of the kernel that take frames from the page allocator.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Subsystem {
+
+    /// Paging tables of all address spaces.
+    PageTables,
+
+    /// Slabs of object caches.
+    Slabs,
+
+    /// Pages of kernel stacks of the threads.
+    KernelStacks,
+
+    /// Stacks of Interrupt Stack Table.
+    InterruptStacks,
+
+    /// Memory given to CCS objects by RAM manager.
+    ObjectRam,
+}
+
+/// Memory usage of the whole system.
+#[derive(Clone, Copy)]
+pub struct MemStats {
+
+    /// Bytes of usable RAM reported by firmware.
+    pub total_bytes     : u64,
+
+    /// Count of free 4KiB frames.
+    pub free_frames     : usize,
+
+    /// Count of frames taken by each subsystem.
+    pub subsystems      : [usize; SUBSYSTEM_COUNT],
+
+    /// Bytes of allocated objects of kernel heap.
+    pub heap_used       : usize,
+
+    /// Bytes of frames that kernel heap took.
+    pub heap_frames     : usize,
+}
+
+/// Count of frames taken by each subsystem. Counters are changed by all
+/// processors.
+static FRAMES: [AtomicUsize; SUBSYSTEM_COUNT] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+impl Subsystem {
+
+    /// Subsystem with given index.
+    pub fn from_index(index: usize) -> Self {
+        match index {
+            0 => Subsystem::PageTables,
+            1 => Subsystem::Slabs,
+            2 => Subsystem::KernelStacks,
+            3 => Subsystem::InterruptStacks,
+            4 => Subsystem::ObjectRam,
+            _ => panic!("Invalid subsystem index"),
+        }
+    }
+
+    /// Index of the subsystem in the counter arrays.
+    pub fn index(&self) -> usize {
+        match *self {
+            Subsystem::PageTables       => 0,
+            Subsystem::Slabs            => 1,
+            Subsystem::KernelStacks     => 2,
+            Subsystem::InterruptStacks  => 3,
+            Subsystem::ObjectRam        => 4,
+        }
+    }
+
+    /// Name of the subsystem to print in statistics.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Subsystem::PageTables       => "page tables",
+            Subsystem::Slabs            => "object caches",
+            Subsystem::KernelStacks     => "kernel stacks",
+            Subsystem::InterruptStacks  => "interrupt stacks",
+            Subsystem::ObjectRam        => "object RAM",
+        }
+    }
+}
+
+/// Account frames taken by the subsystem.
+pub fn charge(sub: Subsystem, frames: usize) {
+    FRAMES[sub.index()].fetch_add(frames, Ordering::Relaxed);
+}
+
+/// Account frames that the subsystem gave back.
+pub fn uncharge(sub: Subsystem, frames: usize) {
+    FRAMES[sub.index()].fetch_sub(frames, Ordering::Relaxed);
+}
+
+/// Count of frames the subsystem currently holds.
+pub fn frames(sub: Subsystem) -> usize {
+    FRAMES[sub.index()].load(Ordering::Relaxed)
+}
+
+/// Collect memory usage of the system.
+/// Is allowed to be used only after `init_page_alloc` call.
+pub fn mem_stats() -> MemStats {
+    let mut subsystems = [0; SUBSYSTEM_COUNT];
+    for i in 0..SUBSYSTEM_COUNT {
+        subsystems[i] = frames(Subsystem::from_index(i));
+    }
+
+    MemStats {
+        total_bytes     : phys_map().total_size(RegionKind::Usable),
+        free_frames     : page_alloc().free_frames(),
+        subsystems      : subsystems,
+        heap_used       : kernel_heap().used(),
+        heap_frames     : kernel_heap().frames(),
+    }
+}
+
+/// Print usage of each range of page allocator and count of split 2MiB
+/// pages.
+#[cfg(not(frame_alloc__buddy))]
+fn dump_ranges() {
+    let alloc = page_alloc();
+    for i in 0..alloc.range_count() {
+        let range = alloc.range_stats(i);
+        write!(logger(),
+            "  range {:016X}..{:016X} {:6}: {} pages, {} used, {} split\n",
+            range.bottom, range.top, range.zone.name(), range.pages,
+            range.used, range.split).unwrap();
+    }
+    write!(logger(), "  split 2MiB pages: {}, status heap: {} KiB\n",
+        alloc.split_pages(), alloc.heap_frames() * 2048).unwrap();
+}
+
+/// Buddy allocator has no ranges of 2MiB pages.
+#[cfg(frame_alloc__buddy)]
+fn dump_ranges() {
+}
+
+/// Print memory usage of the system on the kernel logger.
+pub fn dump() {
+    let stats = mem_stats();
+    let free = stats.free_frames as u64 * 4;
+    write!(logger(), "Memory: {} KiB usable, {} KiB free\n",
+        stats.total_bytes / 1024, free).unwrap();
+    write!(logger(), "  kernel heap: {} bytes used, {} KiB of frames\n",
+        stats.heap_used, stats.heap_frames / 1024).unwrap();
+
+    for i in 0..SUBSYSTEM_COUNT {
+        write!(logger(), "  {}: {} frames\n",
+            Subsystem::from_index(i).name(), stats.subsystems[i]).unwrap();
+    }
+
+    dump_ranges();
+}
@@ -0,0 +1,237 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::spin_loop_hint;
+use ints::{InterruptFrame, cpu_id, send_ipi, end_of_interrupt};
+use ints::KernelVector;
+use super::gdt::MAX_CPU_COUNT;
+
+/// Count of pages that batch can hold. Batch with more pages flushes the
+/// whole TLB.
+pub const BATCH_SIZE: usize = 32;
+
+/// Pages which translations must be invalidated after paging entries
+/// were changed.
+#[derive(Clone, Copy)]
+pub struct FlushBatch {
+
+    /// Addresses of the changed pages.
+    addrs   : [u64; BATCH_SIZE],
+
+    /// Count of valid addresses.
+    count   : usize,
+
+    /// Too many pages were changed so the whole TLB must be flushed.
+    full    : bool,
+
+    /// Some page is in the upper half which is shared by all address
+    /// spaces.
+    kernel  : bool,
+}
+
+/// Processors that have loaded some address space. Kernel half of each
+/// space is the same so these processors may cache kernel pages.
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Taken by the processor that sends shootdown request.
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Processors that have not handled the request yet.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Processors that did not acknowledge some request in time. Such
+/// processor flushes the whole TLB when it handles shootdown IPI.
+static STALE: AtomicUsize = AtomicUsize::new(0);
+
+/// Request that is handled by the target processors.
+static mut REQUEST: FlushBatch = FlushBatch::new();
+
+/// Count of polls of pending processors after which the request is
+/// abandoned. Target processor may be halted or have interrupts disabled.
+const WAIT_LIMIT: usize = 100_000_000;
+
+impl FlushBatch {
+
+    /// Empty batch.
+    pub const fn new() -> Self {
+        FlushBatch {
+            addrs   : [0; BATCH_SIZE],
+            count   : 0,
+            full    : false,
+            kernel  : false,
+        }
+    }
+
+    /// Add the page to the batch.
+    pub fn add(&mut self, virt: u64) {
+        if virt >> 63 != 0 {
+            self.kernel = true;
+        }
+
+        if self.count == BATCH_SIZE {
+            self.full = true;
+        } else {
+            self.addrs[self.count] = virt;
+            self.count += 1;
+        }
+    }
+
+    /// Whether no page was added.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Invalidate translations of the batch on current processor.
+    pub fn flush_local(&self) {
+        unsafe {
+            if self.full && self.kernel {
+                flush_global();
+            } else if self.full {
+                flush_all();
+            } else {
+                for addr in self.addrs[..self.count].iter() {
+                    flush(*addr);
+                }
+            }
+        }
+    }
+}
+
+/// Invalidate TLB entry of given address.
+pub unsafe fn flush(virt: u64) {
+    asm!("invlpg ($0)" : : "r"(virt) : "memory" : "volatile");
+}
+
+/// Invalidate all TLB entries of the current address space except global
+/// pages. With PCID only entries of the current PCID are invalidated.
+pub unsafe fn flush_all() {
+    use arch::cr::{Cr3, Reg};
+    Cr3::read().save();
+}
+
+/// Invalidate all TLB entries including global pages and entries of all
+/// PCIDs. Global pages bit of CR4 is toggled for that.
+pub unsafe fn flush_global() {
+    asm!("mov %cr4, %rax
+          mov %rax, %rcx
+          xor $$0x80, %rax
+          mov %rax, %cr4
+          mov %rcx, %cr4" : : : "rax", "rcx", "memory" : "volatile");
+}
+
+/// Bit of the processor in the processor sets. Processor is given by
+/// it's index from `cpu_id`.
+pub fn cpu_bit(cpu: usize) -> usize {
+    assert!(cpu < MAX_CPU_COUNT);
+    1 << cpu
+}
+
+/// Mark current processor as the one that can cache translations.
+pub fn set_online() {
+    ONLINE.fetch_or(cpu_bit(cpu_id()), Ordering::SeqCst);
+}
+
+/// Invalidate translations of the batch on all processors of the set.
+/// Current processor flushes it's TLB directly and other processors get
+/// IPI and are waited for. When no other processor is in the set IPI is
+/// not sent at all. Pages of the upper half are invalidated on all
+/// processors that are online.
+///
+/// Can be called with interrupts disabled, like in page fault handler.
+/// Request of other processor is handled while waiting for the lock so
+/// two processors that send requests at once do not wait for each other.
+///
+/// Processors that do not acknowledge the request in time are logged and
+/// marked stale. With `mem_debug` this panics instead.
+pub fn shootdown(active: usize, batch: &FlushBatch) {
+    if batch.is_empty() {
+        return;
+    }
+
+    let me = cpu_bit(cpu_id());
+    let targets = if batch.kernel {
+        ONLINE.load(Ordering::SeqCst) | active
+    } else {
+        active
+    };
+
+    if targets & me != 0 {
+        batch.flush_local();
+    }
+
+    let others = targets & !me;
+    if others == 0 {
+        return;
+    }
+
+    while LOCK.compare_and_swap(false, true, Ordering::Acquire) {
+        drain();
+        spin_loop_hint();
+    }
+
+    unsafe { REQUEST = *batch; }
+    PENDING.store(others, Ordering::SeqCst);
+    for cpu in 0..MAX_CPU_COUNT {
+        if others & cpu_bit(cpu) != 0 {
+            send_ipi(cpu, KernelVector::TlbShootdown as u8);
+        }
+    }
+
+    let mut polls = 0;
+    while PENDING.load(Ordering::SeqCst) != 0 {
+        spin_loop_hint();
+        polls += 1;
+        if polls == WAIT_LIMIT {
+            abandon();
+            break;
+        }
+    }
+    LOCK.store(false, Ordering::Release);
+}
+
+/// Stop waiting for the processors that still have not handled the
+/// request. They are logged and flush the whole TLB on their next
+/// shootdown IPI, which stays pending while their interrupts are off.
+fn abandon() {
+    use early::logger;
+    use core::fmt::Write;
+
+    let pending = PENDING.swap(0, Ordering::SeqCst);
+    if pending == 0 {
+        return;
+    }
+    STALE.fetch_or(pending, Ordering::SeqCst);
+
+    write!(logger(), "TLB shootdown is not acknowledged by CPUs").unwrap();
+    for cpu in 0..MAX_CPU_COUNT {
+        if pending & cpu_bit(cpu) != 0 {
+            write!(logger(), " {}", cpu).unwrap();
+        }
+    }
+    write!(logger(), "\n").unwrap();
+
+    if cfg!(mem_debug) {
+        panic!("TLB shootdown timed out");
+    }
+}
+
+/// Invalidate translations of the request and acknowledge it if current
+/// processor has not handled it yet. IPI of the request that was already
+/// handled this way does nothing. Stale processor flushes everything.
+fn drain() {
+    let me = cpu_bit(cpu_id());
+    if STALE.load(Ordering::SeqCst) & me != 0 {
+        STALE.fetch_and(!me, Ordering::SeqCst);
+        unsafe { flush_global(); }
+    }
+    if PENDING.load(Ordering::SeqCst) & me != 0 {
+        unsafe { REQUEST.flush_local(); }
+        PENDING.fetch_and(!me, Ordering::SeqCst);
+    }
+}
+
+/// Handler of shootdown IPI. Invalidates translations of the request
+/// and acknowledges it.
+#[no_mangle]
+pub extern fn tlb_shootdown_handler(_: u64, _: &InterruptFrame) {
+    drain();
+    end_of_interrupt();
+}
@@ -3,8 +3,12 @@ pub use self::arch::*;
 
 /// Memory address operations.
 mod addr;
-pub use self::addr::Address;
+pub use self::addr::{Address, PhysAddr, VirtAddr, PAGE_SIZE};
 
 /// Memory allocator traits and structs.
 mod alloc;
 pub use self::alloc::*;
+
+/// Physical memory regions reported by firmware.
+mod region;
+pub use self::region::*;
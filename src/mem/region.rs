@@ -0,0 +1,449 @@
+/// Maximal amount of regions that region list can hold.
+pub const REGION_LIST_CAPACITY: usize = 64;
+
+/// Type of the physical memory region as reported by firmware.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RegionKind {
+
+    /// RAM that is free to be used by the kernel.
+    Usable,
+
+    /// Memory that must not be used. Can be occupied by firmware or
+    /// memory mapped devices.
+    Reserved,
+
+    /// Memory that holds ACPI tables. Can be used as RAM after the tables
+    /// were read.
+    AcpiReclaimable,
+
+    /// ACPI Non-Volatile Storage. Must be preserved between sleep states.
+    AcpiNvs,
+
+    /// Memory that was found to be defective.
+    Defective,
+}
+
+/// Region of physical memory.
+#[derive(Clone, Copy)]
+pub struct Region {
+
+    /// First byte of the region.
+    start   : u64,
+
+    /// Byte after the last byte of the region.
+    end     : u64,
+
+    /// Type of the region.
+    kind    : RegionKind,
+}
+
+/// Sorted list of physical memory regions.
+pub struct RegionList {
+
+    /// Array that stores regions. Only first `length` entries are valid.
+    arr     : [Region; REGION_LIST_CAPACITY],
+
+    /// Count of valid entries in the array.
+    length  : usize,
+}
+
+/// Iterator over regions in region list.
+pub struct RegionIter<'a> {
+    list    : &'a RegionList,
+    index   : usize,
+}
+
+/// Error of inserting new region into the list.
+pub enum RegionListError {
+
+    /// List has no more space for new region.
+    Full,
+}
+
+const EMPTY_REGION: Region = Region {
+    start   : 0,
+    end     : 0,
+    kind    : RegionKind::Reserved,
+};
+
+impl Region {
+
+    /// Create new region. Start is inclusive and end is exclusive.
+    pub const fn new(start: u64, end: u64, kind: RegionKind) -> Self {
+        Region {
+            start   : start,
+            end     : end,
+            kind    : kind,
+        }
+    }
+
+    /// First byte of the region.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Byte after the last byte of the region.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Size of the region in bytes.
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Type of the region.
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    /// Whether this region is RAM free to be used.
+    pub fn is_usable(&self) -> bool {
+        self.kind == RegionKind::Usable
+    }
+
+    /// Whether given address is in this region.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Whether this region has at least one common byte with given one.
+    pub fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+impl RegionList {
+
+    /// Create empty region list.
+    pub const fn new() -> Self {
+        RegionList {
+            arr     : [EMPTY_REGION; REGION_LIST_CAPACITY],
+            length  : 0,
+        }
+    }
+
+    /// Count of regions in the list.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Whether the list has no regions.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Get region by given index, if any.
+    pub fn get(&self, index: usize) -> Option<&Region> {
+        if index < self.length {
+            Some(&self.arr[index])
+        } else {
+            None
+        }
+    }
+
+    /// Iterator over all regions in the list ordered by start address.
+    pub fn iter(&self) -> RegionIter {
+        RegionIter {
+            list    : self,
+            index   : 0,
+        }
+    }
+
+    /// Insert new region so that the list stays sorted by region start
+    /// address. Empty regions are ignored. Adjacent regions of the same
+    /// type are merged into one.
+    ///
+    /// Regions in the list never overlap. Parts of usable regions that
+    /// are covered by a region of other type are cut off, whichever of
+    /// them is inserted first. For other overlaps the region that is
+    /// already in the list wins.
+    ///
+    /// # Errors
+    /// Full error occurs when there is no space left for new region.
+    pub fn insert(&mut self, region: Region) -> Result<(), RegionListError> {
+        if region.start >= region.end {
+            return Ok(());
+        }
+
+        if !region.is_usable() {
+            try!(self.cut(region.start, region.end, true));
+        }
+
+        // Insert only the parts that are not covered by other regions.
+        let overlap = self.iter().position(|r| r.overlaps(&region));
+        if let Some(i) = overlap {
+            let other = self.arr[i];
+            try!(self.insert(Region::new(region.start, other.start,
+                    region.kind)));
+            return self.insert(Region::new(other.end, region.end,
+                    region.kind));
+        }
+
+        // Find position where the region must be stored.
+        let mut pos = 0;
+        while pos < self.length && self.arr[pos].start < region.start {
+            pos += 1;
+        }
+
+        // Try to merge with previous region.
+        if pos > 0 {
+            let prev = &mut self.arr[pos - 1];
+            if prev.kind == region.kind && prev.end == region.start {
+                prev.end = region.end;
+                self.merge_next(pos - 1);
+                return Ok(());
+            }
+        }
+
+        if self.length == REGION_LIST_CAPACITY {
+            return Err(RegionListError::Full);
+        }
+
+        // Shift all next regions to give space for new one.
+        let mut i = self.length;
+        while i > pos {
+            self.arr[i] = self.arr[i - 1];
+            i -= 1;
+        }
+        self.arr[pos] = region;
+        self.length += 1;
+
+        self.merge_next(pos);
+        Ok(())
+    }
+
+    /// Merge region with given index and the next one if they are adjacent
+    /// and have the same type.
+    fn merge_next(&mut self, index: usize) {
+        let next = index + 1;
+        if next >= self.length {
+            return;
+        }
+
+        if self.arr[index].kind != self.arr[next].kind
+                || self.arr[index].end != self.arr[next].start {
+            return;
+        }
+
+        self.arr[index].end = self.arr[next].end;
+        self.remove(next);
+    }
+
+    /// Remove region with given index from the list.
+    pub fn remove(&mut self, index: usize) {
+        if index >= self.length {
+            return;
+        }
+
+        let mut i = index;
+        while i + 1 < self.length {
+            self.arr[i] = self.arr[i + 1];
+            i += 1;
+        }
+        self.length -= 1;
+    }
+
+    /// Exclude given address range from all regions in the list. Regions
+    /// that are partially covered get shrunk or split in two.
+    ///
+    /// # Errors
+    /// Full error occurs when region must be split but there is no space
+    /// for the second half. Regions that were processed before the error
+    /// keep their changes.
+    pub fn exclude(&mut self, start: u64, end: u64)
+            -> Result<(), RegionListError> {
+        self.cut(start, end, false)
+    }
+
+    /// Exclude given address range from the regions. When `usable_only`
+    /// is set regions of other types are left as they are.
+    fn cut(&mut self, start: u64, end: u64, usable_only: bool)
+            -> Result<(), RegionListError> {
+        let hole = Region::new(start, end, RegionKind::Reserved);
+
+        let mut i = 0;
+        while i < self.length {
+            let region = self.arr[i];
+            if !region.overlaps(&hole) || usable_only && !region.is_usable() {
+                i += 1;
+                continue;
+            }
+
+            if start <= region.start && end >= region.end {
+                // Region is covered entirely.
+                self.remove(i);
+                continue;
+            }
+
+            if start > region.start && end < region.end {
+                // Hole is inside of the region. Split it in two.
+                if self.length == REGION_LIST_CAPACITY {
+                    return Err(RegionListError::Full);
+                }
+                self.arr[i].end = start;
+                try!(self.insert(Region::new(end, region.end, region.kind)));
+            } else if start <= region.start {
+                self.arr[i].start = end;
+            } else {
+                self.arr[i].end = start;
+            }
+            i += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Remove all regions from the list.
+    pub fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    /// Total size in bytes of all regions of given type.
+    pub fn total_size(&self, kind: RegionKind) -> u64 {
+        let mut sum = 0;
+        for region in self.iter() {
+            if region.kind == kind {
+                sum += region.size();
+            }
+        }
+        sum
+    }
+
+    /// Byte after the last byte of the highest usable region. This is the
+    /// amount of physical address space that RAM occupies.
+    pub fn usable_top(&self) -> u64 {
+        let mut top = 0;
+        for region in self.iter() {
+            if region.is_usable() && region.end > top {
+                top = region.end;
+            }
+        }
+        top
+    }
+}
+
+impl<'a> Iterator for RegionIter<'a> {
+
+    type Item = &'a Region;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let region = self.list.get(self.index);
+        if region.is_some() {
+            self.index += 1;
+        }
+        region
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use super::RegionKind::*;
+
+    fn list(regions: &[(u64, u64, RegionKind)]) -> RegionList {
+        let mut list = RegionList::new();
+        for &(start, end, kind) in regions {
+            assert!(list.insert(Region::new(start, end, kind)).is_ok());
+        }
+        list
+    }
+
+    fn assert_bounds(list: &RegionList, expected: &[(u64, u64, bool)]) {
+        assert_eq!(list.length(), expected.len());
+        for (r, &e) in list.iter().zip(expected.iter()) {
+            assert_eq!((r.start(), r.end(), r.is_usable()), e);
+        }
+    }
+
+    #[test]
+    fn keeps_regions_sorted_and_merged() {
+        let list = list(&[
+            (0x3000, 0x4000, Usable),
+            (0x1000, 0x2000, Usable),
+            (0x2000, 0x3000, Usable),
+            (0x5000, 0x6000, Reserved),
+            (0x0000, 0x0000, Usable),
+        ]);
+        assert_bounds(&list, &[
+            (0x1000, 0x4000, true),
+            (0x5000, 0x6000, false),
+        ]);
+    }
+
+    #[test]
+    fn reserved_clips_usable_inserted_before() {
+        let list = list(&[
+            (0x0000, 0x10000, Usable),
+            (0x4000, 0x6000, Reserved),
+        ]);
+        assert_bounds(&list, &[
+            (0x0000, 0x4000, true),
+            (0x4000, 0x6000, false),
+            (0x6000, 0x10000, true),
+        ]);
+        assert_eq!(list.total_size(Usable), 0xE000);
+    }
+
+    #[test]
+    fn reserved_clips_usable_inserted_after() {
+        let list = list(&[
+            (0x4000, 0x6000, Reserved),
+            (0x0000, 0x10000, Usable),
+        ]);
+        assert_bounds(&list, &[
+            (0x0000, 0x4000, true),
+            (0x4000, 0x6000, false),
+            (0x6000, 0x10000, true),
+        ]);
+    }
+
+    #[test]
+    fn first_region_wins_other_overlaps() {
+        let list = list(&[
+            (0x2000, 0x4000, AcpiNvs),
+            (0x3000, 0x6000, Reserved),
+        ]);
+        assert_bounds(&list, &[
+            (0x2000, 0x4000, false),
+            (0x4000, 0x6000, false),
+        ]);
+        assert!(list.get(0).unwrap().kind() == AcpiNvs);
+        assert!(list.get(1).unwrap().kind() == Reserved);
+    }
+
+    #[test]
+    fn exclude_shrinks_and_splits() {
+        let mut list = list(&[
+            (0x0000, 0x4000, Usable),
+            (0x8000, 0xC000, Usable),
+        ]);
+        assert!(list.exclude(0x3000, 0x9000).is_ok());
+        assert!(list.exclude(0xA000, 0xB000).is_ok());
+        assert_bounds(&list, &[
+            (0x0000, 0x3000, true),
+            (0x9000, 0xA000, true),
+            (0xB000, 0xC000, true),
+        ]);
+        assert_eq!(list.usable_top(), 0xC000);
+
+        assert!(list.exclude(0x0000, 0x10000).is_ok());
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn reports_full_list() {
+        let mut list = RegionList::new();
+        for i in 0..REGION_LIST_CAPACITY as u64 {
+            let region = Region::new(i * 0x2000, i * 0x2000 + 0x1000, Usable);
+            assert!(list.insert(region).is_ok());
+        }
+
+        let region = Region::new(0x1000_0000, 0x1000_1000, Usable);
+        assert!(list.insert(region).is_err());
+
+        // Split needs one more entry.
+        assert!(list.exclude(0x400, 0x800).is_err());
+    }
+}